@@ -0,0 +1,200 @@
+use std::collections::BTreeSet;
+
+use super::changelog::LoggedChange;
+use super::error::{ BPlusError, BPlusResult };
+use super::journal::checksum;
+use super::page::{ Page, PagePointer, PageBuffer, DeltaPage, MetadataPage, PAGE_SIZE, DELTA_CAPACITY, METADATA_IDX };
+use super::page::{ DirectoryPage, LeafPage, FreePage, DIR_PAGE_T, LEAF_PAGE_T, FREE_PAGE_T };
+use super::storage::Storage;
+
+/// What a backup holds (see `BPlusTree::backup`)
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct BackupInfo
+{
+  /// The sequence number of the last logged change in the backup
+  pub last_seq: u64,
+  /// The generation that the backup ends.  The next incremental
+  /// backup holds the pages written since.
+  pub generation: u32,
+}
+
+/// How far past a backup `BPlusTree::restore` replays the change
+/// log
+#[allow(dead_code)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum RestorePoint
+{
+  /// Replay every logged change
+  Latest,
+  /// Replay changes up to and including this sequence number
+  Seq(u64),
+  /// Replay changes made at or before this time (see Clock)
+  Time(u32),
+}
+
+impl RestorePoint
+{
+  /// Return true if the change is after the restore point
+  pub fn excludes(&self, change: &LoggedChange) -> bool
+  {
+    match *self
+    {
+      RestorePoint::Latest    => false,
+      RestorePoint::Seq(seq)  => change.seq > seq,
+      RestorePoint::Time(t)   => change.time > t,
+    }
+  }
+}
+
+/// Copy the pages at `pointers` from `source` to `dest`, checking
+/// each one with `check` as it is read
+///
+/// Each copy is read back and compared byte for byte against the
+/// original, so a copy that completes is known to match the pages
+/// it was taken from.  `dest` isn't synced.
+pub fn copy_pages<I>(source: &mut dyn Storage, dest: &mut dyn Storage, pointers: I, 
+  check: fn(PagePointer, &[u8; PAGE_SIZE]) -> BPlusResult<()>) -> BPlusResult<()>
+  where I: IntoIterator<Item = PagePointer>
+{
+  let mut image = [0; PAGE_SIZE];
+  let mut copy = [0; PAGE_SIZE];
+  for ptr in pointers
+  {
+    source.read_page(ptr, &mut image)?;
+    check(ptr, &image)?;
+    dest.write_page(ptr, &image)?;
+    dest.read_page(ptr, &mut copy)?;
+    if copy != image
+    {
+      return Err(BPlusError::corrupt(ptr, "Copied page does not match the original"))
+    }
+  }
+  Ok(())
+}
+
+/// Check that `image`, read from page `ptr` of a tree, is a page
+/// that belongs there (the metadata page, or a directory, leaf, 
+/// or free page), and isn't obviously corrupt
+pub fn check_tree_page(ptr: PagePointer, image: &[u8; PAGE_SIZE]) -> BPlusResult<()>
+{
+  match (ptr, image[0])
+  {
+    (METADATA_IDX, _) => MetadataPage::decode(image).check(ptr),
+    (_, DIR_PAGE_T)   => DirectoryPage::decode(image).check(ptr),
+    (_, LEAF_PAGE_T)  => LeafPage::decode(image).check(ptr),
+    (_, FREE_PAGE_T)  => FreePage::decode(image).check(ptr),
+    (_, t)            => Err(BPlusError::corrupt(ptr, format!("Unknown page type {}", t)))
+  }
+}
+
+/// A backup being taken a few pages at a time, between other 
+/// operations on the tree (see `BPlusTree::begin_backup`)
+#[derive(Debug)]
+pub struct BackupProgress
+{
+  pub dest: Box<dyn Storage>,
+  /// The next page to copy for the first time
+  pub next: PagePointer,
+  /// Pages that have been written since they were copied, and so
+  /// must be copied again
+  pub rewritten: BTreeSet<PagePointer>,
+}
+
+impl BackupProgress
+{
+  pub fn new(dest: Box<dyn Storage>) -> BackupProgress
+  {
+    BackupProgress { dest, next: 0, rewritten: BTreeSet::new() }
+  }
+
+  /// Note that page `ptr` of the tree has been written
+  pub fn written(&mut self, ptr: PagePointer)
+  {
+    if ptr < self.next { self.rewritten.insert(ptr); }
+  }
+}
+
+/// Write the pages at `pointers` from `source` to `dest` as a 
+/// delta holding the pages written after generation `base`, up to
+/// and including `generation`, and sync it
+pub fn write_delta(source: &mut dyn Storage, dest: &mut dyn Storage, pointers: &[PagePointer], 
+  base: u32, generation: u32) -> BPlusResult<()>
+{
+  let mut at: PagePointer = 0;
+  // The last chunk isn't full, even if it has to be empty
+  for chunk in 0 ..= pointers.len() / DELTA_CAPACITY
+  {
+    let chunk = &pointers[chunk * DELTA_CAPACITY .. pointers.len().min((chunk + 1) * DELTA_CAPACITY)];
+    let mut header = DeltaPage::init(base, generation);
+    let mut images = Vec::with_capacity(chunk.len());
+    for (i, ptr) in chunk.iter().enumerate()
+    {
+      let mut image = PageBuffer([0; PAGE_SIZE]);
+      source.read_page(*ptr, &mut image.0)?;
+      dest.write_page(at + 1 + i as PagePointer, &image.0)?;
+      header.pointers[i] = *ptr;
+      images.push(image);
+    }
+    header.count = chunk.len();
+    header.checksum = checksum(chunk.iter().cloned().zip(images.iter()));
+    header.write(dest, at)?;
+    at += 1 + chunk.len() as PagePointer;
+  }
+  dest.sync()
+}
+
+/// Write the pages in `delta` (see `write_delta`) to `target`, the
+/// metadata page last, and sync it
+///
+/// `target` must hold a tree that is at least as new as the 
+/// delta's base generation, but older than the generation it ends
+/// (so that a delta the tree already has isn't applied again), and
+/// the checksum of every chunk must match, or nothing is written.
+pub fn apply_delta(target: &mut dyn Storage, delta: &mut dyn Storage) -> BPlusResult<()>
+{
+  let current = MetadataPage::read(target, METADATA_IDX)?;
+  current.check(METADATA_IDX)?;
+
+  let mut pages = Vec::new();
+  let mut at: PagePointer = 0;
+  loop
+  {
+    let header = DeltaPage::read(delta, at)?;
+    header.check(at)?;
+    if header.base > current.generation
+    {
+      return Err(BPlusError::Unsupported(format!("The delta holds the pages written since generation {}, but the tree is only up to generation {}", header.base, current.generation)))
+    }
+    if header.generation <= current.generation
+    {
+      return Err(BPlusError::Unsupported(format!("The delta holds the pages written up to generation {}, but the tree is already up to generation {}", header.generation, current.generation)))
+    }
+    let mut images = Vec::with_capacity(header.count);
+    for i in 0 .. header.count
+    {
+      let mut image = PageBuffer([0; PAGE_SIZE]);
+      delta.read_page(at + 1 + i as PagePointer, &mut image.0)?;
+      images.push(image);
+    }
+    if checksum(header.pointers[.. header.count].iter().cloned().zip(images.iter())) != header.checksum
+    {
+      return Err(BPlusError::corrupt(at, "Delta chunk does not match its checksum"))
+    }
+    pages.extend(header.pointers[.. header.count].iter().cloned().zip(images));
+    if !header.is_full() { break }
+    at += 1 + header.count as PagePointer;
+  }
+
+  let Some(meta) = pages.iter().position(|(ptr, _)| *ptr == METADATA_IDX) else
+  {
+    return Err(BPlusError::corrupt(0, "Delta has no metadata page"))
+  };
+  let (_, meta) = pages.swap_remove(meta);
+  for (ptr, image) in pages
+  {
+    target.write_page(ptr, &image.0)?;
+  }
+  target.sync()?;
+  target.write_page(METADATA_IDX, &meta.0)?;
+  target.sync()
+}
@@ -1,23 +1,107 @@
-use std::borrow::Borrow;
-use std::fs::OpenOptions;
-use std::io::SeekFrom;
-use std::ops::Range;
-use std::{error::Error, fs::File, io::Seek};
+use std::collections::VecDeque;
+use std::io::Write;
+use std::ops::{ Bound, Range, RangeBounds };
+use std::path::Path;
+use std::time::{ Duration, Instant };
 
 
 use super::page::{ NULL_IDX, DEFAULT_ROOT_IDX, DEFAULT_PAGE0_IDX, METADATA_IDX };
 
-use super::page::{ PagePointer, PAGE_SIZE, Page };
-use super::page::{ LeafPage, DirectoryPage, MetadataPage, FreePage };
-
-pub type BPlusResult<T> = Result<T, Box<dyn Error>>;
-
+use super::page::{ PagePointer, PAGE_SIZE, Page, PageBuffer, JOURNAL_CAPACITY, LEAF_RECORD_COUNT, DIR_KEY_COUNT, LOG_CAPACITY };
+use super::page::{ LeafPage, DirectoryPage, MetadataPage, FreePage, Summary, LAYOUT, LAYOUT_COUNTS };
+use super::storage::{ Storage, FileStorage, MemStorage };
+#[cfg(feature = "mmap")]
+use super::storage::MmapStorage;
+#[cfg(feature = "compression")]
+use super::storage::CompressedStorage;
+#[cfg(feature = "encryption")]
+use super::storage::EncryptedStorage;
+use super::journal::{ self, Journal, PendingPages };
+use super::error::{ BPlusError, BPlusResult };
+use super::ttl::{ Clock, KeyRanges, SystemClock };
+use super::changelog::{ ChangeLog, LoggedChange, Mutation };
+use super::backup::{ copy_pages, check_tree_page, write_delta, apply_delta, BackupInfo, BackupProgress, RestorePoint };
+use super::generations::GenerationMap;
+use super::replication::{ PageBatch, Replica };
+use std::sync::mpsc::Receiver;
+
+/// The most records that one operation of `delete_range` deletes.
+/// Other than at the ends, leaves are at least half full, so the
+/// operation frees at most JOURNAL_CAPACITY/4 leaves (and a few 
+/// directory pages).
+const DELETE_RANGE_CHUNK: u64 = (JOURNAL_CAPACITY / 4 * LEAF_RECORD_COUNT / 2) as u64;
+
+/// The number of expiry index entries that an iterator reads ahead
+/// at a time (about one leaf's worth)
+const EXPIRY_READ_AHEAD: usize = LEAF_RECORD_COUNT;
+
+/// When a BPlusTree makes its writes durable
+///
+/// Except under `Never`, writes are collected in memory and 
+/// committed to storage as a single atomic batch through the 
+/// tree's journal, so a crash can't leave the tree half-updated.
+/// A batch always holds whole operations.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SyncPolicy
+{
+  /// Commit and sync at the end of every operation
+  Always,
+  /// Group commit: commit and sync once this many operations have
+  /// completed since the last sync
+  EveryOps(u32),
+  /// Group commit: commit and sync at the end of the first
+  /// operation that completes this long after the last sync.  The
+  /// interval is only checked when an operation ends, so writes 
+  /// made just before the tree goes idle wait for the next 
+  /// operation (or an explicit `BPlusTree::sync`, or the tree being
+  /// dropped).
+  Interval(Duration),
+  /// Write pages straight to storage, and never sync unless asked
+  /// to (see `BPlusTree::sync`).  A crash may lose or tear writes.
+  Never,
+}
 
 #[derive(Debug)]
 pub struct BPlusTree
 {
-  file: File,
-  meta: MetadataPage
+  storage: Box<dyn Storage>,
+  meta: MetadataPage,
+
+  /// True if `meta` has changes that haven't been written yet
+  meta_dirty: bool,
+
+  policy: SyncPolicy,
+  journal: Option<Journal>,
+  /// Page writes waiting for the next commit (empty under 
+  /// SyncPolicy::Never)
+  pending: PendingPages,
+  ops_since_sync: u32,
+  last_sync: Instant,
+
+  /// The expiry time of each key that has one, if the tree keeps
+  /// expiry times (see `put_expiring`)
+  expiries: Option<Box<BPlusTree>>,
+  /// The time that expiry times are compared against
+  clock: Box<dyn Clock>,
+  /// Keys whose expiry times are to be dropped from `expiries` 
+  /// once the writes that made them stale are committed
+  stale_expiries: KeyRanges,
+
+  /// The log of changes to the tree, if it keeps one (see
+  /// `set_change_log`)
+  changes: Option<ChangeLog>,
+  /// The generation in which each page was last written, if the 
+  /// tree tracks it (see `set_generation_map`)
+  generations: Option<GenerationMap>,
+  /// Where committed batches are sent, if the tree has followers
+  /// (see `set_replica`)
+  replica: Option<Box<dyn Replica>>,
+  /// Why the last replica was let go, if it failed (see
+  /// `take_replica_error`)
+  replica_error: Option<BPlusError>,
+  /// The backup being taken between other operations, if any (see
+  /// `begin_backup`)
+  backup: Option<BackupProgress>,
 }
 
 #[derive(Debug)]
@@ -25,12 +109,118 @@ pub struct BPlusTreeIterator<'a>
 {
   tree: &'a mut BPlusTree,
   page: LeafPage,
-  idx: usize
+  idx: usize,
+  end: Bound<u32>,
+  /// The upcoming entries of the tree's expiry index, read ahead so
+  /// that expired records can be skipped without a lookup apiece
+  expiring: VecDeque<(u32, u32)>,
+  /// The key to read the next entries of the expiry index from, or
+  /// None once there are no more
+  expiring_from: Option<u32>,
+}
+
+/// A position in a BPlusTree that can move in both directions, 
+/// and update or delete the record it is on
+///
+/// The cursor stays valid across the changes it makes itself: 
+/// after `delete_current`, it is on the record after the deleted 
+/// one, even if the deletion merged or rebalanced leaves.
+///
+/// Like the iterators, a cursor skips over expired entries (see 
+/// `BPlusTree::put_expiring`).
+#[derive(Debug)]
+pub struct Cursor<'a>
+{
+  tree: &'a mut BPlusTree,
+  /// The leaf holding the current record, and a copy of it
+  ptr: PagePointer,
+  page: LeafPage,
+  /// The index of the current record on `page`.  Past the last 
+  /// record of the tree, this is `page.count` on the last leaf.
+  idx: usize,
+  /// True if the cursor is before the first record of the tree
+  before_start: bool,
+}
+
+/// The count, sum, least and greatest of the values in a range of
+/// keys (see `BPlusTree::aggregate`)
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct Aggregate
+{
+  pub count: u64,
+  pub sum: u64,
+  /// The least value, or None if the range is empty
+  pub min: Option<u32>,
+  /// The greatest value, or None if the range is empty
+  pub max: Option<u32>,
+}
+
+#[allow(dead_code)]
+impl Aggregate
+{
+  /// Add one value
+  fn add(&mut self, value: u32)
+  {
+    self.count += 1;
+    self.sum += value as u64;
+    self.min = Some(self.min.map_or(value, |m| m.min(value)));
+    self.max = Some(self.max.map_or(value, |m| m.max(value)));
+  }
+
+  /// Add the `count` values that `summary` summarizes
+  #[cfg(feature = "aggregates")]
+  fn include(&mut self, count: u32, summary: &Summary)
+  {
+    if count == 0 { return }
+    self.count += count as u64;
+    self.sum += summary.sum;
+    self.min = Some(self.min.map_or(summary.min, |m| m.min(summary.min)));
+    self.max = Some(self.max.map_or(summary.max, |m| m.max(summary.max)));
+  }
+}
+
+/// The path of the journal for the tree at `path`
+fn journal_path(path: &String) -> String
+{
+  format!("{}.journal", path)
+}
+
+/// The lowest and highest keys in a range, or None if it's empty
+fn inclusive_bounds<R: RangeBounds<u32>>(range: &R) -> Option<(u32, u32)>
+{
+  let low = match range.start_bound()
+  {
+    Bound::Included(k) => *k,
+    Bound::Excluded(k) => k.checked_add(1)?,
+    Bound::Unbounded   => 0,
+  };
+  let high = match range.end_bound()
+  {
+    Bound::Included(k) => *k,
+    Bound::Excluded(k) => k.checked_sub(1)?,
+    Bound::Unbounded   => u32::MAX,
+  };
+  if low > high { None } else { Some((low, high)) }
+}
+
+/// The path of the expiry index for the tree at `path`
+fn expiries_path(path: &String) -> String
+{
+  format!("{}.ttl", path)
+}
+
+/// The path of the change log for the tree at `path`
+fn changes_path(path: &String) -> String
+{
+  format!("{}.changes", path)
 }
 
-fn seek_addr(idx: PagePointer) -> SeekFrom
+/// Open the journal for the tree at `path`, creating it if needed
+fn open_journal_file(path: &String) -> BPlusResult<FileStorage>
 {
-  SeekFrom::Start(idx * (PAGE_SIZE as u64))
+  let journal = journal_path(path);
+  if Path::new(&journal).exists() { FileStorage::open(&journal) }
+  else                            { FileStorage::create(&journal) }
 }
 
 #[allow(dead_code)]
@@ -38,18 +228,156 @@ impl BPlusTree
 {
 
   /// Initialize a brand new BPlusTree at the provided path
+  ///
+  /// The tree's journal is kept next to it, at `path.journal`
   pub fn init(path: &String) -> BPlusResult<BPlusTree>
   {
-    let mut file = 
-      OpenOptions::new()
-                 .create(true)   // Create file if not present
-                 .truncate(true) // Empty the file if it is
-                 .read(true)     // Allow reads
-                 .write(true)    // Allow writes
-                 .open(path)?;
+    BPlusTree::init_journaled(
+      Box::new(FileStorage::create(path)?),
+      Box::new(FileStorage::create(&journal_path(path))?)
+    )
+  }
+
+  /// Open an existing BPlusTree at the provided path
+  pub fn open(path: &String) -> BPlusResult<BPlusTree>
+  {
+    BPlusTree::open_journaled(
+      Box::new(FileStorage::open(path)?),
+      Box::new(open_journal_file(path)?)
+    )
+  }
+
+  /// Initialize a brand new BPlusTree at the provided path, with
+  /// support for expiring entries (see `put_expiring`)
+  ///
+  /// The expiry times are kept in a tree of their own, at 
+  /// `path.ttl`
+  pub fn init_expiring(path: &String) -> BPlusResult<BPlusTree>
+  {
+    let mut tree = BPlusTree::init(path)?;
+    tree.set_expiries(BPlusTree::init(&expiries_path(path))?)?;
+    Ok(tree)
+  }
+
+  /// Open an existing BPlusTree at the provided path, with support
+  /// for expiring entries.  The expiry index is created if the tree
+  /// doesn't have one yet.
+  pub fn open_expiring(path: &String) -> BPlusResult<BPlusTree>
+  {
+    let mut tree = BPlusTree::open(path)?;
+    let ttl_path = expiries_path(path);
+    let expiries = 
+      if Path::new(&ttl_path).exists() { BPlusTree::open(&ttl_path)? }
+      else                             { BPlusTree::init(&ttl_path)? };
+    tree.set_expiries(expiries)?;
+    Ok(tree)
+  }
+
+  /// Initialize a brand new BPlusTree at the provided path, that
+  /// logs its changes (see `changes_since`)
+  ///
+  /// The log is kept next to the tree, at `path.changes`
+  pub fn init_logged(path: &String) -> BPlusResult<BPlusTree>
+  {
+    let mut tree = BPlusTree::init(path)?;
+    tree.set_change_log(Box::new(FileStorage::create(&changes_path(path))?))?;
+    Ok(tree)
+  }
+
+  /// Open an existing BPlusTree at the provided path, that logs its
+  /// changes.  The log is created if the tree doesn't have one yet.
+  pub fn open_logged(path: &String) -> BPlusResult<BPlusTree>
+  {
+    let mut tree = BPlusTree::open(path)?;
+    let log_path = changes_path(path);
+    let log = 
+      if Path::new(&log_path).exists() { FileStorage::open(&log_path)? }
+      else                             { FileStorage::create(&log_path)? };
+    tree.set_change_log(Box::new(log))?;
+    Ok(tree)
+  }
+
+  /// Initialize a brand new BPlusTree held entirely in memory
+  pub fn init_in_memory() -> BPlusResult<BPlusTree>
+  {
+    BPlusTree::init_journaled(Box::new(MemStorage::new()), Box::new(MemStorage::new()))
+  }
+
+  /// Open an existing BPlusTree at the provided path, and serve 
+  /// reads from a memory map of the file.
+  ///
+  /// Lookups through `get` view pages directly in the mapping 
+  /// instead of copying them out of the file (see `view_page`).
+  #[cfg(feature = "mmap")]
+  pub fn open_mmap(path: &String) -> BPlusResult<BPlusTree>
+  {
+    BPlusTree::open_journaled(
+      Box::new(MmapStorage::open(path)?),
+      Box::new(open_journal_file(path)?)
+    )
+  }
+
+  /// Initialize a brand new BPlusTree at the provided path, with
+  /// leaf pages stored compressed (see CompressedStorage)
+  #[cfg(feature = "compression")]
+  pub fn init_compressed(path: &String) -> BPlusResult<BPlusTree>
+  {
+    let file = FileStorage::create(path)?.resized(CompressedStorage::BLOCK_SIZE);
+    BPlusTree::init_journaled(
+      Box::new(CompressedStorage::init(Box::new(file))?),
+      Box::new(FileStorage::create(&journal_path(path))?)
+    )
+  }
+
+  /// Open an existing BPlusTree created by `init_compressed`
+  #[cfg(feature = "compression")]
+  pub fn open_compressed(path: &String) -> BPlusResult<BPlusTree>
+  {
+    let file = FileStorage::open(path)?.resized(CompressedStorage::BLOCK_SIZE);
+    BPlusTree::open_journaled(
+      Box::new(CompressedStorage::open(Box::new(file))?),
+      Box::new(open_journal_file(path)?)
+    )
+  }
+
+  /// Initialize a brand new BPlusTree at the provided path, with
+  /// every page (and the journal) encrypted with the provided key
+  /// (see EncryptedStorage)
+  #[cfg(feature = "encryption")]
+  pub fn init_encrypted(path: &String, key: &[u8; 32]) -> BPlusResult<BPlusTree>
+  {
+    let file = FileStorage::create(path)?.resized(EncryptedStorage::INNER_PAGE_SIZE);
+    let journal = FileStorage::create(&journal_path(path))?.resized(EncryptedStorage::INNER_PAGE_SIZE);
+    BPlusTree::init_journaled(
+      Box::new(EncryptedStorage::for_tree(Box::new(file), key)?),
+      Box::new(EncryptedStorage::new(Box::new(journal), key)?)
+    )
+  }
+
+  /// Open an existing BPlusTree created by `init_encrypted`
+  ///
+  /// Fails if the key doesn't match the one the tree was created
+  /// with.
+  #[cfg(feature = "encryption")]
+  pub fn open_encrypted(path: &String, key: &[u8; 32]) -> BPlusResult<BPlusTree>
+  {
+    let file = FileStorage::open(path)?.resized(EncryptedStorage::INNER_PAGE_SIZE);
+    let journal = open_journal_file(path)?.resized(EncryptedStorage::INNER_PAGE_SIZE);
+    BPlusTree::open_journaled(
+      Box::new(EncryptedStorage::open(Box::new(file), key)?),
+      Box::new(EncryptedStorage::new(Box::new(journal), key)?)
+    )
+  }
+
+  /// Initialize a brand new BPlusTree in the provided storage
+  ///
+  /// Any existing contents of the storage are overwritten.
+  pub fn init_with(mut storage: Box<dyn Storage>) -> BPlusResult<BPlusTree>
+  {
+    BPlusTree::check_storage(storage.as_ref())?;
 
     // Write initial metadata page
-    let meta = MetadataPage::init(
+    let mut meta = MetadataPage::init(
       /* next_free_page */  NULL_IDX,
       /* root_page */       DEFAULT_ROOT_IDX,
       /* data_head */       DEFAULT_PAGE0_IDX,
@@ -57,36 +385,124 @@ impl BPlusTree
       /* pages_allocated */ 3,
       /* depth */           1,
     );
-    file.seek(seek_addr(METADATA_IDX))?;
-    meta.write(&mut file)?;
+    meta.cipher = storage.cipher();
+    meta.key_check = storage.key_check();
+    storage.allocate(meta.pages_allocated)?;
 
     // Write initial root directory page
     let mut root = DirectoryPage::init();
     root.pointers[0] = DEFAULT_PAGE0_IDX;
-    file.seek(seek_addr(DEFAULT_ROOT_IDX))?;
-    root.write(&mut file)?;
+    root.write(storage.as_mut(), DEFAULT_ROOT_IDX)?;
 
     // Write initial data page
     let data = LeafPage::init();
-    file.seek(seek_addr(DEFAULT_PAGE0_IDX))?;
-    data.write(&mut file)?;
+    data.write(storage.as_mut(), DEFAULT_PAGE0_IDX)?;
 
-    Ok(BPlusTree { file, meta })
+    // The metadata page goes last, after the pages it points to
+    meta.write(storage.as_mut(), METADATA_IDX)?;
+    storage.sync()?;
+
+    Ok(BPlusTree::from_parts(storage, meta, None))
   }
 
-  /// Open an existing BPlusTree at the provided path
-  pub fn open(path: &String) -> BPlusResult<BPlusTree>
+  /// Open an existing BPlusTree in the provided storage
+  ///
+  /// Trees without a journal only support SyncPolicy::Never.
+  pub fn open_with(mut storage: Box<dyn Storage>) -> BPlusResult<BPlusTree>
+  {
+    BPlusTree::check_storage(storage.as_ref())?;
+    let meta = MetadataPage::read(storage.as_mut(), METADATA_IDX)?;
+    meta.check(METADATA_IDX)?;
+    BPlusTree::check_meta(&meta, storage.as_ref())?;
+
+    Ok(BPlusTree::from_parts(storage, meta, None))
+  }
+
+  /// Return an error unless a tree with metadata `meta` can be read
+  /// from `storage` by this build
+  fn check_meta(meta: &MetadataPage, storage: &dyn Storage) -> BPlusResult<()>
+  {
+    if meta.cipher != storage.cipher()
+    {
+      return Err(BPlusError::IncompatibleStorage(format!("Tree is encrypted with cipher {}, but storage uses cipher {}", meta.cipher, storage.cipher())))
+    }
+    if meta.key_check != storage.key_check()
+    {
+      return Err(BPlusError::WrongKey)
+    }
+    if meta.layout & LAYOUT_COUNTS == 0
+    {
+      return Err(BPlusError::IncompatibleStorage(format!("Tree has page layout {}, from before directory pages held record counts, and must be rebuilt", meta.layout)))
+    }
+    if meta.layout != LAYOUT
+    {
+      return Err(BPlusError::IncompatibleStorage(format!("Tree has page layout {}, but this build uses layout {} (see the `aggregates` and `key-compression` features)", meta.layout, LAYOUT)))
+    }
+    Ok(())
+  }
+
+  /// Initialize a brand new BPlusTree in the provided storage, 
+  /// using `journal` to make commits atomic.
+  pub fn init_journaled(storage: Box<dyn Storage>, journal: Box<dyn Storage>) 
+    -> BPlusResult<BPlusTree>
+  {
+    BPlusTree::check_storage(journal.as_ref())?;
+    let mut tree = BPlusTree::init_with(storage)?;
+    tree.journal = Some(Journal::init(journal)?);
+    Ok(tree)
+  }
+
+  /// Open an existing BPlusTree in the provided storage, first 
+  /// recovering any commit interrupted by a crash from `journal`.
+  pub fn open_journaled(mut storage: Box<dyn Storage>, journal: Box<dyn Storage>) 
+    -> BPlusResult<BPlusTree>
+  {
+    BPlusTree::check_storage(journal.as_ref())?;
+    let mut journal = Journal::open(journal);
+    journal.recover(storage.as_mut())?;
+    let mut tree = BPlusTree::open_with(storage)?;
+    tree.journal = Some(journal);
+    Ok(tree)
+  }
+
+  fn from_parts(storage: Box<dyn Storage>, meta: MetadataPage, journal: Option<Journal>) 
+    -> BPlusTree
   {
-    let mut file = 
-      OpenOptions::new()
-                 .read(true)     // Allow reads
-                 .write(true)    // Allow writes
-                 .open(path)?;
+    BPlusTree {
+      storage,
+      meta,
+      meta_dirty: false,
+      policy: SyncPolicy::Never,
+      journal,
+      pending: PendingPages::new(),
+      ops_since_sync: 0,
+      last_sync: Instant::now(),
+      expiries: None,
+      clock: Box::new(SystemClock),
+      stale_expiries: KeyRanges::default(),
+      changes: None,
+      generations: None,
+      replica: None,
+      replica_error: None,
+      backup: None,
+    }
+  }
 
-    file.seek(seek_addr(METADATA_IDX))?;
-    let meta = MetadataPage::read(&mut file)?;
+  fn check_storage(storage: &dyn Storage) -> BPlusResult<()>
+  {
+    if storage.page_size() != PAGE_SIZE
+    {
+      return Err(BPlusError::IncompatibleStorage(format!("Storage has {} byte pages, but {} byte pages are required", storage.page_size(), PAGE_SIZE)))
+    }
+    Ok(())
+  }
 
-    Ok(BPlusTree { file, meta })
+  /// Return true if pages can be read in place, without copying
+  /// them (e.g., because the storage is memory mapped and pages 
+  /// aren't compressed)
+  pub fn can_view_pages(&self) -> bool
+  {
+    DirectoryPage::VIEWABLE && LeafPage::VIEWABLE && self.storage.view(METADATA_IDX).is_some()
   }
 
   ////////////////////////////////////////////////////////////////
@@ -112,9 +528,10 @@ impl BPlusTree
     if self.meta.next_free_page == NULL_IDX
     {
       let ptr = self.meta.pages_allocated;
+      self.storage.allocate(ptr + 1)?;
       self.meta.pages_allocated += 1;
-      self.put_meta()?;
       self.put_page(ptr, page)?;
+      self.put_meta()?;
       return Ok(ptr);
     }
     else 
@@ -122,8 +539,8 @@ impl BPlusTree
       let ptr = self.meta.next_free_page;
       let free = self.get_page::<FreePage>(ptr)?;
       self.meta.next_free_page = free.next_free_page;
-      self.put_meta()?;
       self.put_page(ptr, page)?;
+      self.put_meta()?;
       return Ok(ptr);
     }
     // END SNIP
@@ -165,9 +582,34 @@ impl BPlusTree
   /// - Have an O(1) runtime 
   pub fn get_page<T: Page>(&mut self, ptr: PagePointer) -> BPlusResult<T>
   {
-    self.file.seek(seek_addr(ptr))?;
-    let ret = T::read(&mut self.file)?;
-    assert!(ret.page_type() == T::EXPECTED_PAGE_TYPE);
+    let ret = 
+      if let Some(buffer) = self.pending.get(&ptr) { T::decode(&buffer.0) }
+      else 
+      {
+        match self.storage.view(ptr)
+        {
+          Some(bytes) => T::decode(bytes.first_chunk().ok_or(BPlusError::corrupt(ptr, "Page is truncated"))?),
+          None        => T::read(self.storage.as_mut(), ptr)?
+        }
+      };
+    ret.check(ptr)?;
+    Ok(ret)
+  }
+
+  /// View a page in place, without copying it.
+  ///
+  /// Only available if the storage supports it (see 
+  /// `can_view_pages`).
+  pub fn view_page<T: Page>(&self, ptr: PagePointer) -> BPlusResult<&T>
+  {
+    let bytes = 
+      match self.pending.get(&ptr)
+      {
+        Some(buffer) => &buffer.0,
+        None => self.storage.view(ptr).ok_or(BPlusError::Unsupported(format!("Page {} can not be viewed in place", ptr)))?
+      };
+    let ret = T::view(bytes.first_chunk().ok_or(BPlusError::corrupt(ptr, "Page is truncated"))?)?;
+    ret.check(ptr)?;
     Ok(ret)
   }
 
@@ -180,235 +622,579 @@ impl BPlusTree
   pub fn put_page<T: Page>(&mut self, ptr: PagePointer, page: &T) -> BPlusResult<()>
   {
     // SNIP ALT:todo!()
-    self.file.seek(seek_addr(ptr))?;
-    page.write(&mut self.file)
+    if let Some(generations) = self.generations.as_mut()
+    {
+      generations.stamp(ptr, self.meta.generation)?;
+    }
+    if let Some(backup) = self.backup.as_mut()
+    {
+      backup.written(ptr);
+    }
+    if self.policy == SyncPolicy::Never
+    {
+      page.write(self.storage.as_mut(), ptr)
+    }
+    else
+    {
+      self.pending.insert(ptr, PageBuffer::encode(page)?);
+      Ok(())
+    }
   }
 
   /// Write the metadata page to disk
   ///
-  /// Shorthand for self.put_page(METADATA_IDX, self.meta)
+  /// The write is deferred until the end of the current operation
+  /// (or the next `sync`), so that the metadata page always reaches
+  /// the disk after the pages it points to.
   pub fn put_meta(&mut self) -> BPlusResult<()>
   {
-    self.put_page(METADATA_IDX, &self.meta.clone())
+    self.meta_dirty = true;
+    Ok(())
+  }
+
+  /// Write out the metadata page if it has changed
+  ///
+  /// Under SyncPolicy::Never the page goes straight to storage, and
+  /// the change log (if any) is synced before it, so that the 
+  /// metadata's `last_seq` never reaches the disk ahead of the 
+  /// changes it counts.  Under the other policies, `sync` syncs the
+  /// log before each commit.
+  fn flush_meta(&mut self) -> BPlusResult<()>
+  {
+    if self.meta_dirty
+    {
+      if let (SyncPolicy::Never, Some(changes)) = (self.policy, self.changes.as_mut())
+      {
+        changes.sync()?;
+      }
+      self.meta_dirty = false;
+      self.put_page(METADATA_IDX, &self.meta.clone())?;
+    }
+    Ok(())
   }
 
   ////////////////////////////////////////////////////////////////
-  ////////////////////// Read Methods ////////////////////////////
+  ////////////////////// Durability //////////////////////////////
   ////////////////////////////////////////////////////////////////
 
-  /// Retrieve a specific key, if present
-  pub fn get(&mut self, key: u32) -> BPlusResult<Option<u32>>
+  /// Change when writes are made durable.  Any writes still 
+  /// waiting on the old policy are committed first.
+  ///
+  /// Every policy other than SyncPolicy::Never requires a journal
+  /// (see `init_journaled`).
+  pub fn set_sync_policy(&mut self, policy: SyncPolicy) -> BPlusResult<()>
   {
-    let v = self.find_page(key)?;
-    let ptr = v[v.len()-1];
-    let page = self.get_page::<LeafPage>(ptr)?;
-    Ok(page.find_value(key))
+    if policy != SyncPolicy::Never && self.journal.is_none()
+    {
+      return Err(BPlusError::Unsupported(format!("{:?} requires a tree with a journal", policy)))
+    }
+    if policy == SyncPolicy::Never && self.replica.is_some()
+    {
+      return Err(BPlusError::Unsupported("A tree with followers can't use SyncPolicy::Never".into()))
+    }
+    self.sync()?;
+    self.policy = policy;
+    if let Some(expiries) = self.expiries.as_mut()
+    {
+      expiries.set_sync_policy(policy)?;
+    }
+    Ok(())
   }
 
-  /// Iterate over all of the data values
-  pub fn iter<'a>(&'a mut self) -> BPlusResult<BPlusTreeIterator<'a>>
+  /// The current sync policy
+  pub fn sync_policy(&self) -> SyncPolicy
   {
-    let data_idx = self.meta.data_head.to_owned();
-    let data_page = self.get_page::<LeafPage>(data_idx)?;
-
-    Ok(BPlusTreeIterator { 
-      tree: self, 
-      page: data_page, 
-      idx: 0
-    })
+    self.policy
   }
 
-  ////////////////////////////////////////////////////////////////
-  /////////////////// Part 2: Insertion //////////////////////////
-  ////////////////////////////////////////////////////////////////
-
-
-  /// Insert a new key/value pair into the dataset.
-  ///
-  /// With N records and K keys per directory page, this 
-  /// function's asymptotic bounds should be:
-  /// - Memory: O(log_K(N))
-  /// - IO: O(log_K(N)) reads, O(1) amortized writes.
-  ///
-  /// Amortized bounds may assume no intervening calls
-  /// to delete()
-  ///
-  /// You are encouraged to write this function in several steps:
-  /// 1. First solve the case where the leaf that would hold 
-  ///    key has sufficient space; Leave a todo!() for the 
-  ///    other case
-  /// 2. Then solve the case where the leaf that would hold
-  ///    key needs to be split; Leave a todo!() for the case
-  ///    where the parent directory page needs to be split.
-  /// 3. Then solve the case where the parent directory page
-  ///    is the root and needs to be split.  Leave a todo!() for
-  ///    the case where a non-root directory page needs to be
-  ///    split.
-  /// 4. Finally solve the case where the a non-root directory 
-  ///    page needs to be split.
-  /// 
-  /// You are also encouraged to use several helper functions:
-  /// - LeafPage::split()
-  /// - DirectoryPage::split_ptr()
-  /// - DirectoryPage::split()
-  /// - BPlusTree::find_page()
+  /// Make every completed write durable.
   ///
-  pub fn put(&mut self, key: u32, value: u32) -> BPlusResult<()>
+  /// Pending writes are committed atomically through the journal
+  /// if there is one.  The metadata page is always written after 
+  /// the pages it points to have been synced.  The expiry index,
+  /// change log, and generation map (if any) are synced first, and
+  /// each committed batch is then sent to the replica (if any).
+  /// A replica that fails to take a batch is let go, without 
+  /// failing the commit (see `take_replica_error`).
+  /// Expiry times made stale by the batch are only dropped once it
+  /// is committed (see `put_expiring`).
+  pub fn sync(&mut self) -> BPlusResult<()>
   {
-    // BEGIN SNIP
-    // SNIP ALT:todo!()
-    let ptr_stack = self.find_page(key)?;
-    // println!("{:?}", ptr_stack);
-
-    let leaf_ptr = ptr_stack[ptr_stack.len()-1];
-
-    let mut leaf = self.get_page::<LeafPage>(leaf_ptr)?;
-    // println!("BEFORE: {:?}", leaf);
-    if leaf.is_full()
+    if let Some(expiries) = self.expiries.as_mut()
     {
-      // Split required
-      // println!("BEFORE: {:?}", leaf);
-      let (split_key, new_leaf_ptr, mut new_leaf) = 
-        self.split_leaf(&mut leaf, ptr_stack.borrow())?;
-      assert!(!leaf.is_full());
-      assert!(!new_leaf.is_full());
-      if key < split_key
-      {
-        leaf.put(key, value)?;
-        self.put_page(leaf_ptr, &leaf)?;
-      }
-      else 
+      expiries.sync()?;
+    }
+    if let Some(changes) = self.changes.as_mut()
+    {
+      changes.sync()?;
+    }
+    if let Some(generations) = self.generations.as_mut()
+    {
+      generations.sync()?;
+    }
+    if self.policy == SyncPolicy::Never
+    {
+      if self.meta_dirty
       {
-        new_leaf.put(key, value)?;
-        self.put_page(new_leaf_ptr, &new_leaf)?;
+        self.storage.sync()?;
+        self.flush_meta()?;
       }
-      // println!("AFTER: {:?}", leaf);
-      // println!("AFTER: {:?}", new_leaf);
-      // println!("AFTER: {:?}", self.meta);
+      self.storage.sync()?;
     }
     else
     {
-      // Split not required
-      leaf.put(key, value)?;
-      self.put_page(leaf_ptr, &leaf)?;
+      let counted = self.meta_dirty || !self.pending.is_empty();
+      if counted
+      {
+        self.meta.commits += 1;
+        self.meta_dirty = true;
+      }
+      let committed = self.flush_meta().and_then(|()| 
+        match self.journal.as_mut()
+        {
+          Some(journal) => journal.commit(&self.pending, self.storage.as_mut()),
+          None          => Ok(())
+        });
+      if let Err(err) = committed
+      {
+        // The batch keeps its number for the next attempt, which 
+        // rewrites the metadata page with it
+        if counted
+        {
+          self.meta.commits -= 1;
+          self.meta_dirty = true;
+        }
+        return Err(err)
+      }
+      let batch = if self.replica.is_some() { self.committed_batch() } else { None };
+      self.pending.clear();
+      if let (Some(replica), Some(batch)) = (self.replica.as_mut(), batch)
+      {
+        if let Err(err) = replica.send(&batch)
+        {
+          // The batch is durable all the same.  Followers can't skip
+          // it, so the replica is let go (see `take_replica_error`).
+          self.replica = None;
+          self.replica_error = Some(err);
+        }
+      }
+      self.drop_stale_expiries()?;
     }
-    // println!("AFTER: {:?}", leaf);
-
+    self.ops_since_sync = 0;
+    self.last_sync = Instant::now();
     Ok(())
-    // END SNIP
   }
 
-  // BEGIN SNIP
-  pub fn split_leaf(&mut self, leaf: &mut LeafPage, ptr_stack: &[PagePointer]) 
-    -> BPlusResult<(u32, PagePointer, LeafPage)>
+  /// Mark the end of a public operation, syncing if the policy 
+  /// calls for it.
+  fn end_op(&mut self) -> BPlusResult<()>
   {
-    assert!(leaf.is_full());
-    let leaf_ptr = ptr_stack[ptr_stack.len()-1];
-    let mut new_leaf = leaf.split();
-    let split_key = new_leaf.get(0).0;
-    new_leaf.prev = leaf_ptr;
-    new_leaf.next = leaf.next;
-    let new_leaf_ptr = self.alloc_page(&new_leaf)?;
-    if new_leaf.next == NULL_IDX 
-    {
-      self.meta.data_tail = new_leaf_ptr;
-      self.put_meta()?;
-    } else 
+    self.ops_since_sync += 1;
+    let due = 
+      match self.policy
+      {
+        SyncPolicy::Always            => true,
+        SyncPolicy::EveryOps(n)       => self.ops_since_sync >= n,
+        SyncPolicy::Interval(period)  => self.last_sync.elapsed() >= period,
+        SyncPolicy::Never             => false,
+      };
+    // A single operation touches O(depth) pages, so leave enough
+    // room in the journal for one more.
+    let full = self.pending.len() + 4 * (self.meta.depth as usize + 2) > JOURNAL_CAPACITY;
+    if due || full { self.sync() } 
+    else           { self.flush_meta() }
+  }
+
+  /// Commit the pending writes if they fill more than half of the
+  /// journal
+  ///
+  /// Operations that write more pages than the journal holds call
+  /// this as they go, wherever the tree is consistent, and so are
+  /// only atomic in parts.
+  fn reserve_journal(&mut self) -> BPlusResult<()>
+  {
+    if self.pending.len() > JOURNAL_CAPACITY / 2 { self.sync()?; }
+    Ok(())
+  }
+
+  /// The number of page writes waiting for the next commit
+  pub fn pending_pages(&self) -> usize
+  {
+    self.pending.len()
+  }
+
+  ////////////////////////////////////////////////////////////////
+  ////////////////////// Read Methods ////////////////////////////
+  ////////////////////////////////////////////////////////////////
+
+  /// Retrieve a specific key, if present
+  pub fn get(&mut self, key: u32) -> BPlusResult<Option<u32>>
+  {
+    if self.can_view_pages()
     {
-      let mut old_next = self.get_page::<LeafPage>(new_leaf.next)?;
-      old_next.prev = new_leaf_ptr;
-      self.put_page(new_leaf.next, &old_next)?
+      // Descend through the storage without copying any pages
+      let mut ptr = self.meta.root_page;
+      for _i in 0 .. self.meta.depth
+      {
+        ptr = self.view_page::<DirectoryPage>(ptr)?.find_pointer(key);
+      }
+      let value = self.view_page::<LeafPage>(ptr)?.find_value(key);
+      return self.unless_expired(key, value)
     }
-    leaf.next = new_leaf_ptr;
-    self.put_page(leaf_ptr, &leaf.clone())?;
+    let v = self.find_page(key)?;
+    let ptr = v[v.len()-1];
+    let page = self.get_page::<LeafPage>(ptr)?;
+    self.unless_expired(key, page.find_value(key))
+  }
 
-    self.split_dir_entry(ptr_stack, split_key, new_leaf_ptr)?;
+  /// Retrieve a batch of keys
+  ///
+  /// Returns the value of each key (or None), in the order of
+  /// `keys`.  The batch is sorted, and every page on the way to the
+  /// batch's leaves is read only once.
+  pub fn get_many(&mut self, keys: &[u32]) -> BPlusResult<Vec<Option<u32>>>
+  {
+    let mut order: Vec<usize> = (0 .. keys.len()).collect();
+    order.sort_by_key(|i| keys[*i]);
+    let sorted: Vec<u32> = order.iter().map(|i| keys[*i]).collect();
 
-    return Ok( (split_key, new_leaf_ptr, new_leaf) )
+    let mut ret = vec![None; keys.len()];
+    for (ptr, range) in self.group_by_leaf(&sorted)?
+    {
+      let leaf = self.get_page::<LeafPage>(ptr)?;
+      for i in range
+      {
+        ret[order[i]] = self.unless_expired(sorted[i], leaf.find_value(sorted[i]))?;
+      }
+    }
+    Ok(ret)
   }
 
-  /// Add a new pointer to a directory page by splitting an
-  /// existing pointer into two.
+  /// Partition sorted keys by the leaf page that would hold them.
   ///
-  /// - `ptr_stack`: The leaf page pointer and its ancestors 
-  ///    (see find_page)
+  /// Returns each leaf with the range of `keys` that belongs on it.
+  /// Every directory page on the way is read only once.
+  fn group_by_leaf(&mut self, keys: &[u32]) -> BPlusResult<Vec<(PagePointer, Range<usize>)>>
+  {
+    let mut groups = Vec::new();
+    self.group_below(self.meta.root_page, 0, keys, 0, &mut groups)?;
+    Ok(groups)
+  }
+
+  fn group_below(&mut self, ptr: PagePointer, depth: u16, keys: &[u32], offset: usize,
+                 groups: &mut Vec<(PagePointer, Range<usize>)>) -> BPlusResult<()>
+  {
+    if keys.is_empty() { return Ok(()) }
+    if depth >= self.meta.depth
+    {
+      groups.push((ptr, offset .. offset + keys.len()));
+      return Ok(())
+    }
+    let dir = self.get_page::<DirectoryPage>(ptr)?;
+    let mut start = 0;
+    while start < keys.len()
+    {
+      let idx = dir.find_pointer_idx(keys[start]);
+      let end = 
+        if idx < dir.count { start + keys[start..].partition_point(|k| *k < dir.keys[idx]) }
+        else               { keys.len() };
+      self.group_below(dir.pointers[idx], depth+1, &keys[start .. end], offset + start, groups)?;
+      start = end;
+    }
+    Ok(())
+  }
+
+  /// The number of keys strictly less than `key`
   ///
-  /// With N records, K keys per directory page, and a directory 
-  /// page at depth D < O(log_K(N)), this function should:
-  /// - Use O(D) memory
-  /// - Perform O(D) unqualified, O(1) amortized IOs
-  /// - Have a O(D) unqualified, O(1) amortized runtime
-  /// 
+  /// Uses the record counts kept alongside each directory pointer,
+  /// so only the pages on the way to `key`'s leaf are read.
+  pub fn rank(&mut self, key: u32) -> BPlusResult<u64>
+  {
+    let mut ret = 0;
+    let mut ptr = self.meta.root_page;
+    for _i in 0 .. self.meta.depth
+    {
+      let dir = self.get_page::<DirectoryPage>(ptr)?;
+      let idx = dir.find_pointer_idx(key);
+      ret += dir.counts[0 .. idx].iter().map(|c| *c as u64).sum::<u64>();
+      ptr = dir.pointers[idx];
+    }
+    let leaf = self.get_page::<LeafPage>(ptr)?;
+    let (Ok(idx) | Err(idx)) = leaf.find_index(key);
+    Ok(ret + idx as u64)
+  }
+
+  /// The record with the `idx`'th smallest key (counting from 0),
+  /// or None if the tree holds no more than `idx` records
   ///
-  pub fn split_dir_entry(&mut self, ptr_stack: &[PagePointer], split_key: u32, new_child_ptr: PagePointer)
-   -> BPlusResult<()>
+  /// Like `rank`, only reads the pages on the way to the record.
+  /// Positions count expired records until they are purged (as 
+  /// `rank` does), but an expired record is itself hidden, and its
+  /// position selects None.
+  pub fn select(&mut self, idx: u64) -> BPlusResult<Option<(u32, u32)>>
   {
-    let child_ptr = ptr_stack[ptr_stack.len()-1];
-    let dir_ptr = ptr_stack[ptr_stack.len()-2];
-    let mut dir_page = self.get_page::<DirectoryPage>(dir_ptr)?;
-    if dir_page.is_full()
+    match self.select_stored(idx)?
     {
-      let (parent_split_key, new_dir_ptr, mut new_dir_page) = 
-        self.split_dir(&mut dir_page, &ptr_stack[0..ptr_stack.len()-1])?;
-      assert!(!dir_page.is_full());
-      assert!(!new_dir_page.is_full());
-      if split_key < parent_split_key
+      Some((key, value)) => Ok(self.unless_expired(key, Some(value))?.map(|value| (key, value))),
+      None               => Ok(None)
+    }
+  }
+
+  /// As `select`, but expired records are returned too
+  fn select_stored(&mut self, idx: u64) -> BPlusResult<Option<(u32, u32)>>
+  {
+    let mut remaining = idx;
+    let mut ptr = self.meta.root_page;
+    for depth in 0 .. self.meta.depth
+    {
+      let dir = self.get_page::<DirectoryPage>(ptr)?;
+      let mut child = 0;
+      while child <= dir.count && remaining >= dir.counts[child] as u64
       {
-        dir_page.split_at_ptr(child_ptr, split_key, new_child_ptr)?;
-        self.put_page(dir_ptr, &dir_page)?;
+        remaining -= dir.counts[child] as u64;
+        child += 1;
       }
-      else 
+      if child > dir.count
       {
-        new_dir_page.split_at_ptr(child_ptr, split_key, new_child_ptr)?;
-        self.put_page(new_dir_ptr, &new_dir_page)?;
+        if depth == 0 { return Ok(None) }
+        return Err(BPlusError::corrupt(ptr, "Holds fewer records than its parent counts"))
       }
-    } else
+      ptr = dir.pointers[child];
+    }
+    let leaf = self.get_page::<LeafPage>(ptr)?;
+    if remaining as usize >= leaf.count
     {
-      dir_page.split_at_ptr(child_ptr, split_key, new_child_ptr)?;
-      self.put_page(dir_ptr, &dir_page)?;
+      return Err(BPlusError::corrupt(ptr, "Holds fewer records than its parent counts"))
     }
-    Ok(())
+    Ok(Some(leaf.get(remaining as usize)))
   }
 
-  pub fn split_dir(&mut self, dir: &mut DirectoryPage, ptr_stack: &[PagePointer]) 
-    -> BPlusResult<(u32, PagePointer, DirectoryPage)>
+  /// The record with the smallest key greater than or equal to 
+  /// `key`, if any
+  ///
+  /// If `key` is past the end of its leaf (or every record after
+  /// it on the leaf has expired), the answer is found on a 
+  /// following leaf.
+  pub fn ceiling(&mut self, key: u32) -> BPlusResult<Option<(u32, u32)>>
   {
-    let dir_ptr = ptr_stack[ptr_stack.len()-1];
-    if ptr_stack.len() <= 1 // root split
+    self.range(key ..)?.next().transpose()
+  }
+
+  /// The record with the greatest key less than or equal to `key`,
+  /// if any
+  ///
+  /// If `key` is before the start of its leaf (or every record 
+  /// before it on the leaf has expired), the answer is found on a
+  /// preceding leaf.
+  pub fn floor(&mut self, key: u32) -> BPlusResult<Option<(u32, u32)>>
+  {
+    let v = self.find_page(key)?;
+    let mut leaf = self.get_page::<LeafPage>(v[v.len()-1])?;
+    // The number of records on the leaf with keys <= key
+    let mut idx = match leaf.find_index(key) { Ok(idx) => idx+1, Err(idx) => idx };
+    loop
     {
-      let (split_key, new_dir_page) = dir.split_page();
-      // self.write_tree()?;
-      // println!("Split {} at {}", dir_ptr, split_key);
-      let new_dir_ptr = self.alloc_page(&new_dir_page)?;
-      self.put_page(dir_ptr, &dir.clone())?;
-      let mut new_root = DirectoryPage::init();
-      new_root.keys[0] = split_key;
-      new_root.pointers[0] = dir_ptr;
-      new_root.pointers[1] = new_dir_ptr;
-      new_root.count = 1;
-      let new_root_ptr = self.alloc_page(&new_root)?;
-      self.meta.root_page = new_root_ptr;
-      self.meta.depth += 1;
-      self.put_meta()?;
-      // self.write_tree()?;
-      Ok( (split_key, new_dir_ptr, new_dir_page) )
-    } else
+      while idx == 0
+      {
+        if leaf.prev == NULL_IDX { return Ok(None) }
+        leaf = self.get_page::<LeafPage>(leaf.prev)?;
+        idx = leaf.count;
+      }
+      idx -= 1;
+      let (key, value) = leaf.get(idx);
+      if !self.is_expired(key)? { return Ok(Some((key, value))) }
+    }
+  }
+
+  /// The record with the smallest key strictly greater than `key`,
+  /// if any
+  pub fn successor(&mut self, key: u32) -> BPlusResult<Option<(u32, u32)>>
+  {
+    match key.checked_add(1)
     {
-      let (split_key, new_dir_page) = dir.split_page();
-      let new_dir_ptr = self.alloc_page(&new_dir_page)?;
-      self.put_page(dir_ptr, &dir.clone())?;
-      self.split_dir_entry(ptr_stack, split_key, new_dir_ptr)?;
-      Ok( (split_key, new_dir_ptr, new_dir_page) )
+      Some(key) => self.ceiling(key),
+      None      => Ok(None)
     }
   }
-  // END SNIP
 
+  /// The record with the greatest key strictly less than `key`,
+  /// if any
+  pub fn predecessor(&mut self, key: u32) -> BPlusResult<Option<(u32, u32)>>
+  {
+    match key.checked_sub(1)
+    {
+      Some(key) => self.floor(key),
+      None      => Ok(None)
+    }
+  }
+
+  /// The record with the smallest key, if any
+  pub fn first(&mut self) -> BPlusResult<Option<(u32, u32)>>
+  {
+    self.iter()?.next().transpose()
+  }
+
+  /// The record with the greatest key, if any
+  pub fn last(&mut self) -> BPlusResult<Option<(u32, u32)>>
+  {
+    self.floor(u32::MAX)
+  }
+
+  /// As `first`, but an expired record is returned too
+  fn first_stored(&mut self) -> BPlusResult<Option<(u32, u32)>>
+  {
+    let leaf = self.get_page::<LeafPage>(self.meta.data_head)?;
+    Ok(if leaf.count > 0 { Some(leaf.get(0)) } else { None })
+  }
+
+  /// As `last`, but an expired record is returned too
+  fn last_stored(&mut self) -> BPlusResult<Option<(u32, u32)>>
+  {
+    let leaf = self.get_page::<LeafPage>(self.meta.data_tail)?;
+    Ok(if leaf.count > 0 { Some(leaf.get(leaf.count-1)) } else { None })
+  }
+
+  /// The number of records in the tree
+  pub fn record_count(&mut self) -> BPlusResult<u64>
+  {
+    let root = self.get_page::<DirectoryPage>(self.meta.root_page)?;
+    Ok(root.total())
+  }
+
+  /// The count, sum, least and greatest of the values with keys in
+  /// the provided range
+  ///
+  /// With the `aggregates` feature, subtrees that lie entirely 
+  /// inside the range are answered from the summaries kept in 
+  /// their parents, so only the leaves at the two ends of the range
+  /// are read.  Without it, every leaf in the range is read.
+  ///
+  /// Expired records are left out, by aggregating the stretches of
+  /// the range between them (as found in the expiry index).
+  pub fn aggregate<R: RangeBounds<u32>>(&mut self, range: R) -> BPlusResult<Aggregate>
+  {
+    let mut agg = Aggregate::default();
+    let Some((mut low, high)) = inclusive_bounds(&range) else { return Ok(agg) };
+    let root = self.meta.root_page;
+    loop
+    {
+      let expired = self.next_expired(low, high)?;
+      if expired != Some(low)
+      {
+        let high = expired.map_or(high, |key| key - 1);
+        self.aggregate_in(root, 0, Some(low), Some(high), &mut agg)?;
+      }
+      match expired.and_then(|key| key.checked_add(1))
+      {
+        Some(next) if next <= high => low = next,
+        _                          => return Ok(agg)
+      }
+    }
+  }
+
+  /// Add the values with keys in [low, high] in the subtree at 
+  /// `ptr`, `depth` levels below the root, to `agg`.  As in 
+  /// `cut_range`, a bound of None means that the range runs past
+  /// that side of the subtree.
+  fn aggregate_in(&mut self, ptr: PagePointer, depth: u16, low: Option<u32>, high: Option<u32>,
+                  agg: &mut Aggregate) -> BPlusResult<()>
+  {
+    if depth == self.meta.depth
+    {
+      let leaf = self.get_page::<LeafPage>(ptr)?;
+      let (low, high) = (low.unwrap_or(0), high.unwrap_or(u32::MAX));
+      for (_, value) in leaf.iter().filter(|(k, _)| low <= *k && *k <= high)
+      {
+        agg.add(*value);
+      }
+      return Ok(())
+    }
+
+    let dir = self.get_page::<DirectoryPage>(ptr)?;
+    let first = low.map_or(0, |k| dir.find_pointer_idx(k));
+    let last = high.map_or(dir.count, |k| dir.find_pointer_idx(k));
+    for idx in first ..= last
+    {
+      let low = if idx == first { low } else { None };
+      let high = if idx == last { high } else { None };
+      #[cfg(feature = "aggregates")]
+      if low.is_none() && high.is_none()
+      {
+        agg.include(dir.counts[idx], &dir.summaries[idx]);
+        continue
+      }
+      self.aggregate_in(dir.pointers[idx], depth+1, low, high, agg)?;
+    }
+    Ok(())
+  }
+
+  /// Iterate over all of the data values
+  pub fn iter<'a>(&'a mut self) -> BPlusResult<BPlusTreeIterator<'a>>
+  {
+    let data_idx = self.meta.data_head.to_owned();
+    let data_page = self.get_page::<LeafPage>(data_idx)?;
+
+    Ok(BPlusTreeIterator { 
+      tree: self, 
+      page: data_page, 
+      idx: 0,
+      end: Bound::Unbounded,
+      expiring: VecDeque::new(),
+      expiring_from: Some(0),
+    })
+  }
+
+  /// Iterate over the data values with keys in the provided range
+  ///
+  /// The iterator starts at the leaf page that would contain the
+  /// lower bound of the range, and follows the `next` pointers
+  /// until it passes the upper bound.
+  pub fn range<'a, R: RangeBounds<u32>>(&'a mut self, range: R) 
+    -> BPlusResult<BPlusTreeIterator<'a>>
+  {
+    let end = range.end_bound().cloned();
+    let start = match range.start_bound()
+    {
+      Bound::Included(k) => *k,
+      Bound::Excluded(k) => match k.checked_add(1)
+      {
+        Some(k) => k,
+        // Nothing is strictly greater than u32::MAX
+        None => return Ok(BPlusTreeIterator {
+          page: LeafPage::init(),
+          tree: self,
+          idx: 0,
+          end: Bound::Excluded(0),
+          expiring: VecDeque::new(),
+          expiring_from: None,
+        })
+      },
+      Bound::Unbounded => 0,
+    };
+
+    let v = self.find_page(start)?;
+    let page = self.get_page::<LeafPage>(v[v.len()-1])?;
+    let idx = match page.find_index(start) { Ok(idx) => idx, Err(idx) => idx };
+
+    Ok(BPlusTreeIterator { tree: self, page, idx, end, expiring: VecDeque::new(), expiring_from: Some(start) })
+  }
+
+  /// A cursor on the record with the smallest key (see `Cursor`)
+  pub fn cursor(&mut self) -> BPlusResult<Cursor<'_>>
+  {
+    let ptr = self.meta.data_head;
+    let page = self.get_page::<LeafPage>(ptr)?;
+    let mut cursor = Cursor { tree: self, ptr, page, idx: 0, before_start: false };
+    cursor.settle()?;
+    Ok(cursor)
+  }
 
   ////////////////////////////////////////////////////////////////
-  //////////////////// Part 2: Deletion //////////////////////////
+  /////////////////// Part 2: Insertion //////////////////////////
   ////////////////////////////////////////////////////////////////
-  
-  /// Delete a key from the tree
+
+
+  /// Insert a new key/value pair into the dataset.
   ///
   /// With N records and K keys per directory page, this 
   /// function's asymptotic bounds should be:
@@ -416,266 +1202,1890 @@ impl BPlusTree
   /// - IO: O(log_K(N)) reads, O(1) amortized writes.
   ///
   /// Amortized bounds may assume no intervening calls
-  /// to put()
+  /// to delete()
   ///
   /// You are encouraged to write this function in several steps:
-  /// 1. First solve the case where the leaf that holds the key
-  ///    is at least 50% full after the deletion, and does not 
-  ///    require a merge.  Leave a todo!() for the other cases.
-  /// 2. Next, solve the case where one of the leaves adjacent
-  ///    to the underfull leaf has keys that can be stolen.  
-  ///    Leave a todo!() for the other cases.
-  /// 3. Then, solve the case where the parent of the leaf
-  ///    is at least 50% full after losing a pointer, and so
-  ///    does not require a recursive merge.  Leave a todo!() 
-  ///    for the other cases.
-  /// 4. After that, solve the case where an adjacent sibling of
-  ///    the parent of the leaf has keys that can be stolen.
-  ///    Leave a todo!() for the other cases.
-  /// 5. Fifth, solve the case where the parent of the leaf is
-  ///    a root page that contains >= 2 pointers after losing
-  ///    a pointer.  Leave a todo!() for the other cases. (Hint:
-  ///    this case is really really easy :) )
-  /// 6. Next, solve the case where the parent of the leaf is
-  ///    not a root page.  Leave a todo!() for the final case.
-  /// 7. Finally, solve the case where the deletion drops the
-  ///    root page down to a single pointer (with no keys).
+  /// 1. First solve the case where the leaf that would hold 
+  ///    key has sufficient space; Leave a todo!() for the 
+  ///    other case
+  /// 2. Then solve the case where the leaf that would hold
+  ///    key needs to be split; Leave a todo!() for the case
+  ///    where the parent directory page needs to be split.
+  /// 3. Then solve the case where the parent directory page
+  ///    is the root and needs to be split.  Leave a todo!() for
+  ///    the case where a non-root directory page needs to be
+  ///    split.
+  /// 4. Finally solve the case where the a non-root directory 
+  ///    page needs to be split.
   /// 
   /// You are also encouraged to use several helper functions:
+  /// - LeafPage::split()
+  /// - DirectoryPage::split_ptr()
+  /// - DirectoryPage::split()
   /// - BPlusTree::find_page()
-  /// - LeafPage::is_underfull()
-  /// - LeafPage::can_allow_stolen_key()
-  /// - LeafPage::steal_low()
-  /// - LeafPage::steal_high()
-  /// - DirectoryPage::is_underfull()
-  /// - DirectoryPage::can_allow_stolen_key()
-  /// - DirectoryPage::steal_low()
-  /// - DirectoryPage::steal_high()
   ///
-  pub fn delete(&mut self, key: u32) -> BPlusResult<()>
+  pub fn put(&mut self, key: u32, value: u32) -> BPlusResult<()>
   {
     // BEGIN SNIP
     // SNIP ALT:todo!()
     let ptr_stack = self.find_page(key)?;
-    let leaf_ptr = ptr_stack[ptr_stack.len()-1];
-    let mut leaf_page = self.get_page::<LeafPage>(leaf_ptr)?;
+    // println!("{:?}", ptr_stack);
+    let leaf = self.get_page::<LeafPage>(ptr_stack[ptr_stack.len()-1])?;
+    self.put_in_leaf(&ptr_stack, leaf, key, value)?;
+    self.forget_expiries(key, key)?;
 
-    if !leaf_page.delete(key) { return Ok(()) }
-    if !leaf_page.is_underfull()
-    { 
-      self.put_page(leaf_ptr, &leaf_page)?;
-      return Ok(()) 
+    self.end_op()
+    // END SNIP
+  }
+
+  /// The body of `put`, once the leaf that would hold `key` has
+  /// been read.  `ptr_stack` is the leaf and its ancestors (see
+  /// find_page).
+  fn put_in_leaf(&mut self, ptr_stack: &[PagePointer], mut leaf: LeafPage, key: u32, value: u32)
+    -> BPlusResult<()>
+  {
+    // BEGIN SNIP
+    // SNIP ALT:todo!()
+    let leaf_ptr = ptr_stack[ptr_stack.len()-1];
+    let is_new = leaf.find_index(key).is_err();
+    let mut split = false;
+    let summary;
+
+    // println!("BEFORE: {:?}", leaf);
+    if !leaf.can_insert(key)
+    {
+      split = true;
+      // Split required
+      // println!("BEFORE: {:?}", leaf);
+      let (split_key, new_leaf_ptr, mut new_leaf) = 
+        self.split_leaf(&mut leaf, ptr_stack)?;
+      if leaf.is_full() || new_leaf.is_full()
+      {
+        return Err(BPlusError::corrupt(leaf_ptr, "Leaf is still full after splitting"))
+      }
+      if key < split_key
+      {
+        leaf.put(key, value)?;
+        self.put_page(leaf_ptr, &leaf)?;
+        summary = leaf.summary();
+      }
+      else 
+      {
+        new_leaf.put(key, value)?;
+        self.put_page(new_leaf_ptr, &new_leaf)?;
+        summary = new_leaf.summary();
+      }
+      // println!("AFTER: {:?}", leaf);
+      // println!("AFTER: {:?}", new_leaf);
+      // println!("AFTER: {:?}", self.meta);
+    }
+    else
+    {
+      // Split not required
+      leaf.put(key, value)?;
+      self.put_page(leaf_ptr, &leaf)?;
+      summary = leaf.summary();
     }
+    // println!("AFTER: {:?}", leaf);
 
-    let dir_ptr = ptr_stack[ptr_stack.len()-2];
-    let mut dir_page = self.get_page::<DirectoryPage>(dir_ptr)?;
-    let dir_idx = dir_page.find_pointer_idx(key);
+    // An update only changes the summaries
+    if is_new || Summary::KEPT
+    {
+      // A split may have moved the key to another leaf (and
+      // parent)
+      let path = if split { self.find_page(key)? } else { ptr_stack.into() };
+      self.update_path(&path[..path.len()-1], key, is_new as i32, summary)?;
+    }
+    self.log_change(Mutation::Put { key, value })?;
+    Ok(())
+    // END SNIP
+  }
 
-    let merge_is_low;
-    let mut merge_page: LeafPage;
-    let merge_ptr: PagePointer;
+  /// Add `delta` to the record count of the pointer that `key`
+  /// follows in each of the directory pages in `path`, and bring
+  /// their summaries up to date, given that `summary` is the new 
+  /// summary of the leaf that holds `key`.
+  fn update_path(&mut self, path: &[PagePointer], key: u32, delta: i32, summary: Summary) 
+    -> BPlusResult<()>
+  {
+    // Summaries are computed bottom-up
+    let mut summary = summary;
+    for ptr in path.iter().rev()
+    {
+      let mut dir = self.get_page::<DirectoryPage>(*ptr)?;
+      let idx = dir.find_pointer_idx(key);
+      dir.counts[idx] = dir.counts[idx].checked_add_signed(delta)
+        .ok_or_else(|| BPlusError::corrupt(*ptr, format!("Record count {} under pointer {} can't change by {}", dir.counts[idx], idx, delta)))?;
+      dir.summaries[idx] = summary;
+      summary = dir.summary();
+      self.put_page(*ptr, &dir)?;
+    }
+    Ok(())
+  }
 
-    // Attempt thefts
-    if dir_idx > 0
+  /// Insert or update a batch of key/value pairs
+  ///
+  /// If a key appears more than once, the last value wins.  The
+  /// batch is sorted, and all of the records that land on the same
+  /// leaf are applied with a single read and write of that leaf.
+  /// Records that don't fit on their leaf are put one at a time, 
+  /// splitting it as needed.
+  ///
+  /// The batch is a single operation, and so is atomic, unless it
+  /// writes more pages than fit in the journal.  A larger batch is
+  /// committed in parts, lowest keys first (as in `delete_range`),
+  /// and a crash part way through may leave a prefix of it put.
+  pub fn put_many(&mut self, records: &[(u32, u32)]) -> BPlusResult<()>
+  {
+    let mut sorted = records.to_vec();
+    // A stable sort keeps the last value for a key last
+    sorted.sort_by_key(|record| record.0);
+    let keys: Vec<u32> = sorted.iter().map(|record| record.0).collect();
+
+    // Splits only ever move records off of the leaf being split, so 
+    // the other groups stay valid.
+    for (ptr, range) in self.group_by_leaf(&keys)?
     {
-      let prev_leaf_ptr = leaf_page.prev;
-      let mut prev_leaf_page = self.get_page::<LeafPage>(prev_leaf_ptr)?;
-      if prev_leaf_page.can_allow_stolen_key()
+      let group = &sorted[range];
+      let mut leaf = self.get_page::<LeafPage>(ptr)?;
+      let before = leaf.count;
+      let mut applied = 0;
+      while applied < group.len() && leaf.can_insert(group[applied].0)
       {
-        // println!("STEALING HIGH TO LEAF PAGE[{}] = {:?}\nFROM PAGE[{}] = {:?}", leaf_ptr, leaf_page, prev_leaf_ptr, prev_leaf_page);
-        let (key, value) = prev_leaf_page.steal_high();
-        leaf_page.put(key, value)?;
-        assert!(dir_idx > 0);
-        dir_page.keys[dir_idx-1] = key;
-        self.put_page(leaf_ptr, &leaf_page)?;
-        self.put_page(prev_leaf_ptr, &prev_leaf_page)?;
-        self.put_page(dir_ptr, &dir_page)?;
-        return Ok(())
-      } else {
-        // self.write_tree()?;
-        // println!("Merge #{} -> #{}", prev_leaf_ptr, leaf_ptr);
-        merge_page = prev_leaf_page;
-        merge_ptr  = prev_leaf_ptr;
-        merge_is_low = true;
+        let (key, value) = group[applied];
+        leaf.put(key, value)?;
+        self.log_change(Mutation::Put { key, value })?;
+        applied += 1;
       }
-    } else if dir_idx < dir_page.count
+      self.put_page(ptr, &leaf)?;
+      if leaf.count > before || (Summary::KEPT && applied > 0)
+      {
+        let path = self.find_page(group[0].0)?;
+        self.update_path(&path[..path.len()-1], group[0].0, (leaf.count - before) as i32, leaf.summary())?;
+      }
+
+      for (key, value) in &group[applied ..]
+      {
+        self.reserve_journal()?;
+        let ptr_stack = self.find_page(*key)?;
+        let leaf = self.get_page::<LeafPage>(ptr_stack[ptr_stack.len()-1])?;
+        self.put_in_leaf(&ptr_stack, leaf, *key, *value)?;
+      }
+      for (key, _) in group
+      {
+        self.forget_expiries(*key, *key)?;
+      }
+      self.reserve_journal()?;
+    }
+    self.end_op()
+  }
+
+  /// Insert or update a key/value pair, returning the key's 
+  /// previous value (if any)
+  pub fn insert(&mut self, key: u32, value: u32) -> BPlusResult<Option<u32>>
+  {
+    self.update(key, |_| Some(value))
+  }
+
+  /// Insert a key/value pair, failing with a KeyExists error if the
+  /// key is already present.  An existing value is left unchanged.
+  pub fn insert_new(&mut self, key: u32, value: u32) -> BPlusResult<()>
+  {
+    match self.update(key, |old| old.or(Some(value)))?
     {
-      let next_leaf_ptr = dir_page.pointers[dir_idx+1];
-      let mut next_leaf_page = self.get_page::<LeafPage>(next_leaf_ptr)?;
-      if next_leaf_page.can_allow_stolen_key()
+      None           => Ok(()),
+      Some(existing) => Err(BPlusError::KeyExists { key, value: existing })
+    }
+  }
+
+  /// Delete a key, returning its value (or None if it wasn't 
+  /// present)
+  pub fn remove(&mut self, key: u32) -> BPlusResult<Option<u32>>
+  {
+    self.update(key, |_| None)
+  }
+
+  /// Atomically replace the value of a key with the result of `f`
+  ///
+  /// `f` is passed the current value (or None), and returns the 
+  /// new value, or None to delete the key.  The leaf holding the key
+  /// is found with a single descent, and written (at most) once.
+  /// Returns the value before the update.
+  pub fn update<F>(&mut self, key: u32, f: F) -> BPlusResult<Option<u32>>
+    where F: FnOnce(Option<u32>) -> Option<u32>
+  {
+    let ptr_stack = self.find_page(key)?;
+    let leaf = self.get_page::<LeafPage>(ptr_stack[ptr_stack.len()-1])?;
+    let stored = leaf.find_value(key);
+    let old = self.unless_expired(key, stored)?;
+    let new = f(old);
+    // An expired key is written even if its value stays the same,
+    // since it comes back to life
+    let write = new != old || new != stored;
+    if write
+    {
+      match new
       {
-        // println!("STEALING LOW TO LEAF PAGE[{}] = {:?}\nFROM PAGE[{}] = {:?}", leaf_ptr, leaf_page, next_leaf_ptr, next_leaf_page);
-        let (key, value) = next_leaf_page.steal_low();
-        leaf_page.put(key, value)?;
-        dir_page.keys[dir_idx] = next_leaf_page.get(0).0;
-        self.put_page(leaf_ptr, &leaf_page)?;
-        self.put_page(next_leaf_ptr, &next_leaf_page)?;
+        Some(value) => self.put_in_leaf(&ptr_stack, leaf, key, value)?,
+        None        => self.delete_in_leaf(&ptr_stack, leaf, key)?,
+      }
+    }
+    // A key keeps its expiry time only while it stays live
+    if old.is_none() || new.is_none()
+    {
+      self.forget_expiries(key, key)?;
+    }
+    if write
+    {
+      self.end_op()?;
+    }
+    Ok(old)
+  }
+
+  /// Set the value of a key to `new`, but only if its value is
+  /// currently `expected` (None if the key must be absent).
+  ///
+  /// Like `AtomicU32::compare_exchange`, returns Ok with the old 
+  /// value if the swap happened, or Err with the actual value if
+  /// it didn't.
+  pub fn compare_and_swap(&mut self, key: u32, expected: Option<u32>, new: u32) 
+    -> BPlusResult<Result<Option<u32>, Option<u32>>>
+  {
+    let old = self.update(key, |old| if old == expected { Some(new) } else { old })?;
+    Ok(if old == expected { Ok(old) } else { Err(old) })
+  }
+
+  /// Add `delta` to the value of a key (wrapping on overflow), 
+  /// treating a missing key as 0.  Returns the old value.
+  pub fn fetch_add(&mut self, key: u32, delta: u32) -> BPlusResult<u32>
+  {
+    let old = self.update(key, |old| Some(old.unwrap_or(0).wrapping_add(delta)))?;
+    Ok(old.unwrap_or(0))
+  }
+
+  // BEGIN SNIP
+  pub fn split_leaf(&mut self, leaf: &mut LeafPage, ptr_stack: &[PagePointer]) 
+    -> BPlusResult<(u32, PagePointer, LeafPage)>
+  {
+    let leaf_ptr = ptr_stack[ptr_stack.len()-1];
+    if leaf.count < LEAF_RECORD_COUNT
+    {
+      return Err(BPlusError::corrupt(leaf_ptr, format!("Can't split a leaf with only {} records", leaf.count)))
+    }
+    let mut new_leaf = leaf.split();
+    let split_key = new_leaf.get(0).0;
+    new_leaf.prev = leaf_ptr;
+    new_leaf.next = leaf.next;
+    let new_leaf_ptr = self.alloc_page(&new_leaf)?;
+    if new_leaf.next == NULL_IDX 
+    {
+      self.meta.data_tail = new_leaf_ptr;
+      self.put_meta()?;
+    } else 
+    {
+      let mut old_next = self.get_page::<LeafPage>(new_leaf.next)?;
+      old_next.prev = new_leaf_ptr;
+      self.put_page(new_leaf.next, &old_next)?
+    }
+    leaf.next = new_leaf_ptr;
+    self.put_page(leaf_ptr, &leaf.clone())?;
+
+    self.split_dir_entry(ptr_stack, split_key, new_leaf_ptr, new_leaf.count as u32,
+                         [leaf.summary(), new_leaf.summary()])?;
+
+    return Ok( (split_key, new_leaf_ptr, new_leaf) )
+  }
+
+  /// Add a new pointer to a directory page by splitting an
+  /// existing pointer into two.
+  ///
+  /// - `ptr_stack`: The leaf page pointer and its ancestors 
+  ///    (see find_page)
+  /// - `new_count`: The number of records moved to the new child
+  /// - `summaries`: The summaries of the split and new children
+  ///
+  /// With N records, K keys per directory page, and a directory 
+  /// page at depth D < O(log_K(N)), this function should:
+  /// - Use O(D) memory
+  /// - Perform O(D) unqualified, O(1) amortized IOs
+  /// - Have a O(D) unqualified, O(1) amortized runtime
+  /// 
+  ///
+  pub fn split_dir_entry(&mut self, ptr_stack: &[PagePointer], split_key: u32, new_child_ptr: PagePointer,
+                         new_count: u32, summaries: [Summary; 2])
+   -> BPlusResult<()>
+  {
+    let child_ptr = ptr_stack[ptr_stack.len()-1];
+    let dir_ptr = ptr_stack[ptr_stack.len()-2];
+    let mut dir_page = self.get_page::<DirectoryPage>(dir_ptr)?;
+    if !dir_page.can_insert(split_key)
+    {
+      let (parent_split_key, new_dir_ptr, mut new_dir_page) = 
+        self.split_dir(&mut dir_page, &ptr_stack[0..ptr_stack.len()-1])?;
+      if dir_page.is_full() || new_dir_page.is_full()
+      {
+        return Err(BPlusError::corrupt(dir_ptr, "Directory page is still full after splitting"))
+      }
+      if split_key < parent_split_key
+      {
+        dir_page.split_at_ptr(child_ptr, split_key, new_child_ptr, new_count, summaries)?;
         self.put_page(dir_ptr, &dir_page)?;
-        return Ok(())
-      } else {
-        // self.write_tree()?;
-        // println!("Merge #{} <- #{}", leaf_ptr, next_leaf_ptr);
-        merge_page = next_leaf_page;
-        merge_ptr  = next_leaf_ptr;
-        merge_is_low = false;
       }
+      else 
+      {
+        new_dir_page.split_at_ptr(child_ptr, split_key, new_child_ptr, new_count, summaries)?;
+        self.put_page(new_dir_ptr, &new_dir_page)?;
+      }
+    } else
+    {
+      dir_page.split_at_ptr(child_ptr, split_key, new_child_ptr, new_count, summaries)?;
+      self.put_page(dir_ptr, &dir_page)?;
     }
-    else 
+    Ok(())
+  }
+
+  pub fn split_dir(&mut self, dir: &mut DirectoryPage, ptr_stack: &[PagePointer]) 
+    -> BPlusResult<(u32, PagePointer, DirectoryPage)>
+  {
+    let dir_ptr = ptr_stack[ptr_stack.len()-1];
+    if ptr_stack.len() <= 1 // root split
+    {
+      let (split_key, new_dir_page) = dir.split_page();
+      // self.write_tree()?;
+      // println!("Split {} at {}", dir_ptr, split_key);
+      let new_dir_ptr = self.alloc_page(&new_dir_page)?;
+      self.put_page(dir_ptr, &dir.clone())?;
+      let mut new_root = DirectoryPage::init();
+      new_root.keys[0] = split_key;
+      new_root.pointers[0] = dir_ptr;
+      new_root.pointers[1] = new_dir_ptr;
+      new_root.counts[0] = dir.total() as u32;
+      new_root.counts[1] = new_dir_page.total() as u32;
+      new_root.summaries[0] = dir.summary();
+      new_root.summaries[1] = new_dir_page.summary();
+      new_root.count = 1;
+      let new_root_ptr = self.alloc_page(&new_root)?;
+      self.meta.root_page = new_root_ptr;
+      self.meta.depth += 1;
+      self.put_meta()?;
+      // self.write_tree()?;
+      Ok( (split_key, new_dir_ptr, new_dir_page) )
+    } else
+    {
+      let (split_key, new_dir_page) = dir.split_page();
+      let new_dir_ptr = self.alloc_page(&new_dir_page)?;
+      self.put_page(dir_ptr, &dir.clone())?;
+      self.split_dir_entry(ptr_stack, split_key, new_dir_ptr, new_dir_page.total() as u32,
+                           [dir.summary(), new_dir_page.summary()])?;
+      Ok( (split_key, new_dir_ptr, new_dir_page) )
+    }
+  }
+  // END SNIP
+
+
+  ////////////////////////////////////////////////////////////////
+  //////////////////// Part 2: Deletion //////////////////////////
+  ////////////////////////////////////////////////////////////////
+  
+  /// Delete a key from the tree
+  ///
+  /// With N records and K keys per directory page, this 
+  /// function's asymptotic bounds should be:
+  /// - Memory: O(log_K(N))
+  /// - IO: O(log_K(N)) reads, O(1) amortized writes.
+  ///
+  /// Amortized bounds may assume no intervening calls
+  /// to put()
+  ///
+  /// You are encouraged to write this function in several steps:
+  /// 1. First solve the case where the leaf that holds the key
+  ///    is at least 50% full after the deletion, and does not 
+  ///    require a merge.  Leave a todo!() for the other cases.
+  /// 2. Next, solve the case where one of the leaves adjacent
+  ///    to the underfull leaf has keys that can be stolen.  
+  ///    Leave a todo!() for the other cases.
+  /// 3. Then, solve the case where the parent of the leaf
+  ///    is at least 50% full after losing a pointer, and so
+  ///    does not require a recursive merge.  Leave a todo!() 
+  ///    for the other cases.
+  /// 4. After that, solve the case where an adjacent sibling of
+  ///    the parent of the leaf has keys that can be stolen.
+  ///    Leave a todo!() for the other cases.
+  /// 5. Fifth, solve the case where the parent of the leaf is
+  ///    a root page that contains >= 2 pointers after losing
+  ///    a pointer.  Leave a todo!() for the other cases. (Hint:
+  ///    this case is really really easy :) )
+  /// 6. Next, solve the case where the parent of the leaf is
+  ///    not a root page.  Leave a todo!() for the final case.
+  /// 7. Finally, solve the case where the deletion drops the
+  ///    root page down to a single pointer (with no keys).
+  /// 
+  /// You are also encouraged to use several helper functions:
+  /// - BPlusTree::find_page()
+  /// - LeafPage::is_underfull()
+  /// - LeafPage::can_allow_stolen_key()
+  /// - LeafPage::steal_low()
+  /// - LeafPage::steal_high()
+  /// - DirectoryPage::is_underfull()
+  /// - DirectoryPage::can_allow_stolen_key()
+  /// - DirectoryPage::steal_low()
+  /// - DirectoryPage::steal_high()
+  ///
+  pub fn delete(&mut self, key: u32) -> BPlusResult<()>
+  {
+    self.delete_key(key)?;
+    self.end_op()
+  }
+
+  /// The body of `delete`, without the end-of-operation sync
+  fn delete_key(&mut self, key: u32) -> BPlusResult<()>
+  {
+    let ptr_stack = self.find_page(key)?;
+    let leaf_page = self.get_page::<LeafPage>(ptr_stack[ptr_stack.len()-1])?;
+    self.delete_in_leaf(&ptr_stack, leaf_page, key)?;
+    self.forget_expiries(key, key)
+  }
+
+  /// The body of `delete`, once the leaf that would hold `key` has
+  /// been read.  `ptr_stack` is the leaf and its ancestors (see
+  /// find_page).
+  fn delete_in_leaf(&mut self, ptr_stack: &[PagePointer], mut leaf_page: LeafPage, key: u32)
+    -> BPlusResult<()>
+  {
+    // BEGIN SNIP
+    // SNIP ALT:todo!()
+    let leaf_ptr = ptr_stack[ptr_stack.len()-1];
+
+    if !leaf_page.delete(key) { return Ok(()) }
+    self.update_path(&ptr_stack[..ptr_stack.len()-1], key, -1, leaf_page.summary())?;
+    self.log_change(Mutation::Delete { key })?;
+    if !leaf_page.is_underfull()
+    { 
+      self.put_page(leaf_ptr, &leaf_page)?;
+      return Ok(()) 
+    }
+
+    let dir_ptr = ptr_stack[ptr_stack.len()-2];
+    let mut dir_page = self.get_page::<DirectoryPage>(dir_ptr)?;
+    let dir_idx = dir_page.find_pointer_idx(key);
+
+    let merge_is_low;
+    let mut merge_page: LeafPage;
+    let merge_ptr: PagePointer;
+
+    // Attempt thefts
+    if dir_idx > 0
+    {
+      let prev_leaf_ptr = leaf_page.prev;
+      let mut prev_leaf_page = self.get_page::<LeafPage>(prev_leaf_ptr)?;
+      if prev_leaf_page.can_allow_stolen_key()
+      {
+        let (key, value) = prev_leaf_page.steal_high()?;
+        leaf_page.put(key, value)?;
+        dir_page.keys[dir_idx-1] = key;
+        dir_page.counts[dir_idx-1] = prev_leaf_page.count as u32;
+        dir_page.counts[dir_idx] = leaf_page.count as u32;
+        dir_page.summaries[dir_idx-1] = prev_leaf_page.summary();
+        dir_page.summaries[dir_idx] = leaf_page.summary();
+        self.put_page(leaf_ptr, &leaf_page)?;
+        self.put_page(prev_leaf_ptr, &prev_leaf_page)?;
+        self.put_page(dir_ptr, &dir_page)?;
+        return Ok(())
+      } else {
+        merge_page = prev_leaf_page;
+        merge_ptr  = prev_leaf_ptr;
+        merge_is_low = true;
+      }
+    } else if dir_idx < dir_page.count
+    {
+      let next_leaf_ptr = dir_page.pointers[dir_idx+1];
+      let mut next_leaf_page = self.get_page::<LeafPage>(next_leaf_ptr)?;
+      if next_leaf_page.can_allow_stolen_key()
+      {
+        let (key, value) = next_leaf_page.steal_low()?;
+        leaf_page.put(key, value)?;
+        dir_page.keys[dir_idx] = next_leaf_page.get(0).0;
+        dir_page.counts[dir_idx] = leaf_page.count as u32;
+        dir_page.counts[dir_idx+1] = next_leaf_page.count as u32;
+        dir_page.summaries[dir_idx] = leaf_page.summary();
+        dir_page.summaries[dir_idx+1] = next_leaf_page.summary();
+        self.put_page(leaf_ptr, &leaf_page)?;
+        self.put_page(next_leaf_ptr, &next_leaf_page)?;
+        self.put_page(dir_ptr, &dir_page)?;
+        return Ok(())
+      } else {
+        merge_page = next_leaf_page;
+        merge_ptr  = next_leaf_ptr;
+        merge_is_low = false;
+      }
+    }
+    else 
+    {
+      // ooops... only one leaf page.  We can't delete it, so
+      // leave it be.  Don't forget to flush though.
+      self.put_page(leaf_ptr, &leaf_page)?;
+      return Ok(())
+    }
+
+    // If theft fails, merge pages
+    if merge_is_low
+    {
+      merge_page.merge_with(&leaf_page)?;
+      merge_page.next = leaf_page.next;
+      if merge_page.next == NULL_IDX { 
+        self.meta.data_tail = merge_ptr;
+        self.put_meta()?;
+      } else {
+        let mut temp_page: LeafPage = self.get_page(merge_page.next)?;
+        temp_page.prev = merge_ptr;
+        self.put_page(merge_page.next, &temp_page)?
+      }
+      dir_page.delete_idx(dir_idx)?;
+      self.free_page(leaf_ptr)?;
+      self.put_page(merge_ptr, &merge_page)?;
+      self.put_page(dir_ptr, &dir_page)?;
+    } else
+    {
+      leaf_page.merge_with(&merge_page)?;
+      leaf_page.next = merge_page.next;
+      if leaf_page.next == NULL_IDX { 
+        self.meta.data_tail = leaf_ptr;
+        self.put_meta()?;
+      } else {
+        let mut temp_page: LeafPage = self.get_page(leaf_page.next)?;
+        temp_page.prev = leaf_ptr;
+        self.put_page(leaf_page.next, &temp_page)?
+      }
+      dir_page.delete_idx(dir_idx+1)?;
+      self.free_page(merge_ptr)?;
+      self.put_page(leaf_ptr, &leaf_page)?;
+      self.put_page(dir_ptr, &dir_page)?;
+    }
+    if dir_page.is_underfull()
+    { 
+      self.merge_dir_page(&ptr_stack[0..ptr_stack.len()-1], key)?
+    }
+
+    Ok(())
+    // END SNIP
+  }
+
+  fn merge_dir_page(&mut self, ptr_stack: &[PagePointer], key: u32) -> BPlusResult<()>
+  {
+    let dir_ptr = ptr_stack[ptr_stack.len()-1];
+    let mut dir_page = self.get_page::<DirectoryPage>(dir_ptr)?;
+
+    if ptr_stack.len() <= 1
+    {
+      // Root merge
+      // Case 1: The root has > 1 key.  This is ok.  Leave
+      //         it as is.
+      if dir_page.count >= 1 { return Ok(()); }
+
+      // Case 2: The tree is a single level deep
+      if self.meta.depth == 1 { return Ok(()); }
+
+      // Case 3: The root has no keys.  Replace the root with
+      //         the page being pointed to
+      self.meta.root_page = dir_page.pointers[0];
+      self.meta.depth -= 1;
+      self.put_meta()?;
+      self.free_page(ptr_stack[0])?;
+      return Ok(())
+    } else 
+    {
+      let parent_ptr = ptr_stack[ptr_stack.len()-2];
+      let mut parent_page = self.get_page::<DirectoryPage>(parent_ptr)?;
+      let dir_idx = parent_page.find_pointer_idx(key);
+
+      let sibling_is_low;
+      let sibling_ptr;
+      let mut sibling_page: DirectoryPage;
+
+      // Attempt thefts
+      if dir_idx > 0 
+      {
+        sibling_ptr = parent_page.pointers[dir_idx-1];
+        sibling_page = self.get_page::<DirectoryPage>(sibling_ptr)?;
+        if sibling_page.can_allow_stolen_key()
+        {
+          let new_parent_key = 
+            dir_page.steal_high_from(&mut sibling_page, parent_page.keys[dir_idx-1])?;
+          parent_page.keys[dir_idx-1] = new_parent_key;
+          parent_page.counts[dir_idx-1] = sibling_page.total() as u32;
+          parent_page.counts[dir_idx] = dir_page.total() as u32;
+          parent_page.summaries[dir_idx-1] = sibling_page.summary();
+          parent_page.summaries[dir_idx] = dir_page.summary();
+          self.put_page(dir_ptr, &dir_page)?;
+          self.put_page(sibling_ptr, &sibling_page)?;
+          self.put_page(parent_ptr, &parent_page)?;
+          return Ok(())
+        } else {
+          sibling_is_low = true;
+        }
+      } else
+      {
+        sibling_ptr = parent_page.pointers[dir_idx+1];
+        sibling_page = self.get_page::<DirectoryPage>(sibling_ptr)?;
+        if sibling_page.can_allow_stolen_key()
+        {
+          let new_parent_key = 
+            dir_page.steal_low_from(&mut sibling_page, parent_page.keys[dir_idx])?;
+          parent_page.keys[dir_idx] = new_parent_key;
+          parent_page.counts[dir_idx] = dir_page.total() as u32;
+          parent_page.counts[dir_idx+1] = sibling_page.total() as u32;
+          parent_page.summaries[dir_idx] = dir_page.summary();
+          parent_page.summaries[dir_idx+1] = sibling_page.summary();
+          self.put_page(dir_ptr, &dir_page)?;
+          self.put_page(sibling_ptr, &sibling_page)?;
+          self.put_page(parent_ptr, &parent_page)?;
+          return Ok(())
+        } else {
+          sibling_is_low = false;
+        }
+      }
+
+      // If theft fails, merge pages
+      
+      if sibling_ptr == NULL_IDX
+      {
+        return Err(BPlusError::corrupt(parent_ptr, format!("Page {} is its only child", dir_ptr)))
+      }
+      if sibling_is_low
+      {
+        sibling_page.merge_with(&dir_page, parent_page.keys[dir_idx-1])?;
+        parent_page.delete_idx(dir_idx)?;
+        self.free_page(dir_ptr)?;
+        self.put_page(sibling_ptr, &sibling_page)?;
+        self.put_page(parent_ptr, &parent_page)?;
+      } else
+      {
+        dir_page.merge_with(&sibling_page, parent_page.keys[dir_idx])?;
+        parent_page.delete_idx(dir_idx+1)?;
+        self.free_page(sibling_ptr)?;
+        self.put_page(dir_ptr, &dir_page)?;
+        self.put_page(parent_ptr, &parent_page)?;
+      }
+      if parent_page.is_underfull()
+      { 
+        self.merge_dir_page(&ptr_stack[0..ptr_stack.len()-1], key)?
+      }
+    }
+    Ok(())
+  }
+
+  /// Delete every key in the provided range, and return the 
+  /// number of records deleted
+  ///
+  /// Leaves and directory subtrees that lie entirely inside the
+  /// range are freed whole, without reading their records.  Only 
+  /// the pages on the way to the two ends of the range are 
+  /// rebalanced.
+  ///
+  /// A large range is deleted as a series of operations that each
+  /// fit in the journal, lowest keys first; a crash part way 
+  /// through may leave a prefix of the range deleted.
+  pub fn delete_range<R: RangeBounds<u32>>(&mut self, range: R) -> BPlusResult<u64>
+  {
+    let Some((low, high)) = inclusive_bounds(&range) else { return Ok(0) };
+
+    let mut deleted = 0;
+    loop
+    {
+      // Each chunk frees at most JOURNAL_CAPACITY/4 or so pages
+      self.reserve_journal()?;
+      let next_idx = self.rank(low)? + DELETE_RANGE_CHUNK;
+      let (chunk_high, done) = 
+        match self.select_stored(next_idx)?
+        {
+          Some((k, _)) if k <= high => (k-1, false),
+          _                         => (high, true)
+        };
+      let n = self.delete_range_chunk(low, chunk_high)?;
+      if n > 0 { self.log_change(Mutation::DeleteRange { low, high: chunk_high })?; }
+      self.forget_expiries(low, chunk_high)?;
+      deleted += n;
+      self.end_op()?;
+      if done { break }
+    }
+    Ok(deleted)
+  }
+
+  /// The body of `delete_range` for keys in [low, high], without
+  /// the end-of-operation sync
+  fn delete_range_chunk(&mut self, low: u32, high: u32) -> BPlusResult<u64>
+  {
+    let mut leaves = Vec::new();
+    let root = self.meta.root_page;
+    let (deleted, _) = self.cut_range(root, 0, Some(low), Some(high), &mut leaves)?;
+
+    // Any leaves between the two ends are gone
+    if let [low_ptr, high_ptr] = leaves[..]
+    {
+      let mut low_leaf = self.get_page::<LeafPage>(low_ptr)?;
+      let mut high_leaf = self.get_page::<LeafPage>(high_ptr)?;
+      if low_leaf.next != high_ptr
+      {
+        low_leaf.next = high_ptr;
+        high_leaf.prev = low_ptr;
+        self.put_page(low_ptr, &low_leaf)?;
+        self.put_page(high_ptr, &high_leaf)?;
+      }
+    }
+
+    // Fixing one end can leave the other underfull, and vice versa
+    while self.rebalance_path(low)? | self.rebalance_path(high)? {}
+    Ok(deleted)
+  }
+
+  /// Delete the keys in [low, high] from the subtree at `ptr`, 
+  /// `depth` levels below the root, and return the number of 
+  /// records deleted and the summary of those that remain.  A 
+  /// bound of None means that the range runs past that side of
+  /// the subtree.
+  ///
+  /// Pages are left as underfull as the deletion makes them.  The
+  /// leaves at the ends of the range are added to `leaves`.
+  fn cut_range(&mut self, ptr: PagePointer, depth: u16, low: Option<u32>, high: Option<u32>, 
+               leaves: &mut Vec<PagePointer>) -> BPlusResult<(u64, Summary)>
+  {
+    if depth == self.meta.depth
+    {
+      let mut leaf = self.get_page::<LeafPage>(ptr)?;
+      let deleted = leaf.delete_range(low.unwrap_or(0), high.unwrap_or(u32::MAX));
+      if deleted > 0 { self.put_page(ptr, &leaf)?; }
+      leaves.push(ptr);
+      return Ok((deleted as u64, leaf.summary()))
+    }
+
+    let mut dir = self.get_page::<DirectoryPage>(ptr)?;
+    let first = low.map(|k| dir.find_pointer_idx(k));
+    let last = high.map(|k| dir.find_pointer_idx(k));
+    let mut deleted = 0;
+
+    // Recurse into the children holding the ends of the range...
+    let ends = 
+      match (first, last)
+      {
+        (Some(f), Some(l)) if f == l => vec![(f, low, high)],
+        _ => first.map(|f| (f, low, None)).into_iter().chain(last.map(|l| (l, None, high))).collect()
+      };
+    for (idx, low, high) in ends
+    {
+      let (n, summary) = self.cut_range(dir.pointers[idx], depth+1, low, high, leaves)?;
+      dir.counts[idx] = dir.counts[idx].checked_sub(n as u32)
+        .ok_or_else(|| BPlusError::corrupt(ptr, format!("Pointer {} counts {} records, but {} were deleted under it", idx, dir.counts[idx], n)))?;
+      dir.summaries[idx] = summary;
+      deleted += n;
+    }
+
+    // ...and free every child in between
+    let inside = first.map_or(0, |f| f+1) .. last.unwrap_or(dir.count+1);
+    for idx in inside.clone()
+    {
+      self.free_subtree(dir.pointers[idx], depth+1)?;
+      deleted += dir.counts[idx] as u64;
+    }
+    dir.delete_ptr_range(inside)?;
+    if deleted > 0 { self.put_page(ptr, &dir)?; }
+    Ok((deleted, dir.summary()))
+  }
+
+  /// Free every page of the subtree at `ptr`, `depth` levels below
+  /// the root.  Leaves are freed without being read.
+  fn free_subtree(&mut self, ptr: PagePointer, depth: u16) -> BPlusResult<()>
+  {
+    if depth < self.meta.depth
+    {
+      let dir = self.get_page::<DirectoryPage>(ptr)?;
+      for child in &dir.pointers[0 .. dir.count+1]
+      {
+        self.free_subtree(*child, depth+1)?;
+      }
+    }
+    self.free_page(ptr)
+  }
+
+  /// Fix every underfull page on the way to `key`, bottom up, and
+  /// then drop any root left with a single pointer.  Unlike 
+  /// `delete`, this copes with pages that are arbitrarily 
+  /// underfull.  Returns true if anything changed.
+  fn rebalance_path(&mut self, key: u32) -> BPlusResult<bool>
+  {
+    let path = self.find_page(key)?;
+    let mut changed = self.rebalance_leaf(path[path.len()-2], key)?;
+    for i in (1 .. path.len()-1).rev()
+    {
+      changed |= self.rebalance_dir(path[i-1], key)?;
+    }
+
+    loop
+    {
+      let root_ptr = self.meta.root_page;
+      let root = self.get_page::<DirectoryPage>(root_ptr)?;
+      if root.count > 0 || self.meta.depth <= 1 { break }
+      self.meta.root_page = root.pointers[0];
+      self.meta.depth -= 1;
+      self.put_meta()?;
+      self.free_page(root_ptr)?;
+      changed = true;
+    }
+    Ok(changed)
+  }
+
+  /// Merge the leaf under `parent_ptr` that would hold `key` with a
+  /// sibling, or move records over from the sibling, until it is 
+  /// no longer underfull.  Returns true if anything changed.
+  fn rebalance_leaf(&mut self, parent_ptr: PagePointer, key: u32) -> BPlusResult<bool>
+  {
+    let mut parent = self.get_page::<DirectoryPage>(parent_ptr)?;
+    let mut changed = false;
+    while parent.count > 0
+    {
+      let idx = parent.find_pointer_idx(key);
+      if !self.get_page::<LeafPage>(parent.pointers[idx])?.is_underfull() { break }
+
+      // The leaf and a sibling, in key order
+      let low_idx = if idx > 0 { idx-1 } else { idx };
+      let (low_ptr, high_ptr) = (parent.pointers[low_idx], parent.pointers[low_idx+1]);
+      let mut low = self.get_page::<LeafPage>(low_ptr)?;
+      let mut high = self.get_page::<LeafPage>(high_ptr)?;
+      if low.count + high.count <= LEAF_RECORD_COUNT
+      {
+        low.merge_with(&high)?;
+        low.next = high.next;
+        if low.next == NULL_IDX
+        {
+          self.meta.data_tail = low_ptr;
+          self.put_meta()?;
+        }
+        else
+        {
+          let mut next = self.get_page::<LeafPage>(low.next)?;
+          next.prev = low_ptr;
+          self.put_page(low.next, &next)?;
+        }
+        parent.delete_idx(low_idx+1)?;
+        self.free_page(high_ptr)?;
+      }
+      else
+      {
+        // Between them, the two have enough records for both
+        while low.is_underfull()
+        {
+          let (k, v) = high.steal_low()?;
+          low.put(k, v)?;
+        }
+        while high.is_underfull()
+        {
+          let (k, v) = low.steal_high()?;
+          high.put(k, v)?;
+        }
+        parent.keys[low_idx] = high.get(0).0;
+        parent.counts[low_idx] = low.count as u32;
+        parent.counts[low_idx+1] = high.count as u32;
+        parent.summaries[low_idx] = low.summary();
+        parent.summaries[low_idx+1] = high.summary();
+        self.put_page(high_ptr, &high)?;
+      }
+      self.put_page(low_ptr, &low)?;
+      changed = true;
+    }
+    if changed { self.put_page(parent_ptr, &parent)?; }
+    Ok(changed)
+  }
+
+  /// Like `rebalance_leaf`, but for the directory page under 
+  /// `parent_ptr` that `key` leads to.
+  fn rebalance_dir(&mut self, parent_ptr: PagePointer, key: u32) -> BPlusResult<bool>
+  {
+    let mut parent = self.get_page::<DirectoryPage>(parent_ptr)?;
+    let mut changed = false;
+    while parent.count > 0
+    {
+      let idx = parent.find_pointer_idx(key);
+      if !self.get_page::<DirectoryPage>(parent.pointers[idx])?.is_underfull() { break }
+
+      let low_idx = if idx > 0 { idx-1 } else { idx };
+      let (low_ptr, high_ptr) = (parent.pointers[low_idx], parent.pointers[low_idx+1]);
+      let mut low = self.get_page::<DirectoryPage>(low_ptr)?;
+      let mut high = self.get_page::<DirectoryPage>(high_ptr)?;
+      // Merging also brings down the parent's key
+      if low.count + high.count < DIR_KEY_COUNT
+      {
+        low.merge_with(&high, parent.keys[low_idx])?;
+        parent.delete_idx(low_idx+1)?;
+        self.free_page(high_ptr)?;
+      }
+      else
+      {
+        while low.is_underfull()
+        {
+          parent.keys[low_idx] = low.steal_low_from(&mut high, parent.keys[low_idx])?;
+        }
+        while high.is_underfull()
+        {
+          parent.keys[low_idx] = high.steal_high_from(&mut low, parent.keys[low_idx])?;
+        }
+        parent.counts[low_idx] = low.total() as u32;
+        parent.counts[low_idx+1] = high.total() as u32;
+        parent.summaries[low_idx] = low.summary();
+        parent.summaries[low_idx+1] = high.summary();
+        self.put_page(high_ptr, &high)?;
+      }
+      self.put_page(low_ptr, &low)?;
+      changed = true;
+    }
+    if changed { self.put_page(parent_ptr, &parent)?; }
+    Ok(changed)
+  }
+
+  /// Delete every record, leaving the tree as `init` would
+  ///
+  /// Every page other than the root and the first leaf is 
+  /// reclaimed; new pages are allocated from just past them.  The
+  /// clear is synced right away (whatever the sync policy), so that
+  /// the reclaimed pages can be cut from the end of storage.
+  pub fn clear(&mut self) -> BPlusResult<()>
+  {
+    let mut root = DirectoryPage::init();
+    root.pointers[0] = DEFAULT_PAGE0_IDX;
+    self.put_page(DEFAULT_ROOT_IDX, &root)?;
+    self.put_page(DEFAULT_PAGE0_IDX, &LeafPage::init())?;
+    // Nothing past the first leaf is reachable any more
+    self.pending.retain(|ptr, _| *ptr <= DEFAULT_PAGE0_IDX);
+
+    self.meta.next_free_page = NULL_IDX;
+    self.meta.root_page = DEFAULT_ROOT_IDX;
+    self.meta.data_head = DEFAULT_PAGE0_IDX;
+    self.meta.data_tail = DEFAULT_PAGE0_IDX;
+    self.meta.pages_allocated = 3;
+    self.meta.depth = 1;
+    self.put_meta()?;
+    self.log_change(Mutation::Clear)?;
+    self.sync()?;
+    // As in `sync`, expiry times are only dropped once the clear
+    // is committed
+    if let Some(expiries) = self.expiries.as_mut()
+    {
+      expiries.clear()?;
+    }
+    // Only now that nothing reachable is past the first leaf
+    self.storage.truncate(self.meta.pages_allocated)
+  }
+
+  ////////////////////////////////////////////////////////////////
+  ///////////////// Splitting and Joining ////////////////////////
+  ////////////////////////////////////////////////////////////////
+
+  /// Move every record with a key greater than or equal to `key` 
+  /// into a brand new BPlusTree at the provided path, and return
+  /// the new tree.
+  pub fn split_off(&mut self, key: u32, new_path: &String) -> BPlusResult<BPlusTree>
+  {
+    self.split_off_into(key, BPlusTree::init(new_path)?)
+  }
+
+  /// Move every record with a key greater than or equal to `key` 
+  /// into `other`, and return it.  Any existing contents of `other`
+  /// are discarded.
+  ///
+  /// The leaf and directory pages on the way to `key` are split in
+  /// two, and the subtrees to their right are moved over whole.  
+  /// Since the trees don't share storage, moved pages are copied, 
+  /// but their records are never re-inserted one at a time.  Only
+  /// the pages along the split are rebalanced.
+  ///
+  /// `other` is synced before any of the moved pages are freed 
+  /// here, so a crash can't lose records, but may leave them in 
+  /// both trees (or leave freed pages unreachable).
+  ///
+  /// The expiry times of the moved records move with them if 
+  /// `other` keeps expiry times, and are dropped otherwise.
+  pub fn split_off_into(&mut self, key: u32, mut other: BPlusTree) -> BPlusResult<BPlusTree>
+  {
+    let old_root = other.meta.root_page;
+    other.free_subtree(old_root, 0)?;
+    other.forget_expiries(0, u32::MAX)?;
+
+    let mut leaves = (NULL_IDX, NULL_IDX);
+    let mut moved = Vec::new();
+    let root = self.meta.root_page;
+    let (new_root, _, _) = self.split_subtree(&mut other, root, 0, key, &mut leaves, &mut moved)?;
+    (other.meta.data_head, other.meta.data_tail) = leaves;
+    other.meta.root_page = new_root;
+    other.meta.depth = self.meta.depth;
+    other.put_meta()?;
+    // The split runs down the left edge of the new tree...
+    while other.rebalance_path(0)? {}
+    // Expiry times are moved before the records are committed, as
+    // in `put_expiring`
+    BPlusTree::move_expiries(self, &mut other, key)?;
+    other.end_op()?;
+    other.sync()?;
+
+    // ...and the right edge of this one
+    while self.rebalance_path(u32::MAX)? {}
+    self.log_change(Mutation::DeleteRange { low: key, high: u32::MAX })?;
+    for ptr in moved
+    {
+      self.reserve_journal()?;
+      self.free_page(ptr)?;
+    }
+    self.end_op()?;
+    Ok(other)
+  }
+
+  /// Split the subtree at `ptr`, `depth` levels below the root, at
+  /// `key`, moving the right half into `dst`.  Returns the pointer
+  /// to the right half in `dst`, the number of records moved, and
+  /// the summaries of the left and right halves.
+  /// 
+  /// The pages of whole subtrees moved to `dst` are added to 
+  /// `moved`; the split pages themselves stay here.
+  fn split_subtree(&mut self, dst: &mut BPlusTree, ptr: PagePointer, depth: u16, key: u32, 
+                   leaves: &mut (PagePointer, PagePointer), moved: &mut Vec<PagePointer>)
+    -> BPlusResult<(PagePointer, u64, [Summary; 2])>
+  {
+    if depth == self.meta.depth
+    {
+      let mut leaf = self.get_page::<LeafPage>(ptr)?;
+      let new_leaf = leaf.split_off(key);
+      leaf.next = NULL_IDX;
+      self.put_page(ptr, &leaf)?;
+      self.meta.data_tail = ptr;
+      self.put_meta()?;
+      dst.reserve_journal()?;
+      let new_ptr = dst.alloc_page(&new_leaf)?;
+      *leaves = (new_ptr, new_ptr);
+      return Ok((new_ptr, new_leaf.count as u64, [leaf.summary(), new_leaf.summary()]))
+    }
+
+    let mut dir = self.get_page::<DirectoryPage>(ptr)?;
+    let idx = dir.find_pointer_idx(key);
+    let (child, count, [low, high]) = self.split_subtree(dst, dir.pointers[idx], depth+1, key, leaves, moved)?;
+    let mut new_dir = DirectoryPage::init();
+    new_dir.pointers[0] = child;
+    new_dir.counts[0] = count as u32;
+    new_dir.summaries[0] = high;
+    dir.counts[idx] = dir.counts[idx].checked_sub(count as u32)
+      .ok_or_else(|| BPlusError::corrupt(ptr, format!("Pointer {} counts {} records, but {} were moved from under it", idx, dir.counts[idx], count)))?;
+    dir.summaries[idx] = low;
+
+    let mut total = count;
+    for i in idx+1 .. dir.count+1
+    {
+      new_dir.keys[i-idx-1] = dir.keys[i-1];
+      new_dir.pointers[i-idx] = self.copy_subtree(dst, dir.pointers[i], depth+1, leaves, moved)?;
+      new_dir.counts[i-idx] = dir.counts[i];
+      new_dir.summaries[i-idx] = dir.summaries[i];
+      total += dir.counts[i] as u64;
+    }
+    new_dir.count = dir.count - idx;
+    dir.delete_ptr_range(idx+1 .. dir.count+1)?;
+    self.put_page(ptr, &dir)?;
+    dst.reserve_journal()?;
+    Ok((dst.alloc_page(&new_dir)?, total, [dir.summary(), new_dir.summary()]))
+  }
+
+  /// Copy the subtree at `ptr`, `depth` levels below the root, to 
+  /// `dst`, and return the pointer to the copy.  The original is
+  /// left as it is, but its pages are added to `moved`.
+  ///
+  /// `leaves` holds the first and last leaves copied to `dst` so 
+  /// far (NULL_IDX if none).  Leaves are copied in key order, and
+  /// linked after the last.
+  fn copy_subtree(&mut self, dst: &mut BPlusTree, ptr: PagePointer, depth: u16, 
+                  leaves: &mut (PagePointer, PagePointer), moved: &mut Vec<PagePointer>)
+    -> BPlusResult<PagePointer>
+  {
+    moved.push(ptr);
+    if depth == self.meta.depth
+    {
+      let mut leaf = self.get_page::<LeafPage>(ptr)?;
+      leaf.prev = leaves.1;
+      leaf.next = NULL_IDX;
+      dst.reserve_journal()?;
+      let new_ptr = dst.alloc_page(&leaf)?;
+      if leaves.1 == NULL_IDX
+      {
+        leaves.0 = new_ptr;
+      }
+      else
+      {
+        let mut prev = dst.get_page::<LeafPage>(leaves.1)?;
+        prev.next = new_ptr;
+        dst.put_page(leaves.1, &prev)?;
+      }
+      leaves.1 = new_ptr;
+      return Ok(new_ptr)
+    }
+
+    let mut dir = self.get_page::<DirectoryPage>(ptr)?;
+    for idx in 0 .. dir.count+1
+    {
+      dir.pointers[idx] = self.copy_subtree(dst, dir.pointers[idx], depth+1, leaves, moved)?;
+    }
+    dst.reserve_journal()?;
+    dst.alloc_page(&dir)
+  }
+
+  /// Move every record of `other` into this tree, leaving `other`
+  /// empty.  Every key in `other` must be greater than every key 
+  /// here.
+  ///
+  /// `other`'s pages are copied over whole, and the two trees are
+  /// joined under a new root, after padding the shorter one with
+  /// single-pointer directory pages.  Only the pages along the 
+  /// join are then rebalanced.
+  ///
+  /// Copying a large tree takes several commits, but the copy 
+  /// isn't reachable until the join, which is atomic.  A crash 
+  /// before `other` is cleared may leave records in both trees.
+  ///
+  /// As in `split_off_into`, expiry times move with the records 
+  /// if this tree keeps them.
+  pub fn append(&mut self, other: &mut BPlusTree) -> BPlusResult<()>
+  {
+    let Some((first, _)) = other.first_stored()? else { return Ok(()) };
+    let last = self.last_stored()?;
+    if let Some((last, _)) = last
+    {
+      if last >= first { return Err(BPlusError::Overlap { last, first }) }
+    }
+    // Expiry times are moved before the join is committed, as in 
+    // `put_expiring`
+    BPlusTree::move_expiries(other, self, first)?;
+
+    let mut leaves = (NULL_IDX, NULL_IDX);
+    let other_root = other.meta.root_page;
+    let mut high_root = other.copy_subtree(self, other_root, 0, &mut leaves, &mut Vec::new())?;
+    let (head, tail) = leaves;
+    if last.is_none()
+    {
+      // Nothing to join with; just replace this tree
+      let root = self.meta.root_page;
+      self.free_subtree(root, 0)?;
+      self.meta.root_page = high_root;
+      self.meta.depth = other.meta.depth;
+      self.meta.data_head = head;
+      self.meta.data_tail = tail;
+      self.put_meta()?;
+    }
+    else
+    {
+      let mut low_root = self.meta.root_page;
+      let low_total = self.record_count()?;
+      let high_total = other.record_count()?;
+      let low_summary = self.get_page::<DirectoryPage>(low_root)?.summary();
+      let high_summary = self.get_page::<DirectoryPage>(high_root)?.summary();
+      let pad = |tree: &mut BPlusTree, ptr, total: u64, summary| 
+      {
+        let mut dir = DirectoryPage::init();
+        dir.pointers[0] = ptr;
+        dir.counts[0] = total as u32;
+        dir.summaries[0] = summary;
+        tree.alloc_page(&dir)
+      };
+      for _i in self.meta.depth .. other.meta.depth { low_root = pad(self, low_root, low_total, low_summary)?; }
+      for _i in other.meta.depth .. self.meta.depth { high_root = pad(self, high_root, high_total, high_summary)?; }
+
+      let mut root = DirectoryPage::init();
+      root.count = 1;
+      root.keys[0] = first;
+      root.pointers[0] = low_root;
+      root.pointers[1] = high_root;
+      root.counts[0] = low_total as u32;
+      root.counts[1] = high_total as u32;
+      root.summaries[0] = low_summary;
+      root.summaries[1] = high_summary;
+      self.meta.root_page = self.alloc_page(&root)?;
+      self.meta.depth = self.meta.depth.max(other.meta.depth) + 1;
+
+      let low_tail = self.meta.data_tail;
+      let mut low_leaf = self.get_page::<LeafPage>(low_tail)?;
+      low_leaf.next = head;
+      self.put_page(low_tail, &low_leaf)?;
+      let mut high_leaf = self.get_page::<LeafPage>(head)?;
+      high_leaf.prev = low_tail;
+      self.put_page(head, &high_leaf)?;
+      self.meta.data_tail = tail;
+      self.put_meta()?;
+      while self.rebalance_path(first - 1)? | self.rebalance_path(first)? {}
+    }
+    if self.changes.is_some()
+    {
+      for record in other.iter()?
+      {
+        let (key, value) = record?;
+        self.log_change(Mutation::Put { key, value })?;
+      }
+    }
+    self.end_op()?;
+    other.clear()
+  }
+
+  /// Move the expiry times of keys from `low` on from one tree's
+  /// expiry index to the other's (or just drop them, if `to` has no
+  /// index)
+  ///
+  /// Whatever `to`'s index held from `low` on is replaced, even if
+  /// `from` has no index.  `from`'s times are only dropped once its
+  /// records are (see `forget_expiries`).
+  fn move_expiries(from: &mut BPlusTree, to: &mut BPlusTree, low: u32) -> BPlusResult<()>
+  {
+    let mut moved = Vec::new();
+    if let Some(src) = from.expiries.as_mut()
+    {
+      for record in src.range(low ..)?
+      {
+        let (key, expires_at) = record?;
+        if !from.stale_expiries.contains(key) { moved.push((key, expires_at)); }
+      }
+    }
+    from.forget_expiries(low, u32::MAX)?;
+    if let Some(dst) = to.expiries.as_mut()
+    {
+      to.stale_expiries.remove(low, u32::MAX);
+      dst.delete_range(low ..)?;
+      dst.put_many(&moved)?;
+    }
+    Ok(())
+  }
+
+  ////////////////////////////////////////////////////////////////
+  ////////////////////////// Expiry //////////////////////////////
+  ////////////////////////////////////////////////////////////////
+
+  /// Keep expiry times for this tree's keys in `expiries` (which
+  /// should be empty, or hold the expiry times of this tree), so 
+  /// that entries can be given one with `put_expiring`
+  ///
+  /// The index takes on this tree's sync policy.  A tree with 
+  /// followers can't keep one (see `set_replica`).
+  pub fn set_expiries(&mut self, mut expiries: BPlusTree) -> BPlusResult<()>
+  {
+    if self.replica.is_some()
+    {
+      return Err(BPlusError::Unsupported("Followers can't be sent a tree's expiry index or change log".into()))
+    }
+    expiries.set_sync_policy(self.policy)?;
+    self.expiries = Some(Box::new(expiries));
+    Ok(())
+  }
+
+  /// Compare expiry times against `clock` instead of the system
+  /// clock, and stamp logged changes with its time
+  pub fn set_clock(&mut self, clock: Box<dyn Clock>)
+  {
+    self.clock = clock;
+  }
+
+  /// Insert or update a key/value pair that expires at 
+  /// `expires_at` (in seconds; see `Clock`)
+  ///
+  /// Once the clock reaches `expires_at`, the entry is hidden from
+  /// `get`, the iterators, and the other queries (`first`, 
+  /// `ceiling`, `select`, `aggregate`, and the like), and updates 
+  /// treat it as absent.  It still takes up space (and is counted 
+  /// by `record_count`, `rank`, and `select`'s positions) until it
+  /// is deleted or swept up by `purge_expired`.
+  ///
+  /// `put` and `put_many` clear a key's expiry time.  Changes 
+  /// through `update` (e.g., `fetch_add`) keep it, as long as the 
+  /// key stays live.
+  ///
+  /// The expiry index is a tree of its own, so expiry times can't
+  /// be committed together with the records.  Instead, they are 
+  /// written to the index before the record, and only dropped from
+  /// it once the write that made them stale has been committed.  A
+  /// crash in between may leave a value with a stale expiry time,
+  /// but can't leave one that should expire without one.
+  pub fn put_expiring(&mut self, key: u32, value: u32, expires_at: u32) -> BPlusResult<()>
+  {
+    let Some(expiries) = self.expiries.as_mut() else
+    {
+      return Err(BPlusError::Unsupported("Expiring entries require a tree with an expiry index".into()))
+    };
+    self.stale_expiries.remove(key, key);
+    expiries.put(key, expires_at)?;
+    let ptr_stack = self.find_page(key)?;
+    let leaf = self.get_page::<LeafPage>(ptr_stack[ptr_stack.len()-1])?;
+    self.put_in_leaf(&ptr_stack, leaf, key, value)?;
+    self.end_op()
+  }
+
+  /// The expiry time of a key, or None if it doesn't have one
+  pub fn expiry(&mut self, key: u32) -> BPlusResult<Option<u32>>
+  {
+    match self.expiries.as_mut()
+    {
+      Some(_) if self.stale_expiries.contains(key) => Ok(None),
+      Some(expiries) => expiries.get(key),
+      None           => Ok(None)
+    }
+  }
+
+  /// Delete every expired entry, and return the number deleted
+  ///
+  /// Runs of expired entries that aren't separated by any live 
+  /// ones are deleted with a single `delete_range`, so whole 
+  /// leaves (and subtrees) of them are freed at once, and only the
+  /// pages at the ends of each run are merged.
+  ///
+  /// The runs are found in a single pass over the expiry index and
+  /// the leaves, without holding the expired keys in memory: each
+  /// starts at an expired key, and ends just before the next live
+  /// entry.
+  pub fn purge_expired(&mut self) -> BPlusResult<u64>
+  {
+    let mut deleted = 0;
+    let mut from = 0;
+    while let Some(low) = self.next_expired(from, u32::MAX)?
+    {
+      // Expiry times of keys that aren't in the tree at all are 
+      // dropped along with the run.
+      match self.ceiling(low)?
+      {
+        Some((live, _)) =>
+        {
+          deleted += self.delete_range(low .. live)?;
+          match live.checked_add(1)
+          {
+            Some(next) => from = next,
+            None       => break
+          }
+        },
+        None =>
+        {
+          deleted += self.delete_range(low ..)?;
+          break
+        }
+      }
+    }
+    Ok(deleted)
+  }
+
+  /// The least key in [low, high] whose expiry time has passed, if
+  /// any
+  fn next_expired(&mut self, low: u32, high: u32) -> BPlusResult<Option<u32>>
+  {
+    let now = self.clock.now();
+    let Some(expiries) = self.expiries.as_mut() else { return Ok(None) };
+    for record in expiries.range(low ..= high)?
+    {
+      let (key, expires_at) = record?;
+      if expires_at <= now && !self.stale_expiries.contains(key) { return Ok(Some(key)) }
+    }
+    Ok(None)
+  }
+
+  /// Up to `n` entries of the expiry index, from `low` on
+  fn expiry_entries(&mut self, low: u32, n: usize) -> BPlusResult<Vec<(u32, u32)>>
+  {
+    match self.expiries.as_mut()
+    {
+      Some(expiries) => expiries.range(low ..)?.take(n).collect(),
+      None           => Ok(Vec::new())
+    }
+  }
+
+  /// True if `key`'s expiry time is `expires_at`, and it has passed
+  fn has_expired(&self, key: u32, expires_at: u32) -> bool
+  {
+    expires_at <= self.clock.now() && !self.stale_expiries.contains(key)
+  }
+
+  /// True if `key` has an expiry time, and it has passed
+  fn is_expired(&mut self, key: u32) -> BPlusResult<bool>
+  {
+    match self.expiry(key)?
+    {
+      Some(expires_at) => Ok(self.has_expired(key, expires_at)),
+      None             => Ok(false)
+    }
+  }
+
+  /// `value`, unless it is the value of an expired key
+  fn unless_expired(&mut self, key: u32, value: Option<u32>) -> BPlusResult<Option<u32>>
+  {
+    if value.is_some() && self.is_expired(key)? { Ok(None) }
+    else                                        { Ok(value) }
+  }
+
+  /// Drop the expiry times of the keys in [low, high], once the 
+  /// writes that removed or replaced their values are committed 
+  /// (see `put_expiring`)
+  ///
+  /// Until then, the keys are treated as having no expiry time.  
+  /// Under SyncPolicy::Never, nothing is committed atomically, and
+  /// the times are dropped right away.
+  fn forget_expiries(&mut self, low: u32, high: u32) -> BPlusResult<()>
+  {
+    let Some(expiries) = self.expiries.as_mut() else { return Ok(()) };
+    if self.policy == SyncPolicy::Never
+    {
+      expiries.delete_range(low ..= high)?;
+    }
+    else
+    {
+      self.stale_expiries.insert(low, high);
+    }
+    Ok(())
+  }
+
+  /// Drop the expiry times made stale by writes that have now been
+  /// committed (see `forget_expiries`)
+  fn drop_stale_expiries(&mut self) -> BPlusResult<()>
+  {
+    let Some(expiries) = self.expiries.as_mut() else { return Ok(()) };
+    let stale = self.stale_expiries.take();
+    if stale.is_empty() { return Ok(()) }
+    for (low, high) in stale
+    {
+      expiries.delete_range(low ..= high)?;
+    }
+    expiries.sync()
+  }
+
+  ////////////////////////////////////////////////////////////////
+  ///////////////////////// Change Log ///////////////////////////
+  ////////////////////////////////////////////////////////////////
+
+  /// Log every change to the tree from now on in `storage` (see 
+  /// ChangeLog), which should be empty, or hold this tree's log
+  ///
+  /// Changes logged past the tree's last committed change (e.g., 
+  /// before a crash) are dropped.  Empty storage starts a new log
+  /// just after the tree's last change (e.g., to leave a long log
+  /// behind; see ChangeLog).  A tree with followers can't keep a
+  /// log (see `set_replica`).
+  pub fn set_change_log(&mut self, storage: Box<dyn Storage>) -> BPlusResult<()>
+  {
+    if self.replica.is_some()
+    {
+      return Err(BPlusError::Unsupported("Followers can't be sent a tree's expiry index or change log".into()))
+    }
+    self.changes = Some(ChangeLog::open(storage, self.meta.last_seq)?);
+    Ok(())
+  }
+
+  /// The sequence number of the last logged change to the tree (0
+  /// if there hasn't been one)
+  pub fn last_seq(&self) -> u64
+  {
+    self.meta.last_seq
+  }
+
+  /// Up to `limit` logged changes with sequence numbers greater 
+  /// than `seq`, in order
+  ///
+  /// A consumer that has applied every change up to `seq` can 
+  /// resume from there, including after either side restarts.  
+  /// Every successful `put` (and the like), delete, `delete_range`,
+  /// `clear`, and `split_off` is logged; `append` is logged as a 
+  /// put of each record.  Operations that change nothing (e.g., 
+  /// deleting a missing key) aren't logged.
+  pub fn changes_since(&mut self, seq: u64, limit: usize) -> BPlusResult<Vec<(u64, Mutation)>>
+  {
+    let changes = self.timed_changes_since(seq, limit)?;
+    Ok(changes.iter().map(|change| (change.seq, change.mutation)).collect())
+  }
+
+  /// As `changes_since`, with the time each change was made
+  pub fn timed_changes_since(&mut self, seq: u64, limit: usize) -> BPlusResult<Vec<LoggedChange>>
+  {
+    let Some(changes) = self.changes.as_mut() else
+    {
+      return Err(BPlusError::Unsupported("The tree doesn't log its changes".into()))
+    };
+    if seq + 1 < changes.first_seq()
+    {
+      return Err(BPlusError::Unsupported(format!("Changes before {} aren't logged", changes.first_seq())))
+    }
+    changes.read(seq + 1, limit)
+  }
+
+  /// Add a change to the change log (if any), stamped with the 
+  /// clock's time.  Its sequence number is committed to the 
+  /// metadata page along with the change.
+  fn log_change(&mut self, mutation: Mutation) -> BPlusResult<()>
+  {
+    if let Some(changes) = self.changes.as_mut()
+    {
+      self.meta.last_seq = changes.append(mutation, self.clock.now())?;
+      self.put_meta()?;
+    }
+    Ok(())
+  }
+
+  /// Make a logged change to the tree (e.g., to replay another 
+  /// tree's change log)
+  pub fn apply(&mut self, mutation: Mutation) -> BPlusResult<()>
+  {
+    match mutation
+    {
+      Mutation::Put { key, value }        => self.put(key, value),
+      Mutation::Delete { key }            => self.delete(key),
+      Mutation::DeleteRange { low, high } => self.delete_range(low ..= high).map(|_| ()),
+      Mutation::Clear                     => self.clear(),
+    }
+  }
+
+  ////////////////////////////////////////////////////////////////
+  /////////////////////// Backup and Restore /////////////////////
+  ////////////////////////////////////////////////////////////////
+
+  /// Track the generation in which each page is written from now
+  /// on in `storage` (see GenerationMap), which should be empty, or
+  /// hold this tree's map, so that `backup_incremental` can find 
+  /// the pages written since a backup
+  ///
+  /// Once a tree tracks generations, it should always be opened
+  /// with its map: pages written without it are missed by 
+  /// incremental backups.
+  pub fn set_generation_map(&mut self, storage: Box<dyn Storage>) -> BPlusResult<()>
+  {
+    self.generations = Some(GenerationMap::open(storage, self.meta.generation, self.meta.pages_allocated)?);
+    Ok(())
+  }
+
+  /// The generation that pages written now belong to.  Every
+  /// backup ends a generation.
+  pub fn generation(&self) -> u32
+  {
+    self.meta.generation
+  }
+
+  /// Copy the tree to `dest`, which becomes a tree of its own
+  ///
+  /// The tree stays open.  Pending writes are committed first, so
+  /// the copy holds exactly the state that a crash would leave.
+  /// Each page is checked as it is read, so that a damaged page 
+  /// fails the backup rather than being copied, and each copy is
+  /// checked against the original (see `copy_pages`).  The expiry
+  /// index, change log, and generation map aren't copied.
+  ///
+  /// The whole tree is copied at once; to keep writing to the tree
+  /// while it is copied, see `begin_backup`.
+  pub fn backup(&mut self, dest: &mut dyn Storage) -> BPlusResult<BackupInfo>
+  {
+    self.check_backup_storage(dest)?;
+    self.sync()?;
+    copy_pages(self.storage.as_mut(), dest, 0 .. self.meta.pages_allocated, check_tree_page)?;
+    dest.sync()?;
+    self.end_generation()
+  }
+
+  /// Start copying the tree to `dest` as `backup` does, but a few
+  /// pages at a time (see `backup_step`), so that the tree can be
+  /// written in between
+  ///
+  /// Pages written after they are copied are copied again, so the
+  /// finished backup holds the tree as of the step that finishes 
+  /// it.  Any backup already in progress is abandoned.
+  pub fn begin_backup(&mut self, dest: Box<dyn Storage>) -> BPlusResult<()>
+  {
+    self.check_backup_storage(dest.as_ref())?;
+    self.backup = Some(BackupProgress::new(dest));
+    Ok(())
+  }
+
+  /// Copy up to `pages` more pages of the backup in progress (see 
+  /// `begin_backup`), committing any pending writes first
+  ///
+  /// Every page is copied once, then those written since they were
+  /// copied are copied again.  Once no more than `pages` are left 
+  /// to copy, they are copied along with the metadata page, and the
+  /// backup is finished and described.  Until then, returns None.  
+  /// If a step fails, the backup is abandoned.
+  pub fn backup_step(&mut self, pages: PagePointer) -> BPlusResult<Option<BackupInfo>>
+  {
+    let Some(mut progress) = self.backup.take() else
+    {
+      return Err(BPlusError::Unsupported("No backup is in progress".into()))
+    };
+    // So that storage holds every write the backup must see
+    self.sync()?;
+    let count = self.meta.pages_allocated;
+    if progress.next < count
+    {
+      let end = count.min(progress.next.saturating_add(pages));
+      copy_pages(self.storage.as_mut(), progress.dest.as_mut(), progress.next .. end, check_tree_page)?;
+      progress.next = end;
+      self.backup = Some(progress);
+      return Ok(None)
+    }
+    // The metadata page is written by nearly every operation, so 
+    // it is only copied again once the rest of the backup is done
+    progress.rewritten.retain(|ptr| *ptr < count && *ptr != METADATA_IDX);
+    if progress.rewritten.len() as PagePointer > pages
+    {
+      let again: Vec<PagePointer> = progress.rewritten.iter().copied().take(pages as usize).collect();
+      copy_pages(self.storage.as_mut(), progress.dest.as_mut(), again.iter().copied(), check_tree_page)?;
+      for ptr in again { progress.rewritten.remove(&ptr); }
+      self.backup = Some(progress);
+      return Ok(None)
+    }
+    let again = std::mem::take(&mut progress.rewritten);
+    copy_pages(self.storage.as_mut(), progress.dest.as_mut(), again.into_iter().chain([METADATA_IDX]), check_tree_page)?;
+    // The tree may have shrunk (see `clear`)
+    progress.dest.truncate(count)?;
+    progress.dest.sync()?;
+    self.end_generation().map(Some)
+  }
+
+  /// Write the pages written since the backup that ended generation
+  /// `since` to `dest`, as a delta for `restore` to apply on top of
+  /// that backup
+  ///
+  /// Deltas are usually taken since the previous one, and applied
+  /// in order, but a delta since any earlier backup holds 
+  /// everything that one since a later backup would.  Requires a
+  /// generation map (see `set_generation_map`).
+  pub fn backup_incremental(&mut self, since: u32, dest: &mut dyn Storage) -> BPlusResult<BackupInfo>
+  {
+    self.check_backup_storage(dest)?;
+    let Some(generations) = self.generations.as_ref() else
+    {
+      return Err(BPlusError::Unsupported("The tree doesn't track page generations".into()))
+    };
+    if since >= self.meta.generation
+    {
+      return Err(BPlusError::Unsupported(format!("No backup has ended generation {} (the current generation is {})", since, self.meta.generation)))
+    }
+    let pointers: Vec<PagePointer> = (0 .. self.meta.pages_allocated)
+      .filter(|ptr| *ptr == METADATA_IDX || generations.generation(*ptr) > since)
+      .collect();
+    self.sync()?;
+    write_delta(self.storage.as_mut(), dest, &pointers, since, self.meta.generation)?;
+    self.end_generation()
+  }
+
+  /// Return an error unless the tree could be backed up to `dest`
+  fn check_backup_storage(&self, dest: &dyn Storage) -> BPlusResult<()>
+  {
+    BPlusTree::check_storage(dest)?;
+    if dest.cipher() != self.storage.cipher() || dest.key_check() != self.storage.key_check()
+    {
+      return Err(BPlusError::IncompatibleStorage("A backup must be encrypted with the same cipher and key as the tree".into()))
+    }
+    Ok(())
+  }
+
+  /// The sequence number of the last change the tree has committed
+  /// (see `last_seq`), as recorded on its metadata page in storage
+  fn committed_seq(&mut self) -> BPlusResult<u64>
+  {
+    let meta = MetadataPage::read(self.storage.as_mut(), METADATA_IDX)?;
+    meta.check(METADATA_IDX)?;
+    Ok(meta.last_seq)
+  }
+
+  /// Start a new generation, once a backup has copied the current
+  /// one, and describe the backup
+  fn end_generation(&mut self) -> BPlusResult<BackupInfo>
+  {
+    let info = BackupInfo { last_seq: self.meta.last_seq, generation: self.meta.generation };
+    self.meta.generation += 1;
+    self.put_meta()?;
+    self.sync()?;
+    Ok(info)
+  }
+
+  /// Restore the tree backed up in `backup` to `dest`, apply the
+  /// `deltas` taken since (see `backup_incremental`) in order, then
+  /// replay the changes in `source`'s change log made after the 
+  /// last of them, up to `until`
+  ///
+  /// `source` is the tree that was backed up, and isn't changed.  
+  /// Only the changes it has committed (up to the `last_seq` on its
+  /// metadata page in storage) are replayed, since any after that
+  /// haven't taken effect yet.  The restored tree's `last_seq` is 
+  /// the last change replayed; to keep logging its changes, give 
+  /// it a copy of the source's log (see `set_change_log`), which 
+  /// drops the changes after that.
+  pub fn restore(backup: &mut dyn Storage, deltas: Vec<Box<dyn Storage>>, dest: Box<dyn Storage>, 
+    source: Option<&mut BPlusTree>, until: RestorePoint) -> BPlusResult<BPlusTree>
+  {
+    BPlusTree::check_storage(backup)?;
+    let meta = MetadataPage::read(backup, METADATA_IDX)?;
+    meta.check(METADATA_IDX)?;
+    let mut dest = dest;
+    BPlusTree::check_storage(dest.as_ref())?;
+    copy_pages(backup, dest.as_mut(), 0 .. meta.pages_allocated, check_tree_page)?;
+    dest.sync()?;
+    for mut delta in deltas
     {
-      // ooops... only one leaf page.  We can't delete it, so
-      // leave it be.  Don't forget to flush though.
-      self.put_page(leaf_ptr, &leaf_page)?;
-      return Ok(())
+      BPlusTree::check_storage(delta.as_ref())?;
+      apply_delta(dest.as_mut(), delta.as_mut())?;
     }
+    let mut tree = BPlusTree::open_with(dest)?;
 
-    // If theft fails, merge pages
-    if merge_is_low
+    let Some(source) = source else { return Ok(tree) };
+    let committed = source.committed_seq()?;
+    let Some(log) = source.changes.as_mut() else
     {
-      merge_page.merge_with(&leaf_page);
-      merge_page.next = leaf_page.next;
-      if merge_page.next == NULL_IDX { 
-        self.meta.data_tail = merge_ptr;
-        self.put_meta()?;
-      } else {
-        let mut temp_page: LeafPage = self.get_page(merge_page.next)?;
-        temp_page.prev = merge_ptr;
-        self.put_page(merge_page.next, &temp_page)?
-      }
-      dir_page.delete_idx(dir_idx);
-      self.free_page(leaf_ptr)?;
-      self.put_page(merge_ptr, &merge_page)?;
-      self.put_page(dir_ptr, &dir_page)?;
-    } else
+      return Err(BPlusError::Unsupported("The tree doesn't log its changes".into()))
+    };
+    if tree.meta.last_seq + 1 < log.first_seq()
     {
-      leaf_page.merge_with(&merge_page);
-      leaf_page.next = merge_page.next;
-      if leaf_page.next == NULL_IDX { 
-        self.meta.data_tail = leaf_ptr;
-        self.put_meta()?;
-      } else {
-        let mut temp_page: LeafPage = self.get_page(leaf_page.next)?;
-        temp_page.prev = leaf_ptr;
-        self.put_page(leaf_page.next, &temp_page)?
+      return Err(BPlusError::Unsupported(format!("The log starts at change {}, after the backup's last change ({})", log.first_seq(), tree.meta.last_seq)))
+    }
+    if let RestorePoint::Seq(seq) = until
+    {
+      if seq < tree.meta.last_seq
+      {
+        return Err(BPlusError::Unsupported(format!("The backup already includes changes up to {}, past {}", tree.meta.last_seq, seq)))
       }
-      dir_page.delete_idx(dir_idx+1);
-      self.free_page(merge_ptr)?;
-      self.put_page(leaf_ptr, &leaf_page)?;
-      self.put_page(dir_ptr, &dir_page)?;
     }
-    if dir_page.is_underfull()
-    { 
-      self.merge_dir_page(&ptr_stack[0..ptr_stack.len()-1], key)?
+    'replay: loop
+    {
+      let changes = log.read(tree.meta.last_seq + 1, LOG_CAPACITY)?;
+      if changes.is_empty() { break }
+      for change in changes
+      {
+        if change.seq > committed || until.excludes(&change) { break 'replay }
+        tree.apply(change.mutation)?;
+        tree.meta.last_seq = change.seq;
+        tree.put_meta()?;
+      }
     }
+    tree.sync()?;
+    Ok(tree)
+  }
 
+  ////////////////////////////////////////////////////////////////
+  ///////////////////////// Replication //////////////////////////
+  ////////////////////////////////////////////////////////////////
+
+  /// Send every batch the tree commits from now on to `replica`, 
+  /// for followers to apply with `apply_batch`
+  ///
+  /// A follower starts from a backup (see `backup`) taken after 
+  /// the replica was set.  Batches are sent once they are durable,
+  /// so followers are never ahead of the tree.  Requires a sync
+  /// policy other than SyncPolicy::Never.
+  ///
+  /// Only the tree's own pages are sent, so a tree that keeps an 
+  /// expiry index or a change log can't have followers.
+  pub fn set_replica(&mut self, replica: Box<dyn Replica>) -> BPlusResult<()>
+  {
+    if self.policy == SyncPolicy::Never
+    {
+      return Err(BPlusError::Unsupported("Followers need a tree that commits through its journal".into()))
+    }
+    if self.expiries.is_some() || self.changes.is_some()
+    {
+      return Err(BPlusError::Unsupported("Followers can't be sent a tree's expiry index or change log".into()))
+    }
+    self.sync()?;
+    self.replica = Some(replica);
     Ok(())
-    // END SNIP
   }
 
-  fn merge_dir_page(&mut self, ptr_stack: &[PagePointer], key: u32) -> BPlusResult<()>
+  /// Stop sending committed batches to the replica, and return it
+  /// (if any).  Any writes still waiting are committed and sent 
+  /// first.
+  pub fn remove_replica(&mut self) -> BPlusResult<Option<Box<dyn Replica>>>
   {
-    // println!("Merge Dir @ {:?}", ptr_stack);
-    let dir_ptr = ptr_stack[ptr_stack.len()-1];
-    let mut dir_page = self.get_page::<DirectoryPage>(dir_ptr)?;
-
-    if ptr_stack.len() <= 1
-    {
-      // Root merge
-      // Case 1: The root has > 1 key.  This is ok.  Leave
-      //         it as is.
-      if dir_page.count >= 1 { return Ok(()); }
+    self.sync()?;
+    Ok(self.replica.take())
+  }
 
-      // Case 2: The tree is a single level deep
-      if self.meta.depth == 1 { return Ok(()); }
+  /// Why the replica was let go, if it failed to take a batch since
+  /// this was last called
+  ///
+  /// The batch it failed on was committed all the same, so its 
+  /// followers are left behind, and need a new backup to follow a
+  /// new replica from.
+  pub fn take_replica_error(&mut self) -> Option<BPlusError>
+  {
+    self.replica_error.take()
+  }
 
-      // Case 3: The root has no keys.  Replace the root with
-      //         the page being pointed to
-      self.meta.root_page = dir_page.pointers[0];
-      self.meta.depth -= 1;
-      self.put_meta()?;
-      self.free_page(ptr_stack[0])?;
-      return Ok(())
-    } else 
-    {
-      let parent_ptr = ptr_stack[ptr_stack.len()-2];
-      let mut parent_page = self.get_page::<DirectoryPage>(parent_ptr)?;
-      let dir_idx = parent_page.find_pointer_idx(key);
+  /// The number of batches committed through the journal
+  pub fn commits(&self) -> u64
+  {
+    self.meta.commits
+  }
 
-      let sibling_is_low;
-      let sibling_ptr;
-      let mut sibling_page: DirectoryPage;
+  /// The batch that was just committed, if any
+  fn committed_batch(&self) -> Option<PageBatch>
+  {
+    let meta = self.pending.get(&METADATA_IDX)?;
+    Some(PageBatch {
+      commit: self.meta.commits,
+      generation: self.meta.generation,
+      pages: self.pending.iter()
+        .filter(|(ptr, _)| **ptr != METADATA_IDX)
+        .map(|(ptr, image)| (*ptr, image.clone()))
+        .collect(),
+      meta: meta.clone(),
+    })
+  }
 
-      // Attempt thefts
-      if dir_idx > 0 
-      {
-        sibling_ptr = parent_page.pointers[dir_idx-1];
-        sibling_page = self.get_page::<DirectoryPage>(sibling_ptr)?;
-        if sibling_page.can_allow_stolen_key()
-        {
-          // println!("STEALING HIGH TO PAGE[{}] = {:?}\nFROM: PAGE[{}] = {:?}", dir_ptr, dir_page, sibling_ptr, sibling_page);
-          let new_parent_key = 
-            dir_page.steal_high_from(&mut sibling_page, parent_page.keys[dir_idx-1]);
-          parent_page.keys[dir_idx-1] = new_parent_key;
-          self.put_page(dir_ptr, &dir_page)?;
-          self.put_page(sibling_ptr, &sibling_page)?;
-          self.put_page(parent_ptr, &parent_page)?;
-          return Ok(())
-        } else {
-          // self.write_tree()?;
-          // println!("Merge #{} -> #{}", prev_leaf_ptr, leaf_ptr);
-          sibling_is_low = true;
-        }
-      } else
+  /// Apply a batch committed by the leader this tree follows (see
+  /// `set_replica`)
+  ///
+  /// Batches must be applied in the order the leader committed 
+  /// them.  Each is applied atomically if the follower has a 
+  /// journal, so the follower can be read between batches.  A 
+  /// follower should not be written to in any other way.
+  ///
+  /// Returns false, and changes nothing, if the follower already 
+  /// has the batch (e.g., from the backup it started from).  A 
+  /// batch from a leader encrypted differently, or with a different
+  /// page layout, is refused.
+  pub fn apply_batch(&mut self, batch: &PageBatch) -> BPlusResult<bool>
+  {
+    if batch.commit <= self.meta.commits { return Ok(false) }
+    if batch.commit != self.meta.commits + 1
+    {
+      return Err(BPlusError::OutOfOrder { expected: self.meta.commits + 1, found: batch.commit })
+    }
+    // The leader's pages must be readable here, with this storage's
+    // cipher and key, and this build's layout
+    let meta = MetadataPage::decode(&batch.meta.0);
+    meta.check(METADATA_IDX)?;
+    BPlusTree::check_meta(&meta, self.storage.as_ref())?;
+    self.sync()?;
+    let mut pages: PendingPages = batch.pages.iter().cloned().collect();
+    pages.insert(METADATA_IDX, batch.meta.clone());
+    // So that incremental backups can be taken from the follower
+    if let Some(generations) = self.generations.as_mut()
+    {
+      for ptr in pages.keys()
       {
-        sibling_ptr = parent_page.pointers[dir_idx+1];
-        sibling_page = self.get_page::<DirectoryPage>(sibling_ptr)?;
-        if sibling_page.can_allow_stolen_key()
-        {
-          // println!("STEALING LOW TO PAGE[{}] = {:?}\nFROM: PAGE[{}] = {:?}", dir_ptr, dir_page, sibling_ptr, sibling_page);
-          let new_parent_key = 
-            dir_page.steal_low_from(&mut sibling_page, parent_page.keys[dir_idx]);
-          parent_page.keys[dir_idx] = new_parent_key;
-          self.put_page(dir_ptr, &dir_page)?;
-          self.put_page(sibling_ptr, &sibling_page)?;
-          self.put_page(parent_ptr, &parent_page)?;
-          return Ok(())
-        } else {
-          // self.write_tree()?;
-          // println!("Merge #{} <- #{}", leaf_ptr, next_leaf_ptr);
-          sibling_is_low = false;
-        }
+        generations.stamp(*ptr, batch.generation)?;
       }
+      generations.sync()?;
+    }
+    if let Some(backup) = self.backup.as_mut()
+    {
+      for ptr in pages.keys() { backup.written(*ptr); }
+    }
+    match self.journal.as_mut()
+    {
+      Some(journal) => journal.commit(&pages, self.storage.as_mut())?,
+      None          => journal::apply(self.storage.as_mut(), pages.iter().map(|(ptr, image)| (*ptr, image)))?,
+    }
+    let meta = MetadataPage::read(self.storage.as_mut(), METADATA_IDX)?;
+    meta.check(METADATA_IDX)?;
+    self.meta = meta;
+    Ok(true)
+  }
 
-      // If theft fails, merge pages
-      
-      assert!(sibling_ptr != NULL_IDX); // at least one of the above
-                                        // must have failed
-      if sibling_is_low
-      {
-        // println!("Before Merge 1 <-: {:?}", sibling_page);
-        // println!("Before Merge 2: {:?}", dir_page);
-        sibling_page.merge_with(&dir_page, parent_page.keys[dir_idx-1]);
-        // println!("After Merge @ {}: {:?}", dir_idx, sibling_page);
-        parent_page.delete_idx(dir_idx);
-        self.free_page(dir_ptr)?;
-        self.put_page(sibling_ptr, &sibling_page)?;
-        self.put_page(parent_ptr, &parent_page)?;
-      } else
-      {
-        // println!("Before Merge 1 ->: {:?}", sibling_page);
-        // println!("Before Merge 2: {:?}", dir_page);
-        dir_page.merge_with(&sibling_page, parent_page.keys[dir_idx]);
-        // println!("After Merge @ {}: {:?}", dir_idx, dir_page);
-        parent_page.delete_idx(dir_idx+1);
-        self.free_page(sibling_ptr)?;
-        self.put_page(dir_ptr, &dir_page)?;
-        self.put_page(parent_ptr, &parent_page)?;
-      }
-      if parent_page.is_underfull()
-      { 
-        self.merge_dir_page(&ptr_stack[0..ptr_stack.len()-1], key)?
-      }
+  /// Apply every batch waiting on the channel, without blocking,
+  /// and return the number applied
+  pub fn catch_up(&mut self, batches: &Receiver<PageBatch>) -> BPlusResult<usize>
+  {
+    let mut applied = 0;
+    for batch in batches.try_iter()
+    {
+      if self.apply_batch(&batch)? { applied += 1; }
     }
-    Ok(())
+    Ok(applied)
   }
 
   ////////////////////////////////////////////////////////////////
@@ -710,6 +3120,18 @@ impl BPlusTree
     self.meta.depth
   }
 
+  /// Return the pointer to the root directory page
+  pub fn root_page(&self) -> PagePointer
+  {
+    self.meta.root_page
+  }
+
+  /// Return the pointers to the first and last leaf pages
+  pub fn data_bounds(&self) -> (PagePointer, PagePointer)
+  {
+    (self.meta.data_head, self.meta.data_tail)
+  }
+
   /// Sanity check the tree
   ///
   /// Returns a string containing the first problem it encounters
@@ -785,7 +3207,8 @@ impl BPlusTree
       last_data = curr_ptr;
 
       // Ascend until we have a 'next'
-      (curr_ptr, curr_idx, low, high) = dir_stack.pop().unwrap();
+      (curr_ptr, curr_idx, low, high) = 
+        dir_stack.pop().ok_or_else(|| BPlusError::corrupt(METADATA_IDX, format!("Tree has depth {}, but must have a directory page", self.meta.depth)))?;
       if curr_ptr >= self.meta.pages_allocated 
       { 
         if dir_stack.is_empty() { return Ok(Some(format!("Invalid root pointer for tree: {}", curr_ptr))); }
@@ -801,7 +3224,7 @@ impl BPlusTree
             None => {
               if next_data != 0                   { return Ok(Some(format!("Last data page {} points to {} and not NULL", last_data, next_data)))}
               if last_data != self.meta.data_tail { return Ok(Some(format!("Metadata tail pointer points to {} and not {}", self.meta.data_tail, last_data)))}
-              return Ok(None)
+              return self.check_counts()
             }
           };
         if curr_ptr >= self.meta.pages_allocated 
@@ -817,52 +3240,326 @@ impl BPlusTree
   }
 
 
+  /// Check the record count and summary of every directory 
+  /// pointer against the records actually under it.  Part of 
+  /// `check_tree`.
+  fn check_counts(&mut self) -> BPlusResult<Option<String>>
+  {
+    fn rcr(tree: &mut BPlusTree, ptr: PagePointer, depth: u16) -> BPlusResult<Result<(u64, Summary), String>>
+    {
+      if depth == tree.meta.depth
+      {
+        let leaf = tree.get_page::<LeafPage>(ptr)?;
+        return Ok(Ok((leaf.count as u64, leaf.summary())))
+      }
+      let dir = tree.get_page::<DirectoryPage>(ptr)?;
+      let mut total = 0;
+      for i in 0 .. dir.count+1
+      {
+        let (actual, summary) = match rcr(tree, dir.pointers[i], depth+1)? { Ok(n) => n, Err(err) => return Ok(Err(err)) };
+        if actual != dir.counts[i] as u64
+        {
+          return Ok(Err(format!("Page {} counts {} records under pointer {}, but there are {}", ptr, dir.counts[i], i, actual)))
+        }
+        if summary != dir.summaries[i]
+        {
+          return Ok(Err(format!("Page {} summarizes pointer {} as {:?}, but it holds {:?}", ptr, i, dir.summaries[i], summary)))
+        }
+        total += actual;
+      }
+      Ok(Ok((total, dir.summary())))
+    }
+    let root = self.meta.root_page;
+    Ok(rcr(self, root, 0)?.err())
+  }
+
+  /// Retrieve the type code (e.g., DIR_PAGE_T) of the page at 
+  /// the specified index without decoding it.
+  pub fn page_type(&mut self, ptr: PagePointer) -> BPlusResult<u8>
+  {
+    if ptr >= self.meta.pages_allocated
+    {
+      return Err(BPlusError::PageOutOfBounds { page: ptr, len: self.meta.pages_allocated })
+    }
+    if let Some(buffer) = self.pending.get(&ptr) { return Ok(buffer.0[0]) }
+    let mut buffer = [0u8; PAGE_SIZE];
+    self.storage.read_page(ptr, &mut buffer)?;
+    Ok(buffer[0])
+  }
+
   /// Helper function: print the entire tree
   pub fn print_tree(&mut self) -> BPlusResult<()>
   {
-    fn rcr(tree: &mut BPlusTree, page: PagePointer, depth: u16)
+    self.write_tree(&mut std::io::stdout())
+  }
+
+  /// Helper function: write the entire tree to the provided output
+  pub fn write_tree(&mut self, out: &mut dyn Write) -> BPlusResult<()>
+  {
+    fn rcr(tree: &mut BPlusTree, out: &mut dyn Write, page: PagePointer, depth: u16)
       -> BPlusResult<()>
     {
+      let indent = " ".repeat((depth*2) as usize);
       if depth < tree.meta.depth
       {
         let data = tree.get_page::<DirectoryPage>(page)?;
-        println!("{}PAGE[{}] = {:?}\n", std::iter::repeat(" ").take((depth*2) as usize).collect::<String>(), page, data);
+        writeln!(out, "{}PAGE[{}] = {:?}\n", indent, page, data)?;
         for page in &data.pointers[0 .. data.count+1]
         {
-          rcr(tree, page.clone(), depth+1)?;
+          rcr(tree, out, *page, depth+1)?;
         }
       } else
       {
         let data = tree.get_page::<LeafPage>(page)?;
-        println!("{}PAGE[{}] = {:?}\n", std::iter::repeat(" ").take((depth*2) as usize).collect::<String>(), page, data);
+        writeln!(out, "{}PAGE[{}] = {:?}\n", indent, page, data)?;
       }
       Ok(())
     }
-    rcr(self, self.meta.root_page, 0)
+    let root = self.meta.root_page;
+    rcr(self, out, root, 0)
+  }
+}
+
+impl Drop for BPlusTree
+{
+  /// Write out anything still waiting on the sync policy
+  ///
+  /// Errors can't be reported from here, so call `sync` first to
+  /// find out whether the last writes were committed.  Those that
+  /// weren't are lost, as in a crash.
+  fn drop(&mut self)
+  {
+    let _ = 
+      if self.policy == SyncPolicy::Never { self.flush_meta() }
+      else                                { self.sync() };
+  }
+}
+
+#[allow(dead_code)]
+impl<'a> BPlusTreeIterator<'a>
+{
+  /// Skip ahead to the first record with a key greater than or 
+  /// equal to `key`.  Never moves backwards.
+  ///
+  /// If the key would be on the current leaf, no pages are read.
+  /// Otherwise, its leaf is found from the root (as in `range`),
+  /// rather than by following `next` pointers through every leaf
+  /// in between.
+  pub fn seek(&mut self, key: u32) -> BPlusResult<()>
+  {
+    if let Bound::Excluded(0) = self.end { return Ok(()) }
+    if self.idx < self.page.count && self.page.get(self.idx).0 >= key { return Ok(()) }
+    if self.page.count == 0 || self.page.get(self.page.count-1).0 < key
+    {
+      let v = self.tree.find_page(key)?;
+      self.page = self.tree.get_page::<LeafPage>(v[v.len()-1])?;
+      self.idx = 0;
+    }
+    let (Ok(idx) | Err(idx)) = self.page.find_index(key);
+    self.idx = self.idx.max(idx);
+    Ok(())
+  }
+}
+
+#[allow(dead_code)]
+impl<'a> Cursor<'a>
+{
+  /// The record the cursor is on, or None if it is before the 
+  /// first record or after the last
+  pub fn current(&self) -> Option<(u32, u32)>
+  {
+    if self.before_start || self.idx >= self.page.count { None }
+    else { Some(self.page.get(self.idx)) }
+  }
+
+  /// Move to the first record with a key greater than or equal to
+  /// `key`, and return it
+  pub fn seek(&mut self, key: u32) -> BPlusResult<Option<(u32, u32)>>
+  {
+    let v = self.tree.find_page(key)?;
+    self.ptr = v[v.len()-1];
+    self.page = self.tree.get_page::<LeafPage>(self.ptr)?;
+    let (Ok(idx) | Err(idx)) = self.page.find_index(key);
+    self.idx = idx;
+    self.before_start = false;
+    self.settle()?;
+    Ok(self.current())
+  }
+
+  /// Move to the next record and return it.  Past the last record,
+  /// returns None and stays put.
+  pub fn next(&mut self) -> BPlusResult<Option<(u32, u32)>>
+  {
+    if self.before_start { self.before_start = false; }
+    else if self.idx < self.page.count { self.idx += 1; }
+    self.settle()?;
+    Ok(self.current())
+  }
+
+  /// Move to the previous record and return it.  Before the first
+  /// record, returns None and stays put.
+  pub fn prev(&mut self) -> BPlusResult<Option<(u32, u32)>>
+  {
+    if self.before_start { return Ok(None) }
+    loop
+    {
+      if self.idx > 0
+      {
+        self.idx -= 1;
+        if self.tree.is_expired(self.page.get(self.idx).0)? { continue }
+        return Ok(self.current())
+      }
+      if self.page.prev == NULL_IDX
+      {
+        self.before_start = true;
+        return Ok(None)
+      }
+      self.ptr = self.page.prev;
+      self.page = self.tree.get_page::<LeafPage>(self.ptr)?;
+      self.idx = self.page.count;
+    }
+  }
+
+  /// Replace the value of the current record in place, and return
+  /// the old value.  Returns None (and changes nothing) if the 
+  /// cursor isn't on a record.
+  pub fn update_value(&mut self, value: u32) -> BPlusResult<Option<u32>>
+  {
+    let Some((key, old)) = self.current() else { return Ok(None) };
+    self.page.key_value[self.idx].1 = value;
+    self.tree.put_page(self.ptr, &self.page)?;
+    self.tree.log_change(Mutation::Put { key, value })?;
+    if Summary::KEPT
+    {
+      let path = self.tree.find_page(key)?;
+      self.tree.update_path(&path[..path.len()-1], key, 0, self.page.summary())?;
+    }
+    self.tree.end_op()?;
+    Ok(Some(old))
+  }
+
+  /// Delete the current record, and return it.  The cursor moves
+  /// on to the following record.  Returns None (and changes 
+  /// nothing) if the cursor isn't on a record.
+  pub fn delete_current(&mut self) -> BPlusResult<Option<(u32, u32)>>
+  {
+    let Some(record) = self.current() else { return Ok(None) };
+    self.tree.delete_key(record.0)?;
+    self.tree.end_op()?;
+    // The deletion may have moved records between leaves
+    self.seek(record.0)?;
+    Ok(Some(record))
+  }
+
+  /// Move forward past any expired records, and off the end of the
+  /// current leaf onto the next one, if there is one
+  fn settle(&mut self) -> BPlusResult<()>
+  {
+    loop
+    {
+      if self.idx < self.page.count
+      {
+        if !self.tree.is_expired(self.page.get(self.idx).0)? { break }
+        self.idx += 1;
+      }
+      else if self.page.next != NULL_IDX
+      {
+        self.ptr = self.page.next;
+        self.page = self.tree.get_page::<LeafPage>(self.ptr)?;
+        self.idx = 0;
+      }
+      else { break }
+    }
+    Ok(())
+  }
+}
+
+impl<'a> BPlusTreeIterator<'a>
+{
+  /// True if `key` has an expiry time, and it has passed.  Keys 
+  /// must be passed in increasing order.
+  fn is_expired(&mut self, key: u32) -> BPlusResult<bool>
+  {
+    loop
+    {
+      while self.expiring.front().is_some_and(|(k, _)| *k < key)
+      {
+        self.expiring.pop_front();
+      }
+      let Some(from) = self.expiring_from else { break };
+      if !self.expiring.is_empty() { break }
+      // Read ahead from `key`, skipping any entries before it
+      let entries = self.tree.expiry_entries(from.max(key), EXPIRY_READ_AHEAD)?;
+      self.expiring_from = 
+        match entries.last()
+        {
+          Some((k, _)) if entries.len() == EXPIRY_READ_AHEAD => k.checked_add(1),
+          _                                                  => None
+        };
+      self.expiring.extend(entries);
+    }
+    match self.expiring.front()
+    {
+      Some((k, expires_at)) if *k == key => Ok(self.tree.has_expired(key, *expires_at)),
+      _                                  => Ok(false)
+    }
   }
 }
 
 impl<'a> Iterator for BPlusTreeIterator<'a>
 {
-    type Item = (u32, u32);
+    type Item = BPlusResult<(u32, u32)>;
 
     fn next(&mut self) -> Option<Self::Item> {
-      while self.idx >= self.page.count
+      // Expired entries are skipped over (see `put_expiring`)
+      loop
       {
-        if self.page.next == NULL_IDX
+        if let Bound::Excluded(0) = self.end { return None }
+        while self.idx >= self.page.count
+        {
+          if self.page.next == NULL_IDX
+          {
+            return None
+          }
+          else {
+            let next_page = self.page.next;
+            match self.tree.get_page(next_page)
+            {
+              Ok(page) => self.page = page,
+              Err(err) =>
+              {
+                // Don't keep reading past a page that can't be read
+                self.end = Bound::Excluded(0);
+                return Some(Err(err))
+              }
+            }
+            self.idx = 0
+          }
+        }
+        let ret = self.page.get(self.idx);
+        let in_range = match self.end
         {
+          Bound::Included(k) => ret.0 <= k,
+          Bound::Excluded(k) => ret.0 < k,
+          Bound::Unbounded   => true,
+        };
+        if !in_range
+        {
+          // Don't bother reading any further pages
+          self.end = Bound::Excluded(0);
           return None
         }
-        else {
-          let next_page = self.page.next;
-          self.page = 
-            self.tree.get_page(next_page)
-                     .expect(format!("Couldn't read next page {}", next_page).as_str());
-          self.idx = 0
+        self.idx += 1;
+        match self.is_expired(ret.0)
+        {
+          Ok(true)  => continue,
+          Ok(false) => return Some(Ok(ret)),
+          Err(err)  =>
+          {
+            self.end = Bound::Excluded(0);
+            return Some(Err(err))
+          }
         }
       }
-      let ret = self.page.get(self.idx);
-      self.idx += 1;
-      return Some(ret);
     }
 }
\ No newline at end of file
@@ -0,0 +1,216 @@
+use super::error::{ BPlusError, BPlusResult };
+use super::page::{ Page, PagePointer, LogEntry, LogPage, LOG_CAPACITY };
+use super::storage::Storage;
+
+/// A change made to a tree, as recorded in its change log (see
+/// `BPlusTree::changes_since`)
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Mutation
+{
+  /// The key was inserted or updated
+  Put { key: u32, value: u32 },
+  /// The key was deleted
+  Delete { key: u32 },
+  /// Every key in [low, high] was deleted
+  DeleteRange { low: u32, high: u32 },
+  /// Every key was deleted
+  Clear,
+}
+
+/// A change in the change log, with the time it was made (see
+/// `BPlusTree::timed_changes_since`)
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct LoggedChange
+{
+  /// The change's sequence number
+  pub seq: u64,
+  /// When the change was made (see Clock)
+  pub time: u32,
+  pub mutation: Mutation,
+}
+
+const OP_PUT: u32 = 0;
+const OP_DELETE: u32 = 1;
+const OP_DELETE_RANGE: u32 = 2;
+const OP_CLEAR: u32 = 3;
+
+impl Mutation
+{
+  fn encode(&self, time: u32) -> LogEntry
+  {
+    let (op, key, value) = 
+      match *self
+      {
+        Mutation::Put { key, value }        => (OP_PUT, key, value),
+        Mutation::Delete { key }            => (OP_DELETE, key, 0),
+        Mutation::DeleteRange { low, high } => (OP_DELETE_RANGE, low, high),
+        Mutation::Clear                     => (OP_CLEAR, 0, 0),
+      };
+    LogEntry { op, key, value, time }
+  }
+
+  fn decode(entry: &LogEntry) -> Option<Mutation>
+  {
+    match entry.op
+    {
+      OP_PUT          => Some(Mutation::Put { key: entry.key, value: entry.value }),
+      OP_DELETE       => Some(Mutation::Delete { key: entry.key }),
+      OP_DELETE_RANGE => Some(Mutation::DeleteRange { low: entry.key, high: entry.value }),
+      OP_CLEAR        => Some(Mutation::Clear),
+      _               => None
+    }
+  }
+}
+
+/// An append-only log of the changes made to a tree, each with a
+/// sequence number one greater than the last
+///
+/// The log is kept in storage of its own, as a series of LogPages.
+/// The tree's metadata page records the sequence number of its
+/// last change, and the log is synced before the tree commits, so
+/// after a crash the log holds every change the tree kept.  Any
+/// entries past the tree's last change were never committed, and
+/// are dropped when the log is opened.
+///
+/// Every page but the tail is full, and the page after the tail 
+/// (if any) is empty, so the tail can also be found by reading 
+/// forward from the first page.
+///
+/// The log only grows: nothing is ever dropped from its front, so
+/// it takes another page every LOG_CAPACITY changes for as long as
+/// the tree keeps it.  To reclaim the space once no consumer needs
+/// the old changes, give the tree a new, empty log with 
+/// `BPlusTree::set_change_log`, which picks up just after the 
+/// tree's last change.
+#[derive(Debug)]
+pub struct ChangeLog
+{
+  storage: Box<dyn Storage>,
+  /// The sequence number of the first entry in the log
+  first_seq: u64,
+  /// The last page of the log, and its index
+  tail: LogPage,
+  tail_ptr: PagePointer,
+  /// True if changes have been written since the last sync
+  unsynced: bool,
+}
+
+impl ChangeLog
+{
+  /// Open the log in the provided storage, for a tree whose last
+  /// change has sequence number `last_seq`.  Empty storage becomes
+  /// a new log, starting just after `last_seq`.
+  pub fn open(mut storage: Box<dyn Storage>, last_seq: u64) -> BPlusResult<ChangeLog>
+  {
+    if storage.is_empty()
+    {
+      let tail = LogPage::init(last_seq + 1);
+      tail.write(storage.as_mut(), 0)?;
+      return Ok(ChangeLog { storage, first_seq: last_seq + 1, tail, tail_ptr: 0, unsynced: true })
+    }
+
+    let head = LogPage::read(storage.as_mut(), 0)?;
+    head.check(0)?;
+    let first_seq = head.first_seq;
+    if last_seq + 1 < first_seq
+    {
+      return Err(BPlusError::corrupt(0, format!("Log starts at change {}, after the tree's last change ({})", first_seq, last_seq)))
+    }
+    let kept = last_seq + 1 - first_seq;
+    let tail_ptr = kept.saturating_sub(1) / LOG_CAPACITY as u64;
+    let mut tail = LogPage::read(storage.as_mut(), tail_ptr)?;
+    tail.check(tail_ptr)?;
+    let count = kept - tail_ptr * LOG_CAPACITY as u64;
+    if tail.first_seq != first_seq + tail_ptr * LOG_CAPACITY as u64 || count > tail.count as u64
+    {
+      return Err(BPlusError::corrupt(tail_ptr, format!("Log is missing changes up to {}", last_seq)))
+    }
+    if count < tail.count as u64
+    {
+      tail.count = count as u32;
+      tail.write(storage.as_mut(), tail_ptr)?;
+      // The dropped changes may have started another page
+      if tail_ptr + 1 < storage.len()
+      {
+        LogPage::init(last_seq + 1).write(storage.as_mut(), tail_ptr + 1)?;
+      }
+    }
+    Ok(ChangeLog { storage, first_seq, tail, tail_ptr, unsynced: true })
+  }
+
+  /// The sequence number of the first change in the log
+  pub fn first_seq(&self) -> u64
+  {
+    self.first_seq
+  }
+
+  /// The sequence number that the next change will get
+  pub fn next_seq(&self) -> u64
+  {
+    self.tail.first_seq + self.tail.count as u64
+  }
+
+  /// Add a change made at `time` to the end of the log, and return
+  /// its sequence number.  The change is written, but not synced.
+  pub fn append(&mut self, mutation: Mutation, time: u32) -> BPlusResult<u64>
+  {
+    let seq = self.next_seq();
+    if self.tail.is_full()
+    {
+      self.tail_ptr += 1;
+      self.tail = LogPage::init(seq);
+    }
+    self.tail.entries[self.tail.count as usize] = mutation.encode(time);
+    self.tail.count += 1;
+    self.tail.write(self.storage.as_mut(), self.tail_ptr)?;
+    self.unsynced = true;
+    Ok(seq)
+  }
+
+  /// Up to `limit` changes, starting from sequence number `from`
+  pub fn read(&mut self, from: u64, limit: usize) -> BPlusResult<Vec<LoggedChange>>
+  {
+    let mut ret = Vec::new();
+    let mut seq = from.max(self.first_seq);
+    while ret.len() < limit && seq < self.next_seq()
+    {
+      let ptr = (seq - self.first_seq) / LOG_CAPACITY as u64;
+      let page =
+        if ptr == self.tail_ptr { self.tail.clone() }
+        else
+        {
+          let page = LogPage::read(self.storage.as_mut(), ptr)?;
+          page.check(ptr)?;
+          // Every page but the tail is full
+          if page.first_seq != self.first_seq + ptr * LOG_CAPACITY as u64 || !page.is_full()
+          {
+            return Err(BPlusError::corrupt(ptr, "Log page is out of sequence"))
+          }
+          page
+        };
+      for idx in (seq - page.first_seq) as usize .. page.count as usize
+      {
+        if ret.len() >= limit { break }
+        let entry = &page.entries[idx];
+        let Some(mutation) = Mutation::decode(entry) else
+        {
+          return Err(BPlusError::corrupt(ptr, format!("Unknown change type {}", entry.op)))
+        };
+        ret.push(LoggedChange { seq: page.first_seq + idx as u64, time: entry.time, mutation });
+      }
+      seq = page.first_seq + page.count as u64;
+    }
+    Ok(ret)
+  }
+
+  /// Make every change appended so far durable
+  pub fn sync(&mut self) -> BPlusResult<()>
+  {
+    if self.unsynced
+    {
+      self.storage.sync()?;
+      self.unsynced = false;
+    }
+    Ok(())
+  }
+}
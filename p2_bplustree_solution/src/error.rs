@@ -0,0 +1,122 @@
+use std::error::Error;
+use std::fmt;
+use std::io;
+
+use super::page::{ PagePointer, JOURNAL_CAPACITY };
+
+pub type BPlusResult<T> = Result<T, BPlusError>;
+
+/// Everything that can go wrong in a BPlusTree or its storage
+///
+/// Problems with the on-disk data (torn or tampered pages, a
+/// pointer to the wrong kind of page, ...) are reported as errors
+/// rather than panics, so that a bad file can't bring down the
+/// process.
+#[derive(Debug)]
+pub enum BPlusError
+{
+  /// The underlying storage failed
+  Io(io::Error),
+  /// A page holds something that can't be right.  `page` is None
+  /// if the problem was found in a page that was already in memory.
+  Corruption { page: Option<PagePointer>, reason: String },
+  /// A page was read as one type (e.g., DIR_PAGE_T), but holds
+  /// another
+  PageTypeMismatch { page: PagePointer, expected: u8, found: u8 },
+  /// A page pointer past the end of the storage
+  PageOutOfBounds { page: PagePointer, len: PagePointer },
+  /// A page has no room for another key
+  PageFull,
+  /// `insert_new` found the key already present
+  KeyExists
+  {
+    key: u32,
+    /// The value already stored under the key
+    value: u32,
+  },
+  /// `append` was given a tree whose keys don't all come after
+  /// this tree's keys
+  Overlap
+  {
+    /// The greatest key in this tree
+    last: u32,
+    /// The smallest key in the appended tree
+    first: u32,
+  },
+  /// An encrypted tree was opened with the wrong key (or without
+  /// a key at all)
+  WrongKey,
+  /// The storage can't hold this tree (e.g., its pages are the
+  /// wrong size)
+  IncompatibleStorage(String),
+  /// A batch of writes has more pages than the journal can hold
+  JournalFull { pages: usize },
+  /// A follower was given a leader's commit other than the one
+  /// after the last it applied
+  OutOfOrder { expected: u64, found: u64 },
+  /// The operation needs something the tree wasn't set up with
+  /// (e.g., a sync policy that needs a journal)
+  Unsupported(String),
+}
+
+impl BPlusError
+{
+  /// A Corruption error in the page at `page`
+  pub fn corrupt(page: PagePointer, reason: impl Into<String>) -> BPlusError
+  {
+    BPlusError::Corruption { page: Some(page), reason: reason.into() }
+  }
+
+  /// A Corruption error in a page that was already in memory
+  pub fn corrupt_page(reason: impl Into<String>) -> BPlusError
+  {
+    BPlusError::Corruption { page: None, reason: reason.into() }
+  }
+}
+
+impl fmt::Display for BPlusError
+{
+  fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+    match self
+    {
+      BPlusError::Io(err) => write!(f, "I/O error: {}", err),
+      BPlusError::Corruption { page: Some(page), reason } => write!(f, "Page {} is corrupt: {}", page, reason),
+      BPlusError::Corruption { page: None, reason } => write!(f, "Corrupt page: {}", reason),
+      BPlusError::PageTypeMismatch { page, expected, found } =>
+        write!(f, "Page {} has type {}, but type {} was expected", page, found, expected),
+      BPlusError::PageOutOfBounds { page, len } =>
+        write!(f, "Page {} is past the end of the storage ({} pages)", page, len),
+      BPlusError::PageFull => write!(f, "Page is full!"),
+      BPlusError::KeyExists { key, value } => write!(f, "Key {} already exists (with value {})", key, value),
+      BPlusError::Overlap { last, first } =>
+        write!(f, "Can't append keys from {} on after keys up to {}", first, last),
+      BPlusError::WrongKey => write!(f, "Wrong key for encrypted tree"),
+      BPlusError::IncompatibleStorage(reason) => write!(f, "Incompatible storage: {}", reason),
+      BPlusError::JournalFull { pages } =>
+        write!(f, "Can't journal {} pages; at most {} fit", pages, JOURNAL_CAPACITY),
+      BPlusError::OutOfOrder { expected, found } =>
+        write!(f, "Expected commit {} from the leader, but got commit {}", expected, found),
+      BPlusError::Unsupported(reason) => write!(f, "{}", reason),
+    }
+  }
+}
+
+impl Error for BPlusError
+{
+  fn source(&self) -> Option<&(dyn Error + 'static)>
+  {
+    match self
+    {
+      BPlusError::Io(err) => Some(err),
+      _ => None
+    }
+  }
+}
+
+impl From<io::Error> for BPlusError
+{
+  fn from(err: io::Error) -> BPlusError
+  {
+    BPlusError::Io(err)
+  }
+}
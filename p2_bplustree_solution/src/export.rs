@@ -0,0 +1,292 @@
+use std::io::Write;
+use std::ops::{ Bound, RangeBounds };
+
+use super::bplus_tree::BPlusTree;
+use super::error::BPlusResult;
+use super::page::{ PagePointer, NULL_IDX };
+use super::page::{ LeafPage, DirectoryPage };
+
+/// Limits on how much of the tree gets exported
+///
+/// By default, the entire tree is exported.
+#[derive(Debug, Clone)]
+pub struct ExportOptions
+{
+  /// Export at most this many levels of pages below the root.
+  /// Subtrees below the cut off are shown as a single truncated
+  /// node.  `Some(0)` exports only the root.
+  pub max_depth: Option<u16>,
+
+  /// Only export subtrees that could contain keys in this range
+  pub low: Bound<u32>,
+  pub high: Bound<u32>,
+}
+
+impl Default for ExportOptions
+{
+  fn default() -> ExportOptions
+  {
+    ExportOptions { max_depth: None, low: Bound::Unbounded, high: Bound::Unbounded }
+  }
+}
+
+impl ExportOptions
+{
+  /// Export only the subtrees overlapping the provided key range
+  pub fn with_keys<R: RangeBounds<u32>>(mut self, range: R) -> ExportOptions
+  {
+    self.low = range.start_bound().cloned();
+    self.high = range.end_bound().cloned();
+    self
+  }
+
+  /// Export at most `depth` levels of pages below the root
+  pub fn with_max_depth(mut self, depth: u16) -> ExportOptions
+  {
+    self.max_depth = Some(depth);
+    self
+  }
+
+  /// Return true if some key in [low, high) is also in the
+  /// requested range.  `high` is None if the subtree is unbounded.
+  fn overlaps(&self, low: u32, high: Option<u32>) -> bool
+  {
+    let below_high = match self.high
+    {
+      Bound::Included(k) => low <= k,
+      Bound::Excluded(k) => low < k,
+      Bound::Unbounded   => true,
+    };
+    let above_low = match (self.low, high)
+    {
+      (_, None)                     => true,
+      (Bound::Included(k), Some(h)) => k < h,
+      (Bound::Excluded(k), Some(h)) => k.saturating_add(1) < h,
+      (Bound::Unbounded, _)         => true,
+    };
+    below_high && above_low
+  }
+}
+
+/// A summary of one page in the tree
+#[derive(Debug)]
+enum Node
+{
+  Directory
+  {
+    ptr: PagePointer,
+    keys: Vec<u32>,
+    /// One entry per pointer on the page; None if the subtree
+    /// was filtered out by the key range
+    children: Vec<Option<Node>>,
+  },
+  Leaf
+  {
+    ptr: PagePointer,
+    count: usize,
+    /// The least and greatest keys on the page, if any
+    keys: Option<(u32, u32)>,
+    prev: PagePointer,
+    next: PagePointer,
+  },
+  /// A subtree below the depth cut off
+  Truncated { ptr: PagePointer },
+}
+
+fn walk(tree: &mut BPlusTree, opts: &ExportOptions, ptr: PagePointer, depth: u16)
+  -> BPlusResult<Node>
+{
+  if opts.max_depth.is_some_and(|max| depth > max)
+  {
+    return Ok(Node::Truncated { ptr })
+  }
+  if depth >= tree.depth()
+  {
+    let page = tree.get_page::<LeafPage>(ptr)?;
+    let keys =
+      if page.count == 0 { None }
+      else               { Some((page.get(0).0, page.get(page.count-1).0)) };
+    return Ok(Node::Leaf { ptr, count: page.count, keys, prev: page.prev, next: page.next })
+  }
+
+  let page = tree.get_page::<DirectoryPage>(ptr)?;
+  let keys = page.keys[0 .. page.count].to_vec();
+  let mut children = Vec::new();
+  for (i, child) in page.pointers[0 .. page.count+1].iter().enumerate()
+  {
+    let low = if i > 0 { keys[i-1] } else { 0 };
+    let high = keys.get(i).cloned();
+    if opts.overlaps(low, high)
+    {
+      children.push(Some(walk(tree, opts, *child, depth+1)?));
+    }
+    else
+    {
+      children.push(None);
+    }
+  }
+  Ok(Node::Directory { ptr, keys, children })
+}
+
+fn collect_leaves<'a>(node: &'a Node, leaves: &mut Vec<&'a Node>)
+{
+  match node
+  {
+    Node::Directory { children, .. } =>
+      for child in children.iter().flatten() { collect_leaves(child, leaves) },
+    Node::Leaf { .. } => leaves.push(node),
+    Node::Truncated { .. } => (),
+  }
+}
+
+fn write_dot_node(node: &Node, out: &mut dyn Write) -> BPlusResult<()>
+{
+  match node
+  {
+    Node::Directory { ptr, keys, children } =>
+    {
+      let mut label = format!("<p0> #{}", ptr);
+      for (i, key) in keys.iter().enumerate()
+      {
+        label.push_str(&format!(" | {} | <p{}>", key, i+1));
+      }
+      writeln!(out, "  page{} [shape=record, label=\"{}\"];", ptr, label)?;
+      for (i, child) in children.iter().enumerate()
+      {
+        if let Some(child) = child
+        {
+          write_dot_node(child, out)?;
+          writeln!(out, "  page{}:p{} -> page{};", ptr, i, child_ptr(child))?;
+        }
+      }
+    }
+    Node::Leaf { ptr, count, keys, .. } =>
+    {
+      let range = match keys
+      {
+        Some((low, high)) => format!("[{}, {}]", low, high),
+        None              => "empty".to_string(),
+      };
+      writeln!(out, "  page{} [shape=box, label=\"#{}\\n{}\\n{} records\"];", ptr, ptr, range, count)?;
+    }
+    Node::Truncated { ptr } =>
+    {
+      writeln!(out, "  page{} [shape=box, style=dashed, label=\"#{}\\n...\"];", ptr, ptr)?;
+    }
+  }
+  Ok(())
+}
+
+fn child_ptr(node: &Node) -> PagePointer
+{
+  match node
+  {
+    Node::Directory { ptr, .. } | Node::Leaf { ptr, .. } | Node::Truncated { ptr } => *ptr
+  }
+}
+
+/// Export the structure of the tree in Graphviz DOT format
+///
+/// Directory pages are drawn as records with one port per child
+/// pointer between the separator keys.  Leaf pages show their key
+/// range and record count, and are linked by dashed `next` edges.
+pub fn to_dot(tree: &mut BPlusTree, opts: &ExportOptions, out: &mut dyn Write) -> BPlusResult<()>
+{
+  let root = walk(tree, opts, tree.root_page(), 0)?;
+
+  writeln!(out, "digraph bplus_tree {{")?;
+  writeln!(out, "  node [fontname=\"monospace\"];")?;
+  write_dot_node(&root, out)?;
+
+  let mut leaves = Vec::new();
+  collect_leaves(&root, &mut leaves);
+  let exported: Vec<PagePointer> = leaves.iter().map(|leaf| child_ptr(leaf)).collect();
+  for leaf in leaves
+  {
+    if let Node::Leaf { ptr, next, prev, .. } = leaf
+    {
+      if *next != NULL_IDX && exported.contains(next)
+      {
+        writeln!(out, "  page{} -> page{} [style=dashed, constraint=false];", ptr, next)?;
+      }
+      if *prev != NULL_IDX && exported.contains(prev)
+      {
+        writeln!(out, "  page{} -> page{} [style=dotted, constraint=false];", ptr, prev)?;
+      }
+    }
+  }
+  // Keep all of the leaves on the same row
+  if exported.len() > 1
+  {
+    let names: Vec<String> = exported.iter().map(|ptr| format!("page{}", ptr)).collect();
+    writeln!(out, "  {{ rank=same; {}; }}", names.join("; "))?;
+  }
+  writeln!(out, "}}")?;
+  Ok(())
+}
+
+fn json_ptr(ptr: PagePointer) -> String
+{
+  if ptr == NULL_IDX { "null".to_string() } else { ptr.to_string() }
+}
+
+fn write_json_node(node: &Node, out: &mut dyn Write) -> BPlusResult<()>
+{
+  match node
+  {
+    Node::Directory { ptr, keys, children } =>
+    {
+      let keys: Vec<String> = keys.iter().map(|k| k.to_string()).collect();
+      write!(out, "{{\"page\":{},\"type\":\"directory\",\"keys\":[{}],\"children\":[", ptr, keys.join(","))?;
+      for (i, child) in children.iter().enumerate()
+      {
+        if i > 0 { write!(out, ",")?; }
+        match child
+        {
+          Some(child) => write_json_node(child, out)?,
+          None        => write!(out, "null")?,
+        }
+      }
+      write!(out, "]}}")?;
+    }
+    Node::Leaf { ptr, count, keys, prev, next } =>
+    {
+      let (low, high) = match keys
+      {
+        Some((low, high)) => (low.to_string(), high.to_string()),
+        None              => ("null".to_string(), "null".to_string()),
+      };
+      write!(out,
+        "{{\"page\":{},\"type\":\"leaf\",\"count\":{},\"min_key\":{},\"max_key\":{},\"prev\":{},\"next\":{}}}",
+        ptr, count, low, high, json_ptr(*prev), json_ptr(*next)
+      )?;
+    }
+    Node::Truncated { ptr } =>
+    {
+      write!(out, "{{\"page\":{},\"type\":\"truncated\"}}", ptr)?;
+    }
+  }
+  Ok(())
+}
+
+/// Export the structure of the tree as a JSON document
+///
+/// ```
+/// { "depth": 1, "root": 1, "data_head": 2, "data_tail": 2,
+///   "tree": { "page": 1, "type": "directory", "keys": [],
+///             "children": [ { "page": 2, "type": "leaf", ... } ] } }
+/// ```
+/// Children filtered out by the key range are `null`.
+pub fn to_json(tree: &mut BPlusTree, opts: &ExportOptions, out: &mut dyn Write) -> BPlusResult<()>
+{
+  let root = walk(tree, opts, tree.root_page(), 0)?;
+  let (head, tail) = tree.data_bounds();
+
+  write!(out,
+    "{{\"depth\":{},\"root\":{},\"data_head\":{},\"data_tail\":{},\"tree\":",
+    tree.depth(), tree.root_page(), head, tail
+  )?;
+  write_json_node(&root, out)?;
+  writeln!(out, "}}")?;
+  Ok(())
+}
@@ -0,0 +1,84 @@
+use super::error::BPlusResult;
+use super::page::{ Page, PagePointer, GenerationPage, GENERATION_CAPACITY };
+use super::storage::Storage;
+
+/// The generation in which each page of a tree was last written
+/// (see `BPlusTree::backup_incremental`)
+///
+/// The map is kept in storage of its own, as a flat array of
+/// GenerationPages, and in memory.  An entry only changes the
+/// first time its page is written in a generation, so the map
+/// page holding it is written right away, and the map is synced
+/// before the tree commits.  A page's entry is therefore never
+/// older than the changes on the page.
+#[derive(Debug)]
+pub struct GenerationMap
+{
+  storage: Box<dyn Storage>,
+  generations: Vec<u32>,
+}
+
+impl GenerationMap
+{
+  /// Open the map in the provided storage, for a tree with `pages`
+  /// pages in generation `generation`.  Pages the map doesn't
+  /// cover yet (e.g., every page, if the storage is empty) are
+  /// taken to have been written in `generation`.
+  pub fn open(mut storage: Box<dyn Storage>, generation: u32, pages: PagePointer)
+    -> BPlusResult<GenerationMap>
+  {
+    let mut generations = Vec::new();
+    for ptr in 0 .. storage.len()
+    {
+      let page = GenerationPage::read(storage.as_mut(), ptr)?;
+      page.check(ptr)?;
+      generations.extend_from_slice(&page.generations);
+    }
+    let mut map = GenerationMap { storage, generations };
+    let covered = map.generations.len() as PagePointer;
+    if covered < pages
+    {
+      map.generations.resize(pages as usize, generation);
+      for idx in covered / GENERATION_CAPACITY as PagePointer ..= (pages - 1) / GENERATION_CAPACITY as PagePointer
+      {
+        map.write_page(idx)?;
+      }
+    }
+    Ok(map)
+  }
+
+  /// The generation in which the page was last written
+  pub fn generation(&self, ptr: PagePointer) -> u32
+  {
+    self.generations.get(ptr as usize).copied().unwrap_or(0)
+  }
+
+  /// Record that the page is being written in `generation`
+  pub fn stamp(&mut self, ptr: PagePointer, generation: u32) -> BPlusResult<()>
+  {
+    let idx = ptr as usize;
+    if idx >= self.generations.len()
+    {
+      self.generations.resize(idx + 1, generation);
+    }
+    else if self.generations[idx] == generation { return Ok(()) }
+    self.generations[idx] = generation;
+    self.write_page(ptr / GENERATION_CAPACITY as PagePointer)
+  }
+
+  /// Write out the map page at the provided index
+  fn write_page(&mut self, idx: PagePointer) -> BPlusResult<()>
+  {
+    let start = idx as usize * GENERATION_CAPACITY;
+    let end = self.generations.len().min(start + GENERATION_CAPACITY);
+    let mut page = GenerationPage::init();
+    page.generations[.. end - start].copy_from_slice(&self.generations[start .. end]);
+    page.write(self.storage.as_mut(), idx)
+  }
+
+  /// Make every entry written so far durable
+  pub fn sync(&mut self) -> BPlusResult<()>
+  {
+    self.storage.sync()
+  }
+}
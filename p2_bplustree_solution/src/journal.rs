@@ -0,0 +1,144 @@
+use std::collections::BTreeMap;
+
+use super::error::{ BPlusError, BPlusResult };
+use super::page::{ Page, PagePointer, PageBuffer, JournalPage, PAGE_SIZE, JOURNAL_CAPACITY, METADATA_IDX };
+use super::storage::Storage;
+
+/// Page writes that have not yet been applied to the tree's storage
+pub type PendingPages = BTreeMap<PagePointer, PageBuffer>;
+
+/// A redo journal that makes a batch of page writes atomic
+///
+/// A batch is committed in three steps:
+/// 1. The page images and a checksummed header are written to the
+///    journal, which is then synced.  Once the header is durable,
+///    the batch is committed.
+/// 2. The images are written to the tree's storage (the metadata
+///    page last), which is then synced.
+/// 3. The journal header is cleared.
+///
+/// If the process dies during step 2, `recover` finds the committed
+/// batch in the journal and writes it to the tree's storage again.
+/// If it dies during step 1, the checksum won't match, and the
+/// batch is ignored.
+#[derive(Debug)]
+pub struct Journal
+{
+  storage: Box<dyn Storage>,
+}
+
+/// FNV-1a over the destination pointers and page images of a batch
+pub fn checksum<'a>(pages: impl Iterator<Item = (PagePointer, &'a PageBuffer)>) -> u64
+{
+  let mut hash: u64 = 0xcbf29ce484222325;
+  let mut add = |bytes: &[u8]| {
+    for b in bytes
+    {
+      hash ^= *b as u64;
+      hash = hash.wrapping_mul(0x100000001b3);
+    }
+  };
+  for (ptr, image) in pages
+  {
+    add(&ptr.to_le_bytes());
+    add(&image.0);
+  }
+  hash
+}
+
+/// Write pages to storage, the metadata page last
+pub fn apply<'a>(target: &mut dyn Storage, pages: impl Iterator<Item = (PagePointer, &'a PageBuffer)>)
+  -> BPlusResult<()>
+{
+  let mut meta: Option<&PageBuffer> = None;
+  for (ptr, image) in pages
+  {
+    if ptr == METADATA_IDX { meta = Some(image); }
+    else                   { target.write_page(ptr, &image.0)?; }
+  }
+  if let Some(image) = meta
+  {
+    target.write_page(METADATA_IDX, &image.0)?;
+  }
+  target.sync()
+}
+
+impl Journal
+{
+  /// Use the provided storage as a journal, discarding anything
+  /// in it.
+  pub fn init(mut storage: Box<dyn Storage>) -> BPlusResult<Journal>
+  {
+    JournalPage::init().write(storage.as_mut(), 0)?;
+    Ok(Journal { storage })
+  }
+
+  /// Use the provided storage as a journal.  Call `recover` before
+  /// reading from the tree's storage.
+  pub fn open(storage: Box<dyn Storage>) -> Journal
+  {
+    Journal { storage }
+  }
+
+  /// Atomically and durably write a batch of pages to `target`
+  pub fn commit(&mut self, pages: &PendingPages, target: &mut dyn Storage) -> BPlusResult<()>
+  {
+    if pages.is_empty() { return Ok(()) }
+    if pages.len() > JOURNAL_CAPACITY
+    {
+      return Err(BPlusError::JournalFull { pages: pages.len() })
+    }
+
+    // Step 1: Log the batch
+    let mut header = JournalPage::init();
+    for (i, (ptr, image)) in pages.iter().enumerate()
+    {
+      self.storage.write_page(i as PagePointer + 1, &image.0)?;
+      header.pointers[i] = *ptr;
+    }
+    header.count = pages.len();
+    header.checksum = checksum(pages.iter().map(|(ptr, image)| (*ptr, image)));
+    header.write(self.storage.as_mut(), 0)?;
+    self.storage.sync()?;
+
+    // Step 2: Apply the batch
+    apply(target, pages.iter().map(|(ptr, image)| (*ptr, image)))?;
+
+    // Step 3: Retire the batch
+    JournalPage::init().write(self.storage.as_mut(), 0)
+  }
+
+  /// Re-apply a committed batch left over from a crash, if any.
+  ///
+  /// Returns the number of pages recovered.
+  pub fn recover(&mut self, target: &mut dyn Storage) -> BPlusResult<usize>
+  {
+    if self.storage.is_empty() { return Ok(0) }
+
+    let header = JournalPage::read(self.storage.as_mut(), 0)?;
+    if header.page_type() != JournalPage::EXPECTED_PAGE_TYPE || header.count == 0
+       || header.count > JOURNAL_CAPACITY
+    {
+      return Ok(0)
+    }
+
+    let mut images = Vec::with_capacity(header.count);
+    for i in 0 .. header.count
+    {
+      let mut image = PageBuffer([0; PAGE_SIZE]);
+      if self.storage.read_page(i as PagePointer + 1, &mut image.0).is_err() { return Ok(0) }
+      images.push(image);
+    }
+    let batch = || header.pointers.iter().cloned().zip(images.iter());
+    if checksum(batch()) != header.checksum
+    {
+      // The batch was never committed
+      return Ok(0)
+    }
+
+    apply(target, batch())?;
+    JournalPage::init().write(self.storage.as_mut(), 0)?;
+    self.storage.sync()?;
+    Ok(header.count)
+  }
+}
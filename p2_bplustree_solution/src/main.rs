@@ -1,14 +1,90 @@
+mod backup;
 mod bplus_tree;
+mod changelog;
+mod error;
+mod export;
+mod generations;
+mod journal;
+mod merge;
 mod page;
+mod repl;
+mod replication;
+mod storage;
+mod ttl;
 #[cfg(test)] mod test;
 
 use std::error::Error;
+use std::path::Path;
 use std::result::Result;
-use bplus_tree::BPlusTree;
+use std::time::Duration;
+use bplus_tree::{ BPlusTree, SyncPolicy };
+use repl::Repl;
+
+const USAGE: &str = "\
+Usage: bplus_tree [--init] [--check] [--sync=POLICY] <path> [command ...]
+
+Opens the tree at <path> (creating it if it doesn't exist, or if
+--init is given).  If a command is given (e.g., `put 10 20`), it is
+run and the program exits.  Otherwise, an interactive shell is
+started; type `help` for a list of commands.
+
+  --init   Re-initialize the tree, discarding its contents
+  --check  Sanity check the tree after every put/del
+  --sync=POLICY
+           When to make writes durable: always, never (the default),
+           ops=N (every N operations), or ms=N (every N milliseconds)";
+
+fn parse_policy(policy: &str) -> Result<SyncPolicy, Box<dyn Error>>
+{
+  if policy == "always" { return Ok(SyncPolicy::Always) }
+  if policy == "never"  { return Ok(SyncPolicy::Never) }
+  if let Some(n) = policy.strip_prefix("ops=") { return Ok(SyncPolicy::EveryOps(n.parse()?)) }
+  if let Some(n) = policy.strip_prefix("ms=")  { return Ok(SyncPolicy::Interval(Duration::from_millis(n.parse()?))) }
+  Err(format!("Unknown sync policy: {}\n\n{}", policy, USAGE).into())
+}
 
 fn main() -> Result<(), Box<dyn Error>>
 {
-  BPlusTree::init(&"test.btree".to_string())?;
+  let mut init = false;
+  let mut auto_check = false;
+  let mut policy = SyncPolicy::Never;
+  let mut path: Option<String> = None;
+  let mut command: Vec<String> = Vec::new();
+
+  for arg in std::env::args().skip(1)
+  {
+    if path.is_some()               { command.push(arg); }
+    else if arg == "--init"         { init = true; }
+    else if arg == "--check"        { auto_check = true; }
+    else if arg == "--help"         { println!("{}", USAGE); return Ok(()) }
+    else if let Some(p) = arg.strip_prefix("--sync=") { policy = parse_policy(p)?; }
+    else if arg.starts_with("--")   { return Err(format!("Unknown option {}\n\n{}", arg, USAGE).into()) }
+    else                            { path = Some(arg); }
+  }
+
+  let path = match path
+  {
+    Some(path) => path,
+    None => { eprintln!("{}", USAGE); return Ok(()) }
+  };
+
+  let mut tree =
+    if init || !Path::new(&path).exists() { BPlusTree::init(&path)? }
+    else                                  { BPlusTree::open(&path)? };
+
+  tree.set_sync_policy(policy)?;
+
+  let mut repl = Repl::new(&mut tree);
+  repl.auto_check = auto_check;
+  let mut stdout = std::io::stdout();
 
+  if command.is_empty()
+  {
+    repl.run(&mut std::io::stdin().lock(), &mut stdout)?;
+  }
+  else
+  {
+    repl.exec(&command.join(" "), &mut stdout)?;
+  }
   Ok(())
 }
@@ -0,0 +1,224 @@
+use std::cmp::Ordering;
+
+use super::bplus_tree::BPlusTreeIterator;
+use super::error::BPlusResult;
+
+/// A difference between two trees, as reported by 
+/// `BPlusTreeIterator::diff`
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Change
+{
+  /// The key is only in the new tree
+  Added { key: u32, value: u32 },
+  /// The key is only in the old tree
+  Removed { key: u32, value: u32 },
+  /// The key is in both trees, with different values
+  Changed { key: u32, old: u32, new: u32 },
+}
+
+/// How a key lines up between the two sides of a merge
+#[derive(Debug, Clone, Copy)]
+enum Step
+{
+  Left(u32, u32),
+  Right(u32, u32),
+  Both(u32, u32, u32),
+}
+
+/// One side of a merge, with its next record read ahead
+#[derive(Debug)]
+struct Side<'a>
+{
+  iter: BPlusTreeIterator<'a>,
+  head: Option<(u32, u32)>,
+}
+
+impl<'a> Side<'a>
+{
+  fn advance(&mut self) -> BPlusResult<()>
+  {
+    self.head = self.iter.next().transpose()?;
+    Ok(())
+  }
+
+  /// Skip to the first record with a key >= `key`
+  fn seek(&mut self, key: u32) -> BPlusResult<()>
+  {
+    if let Some((k, _)) = self.head
+    {
+      if k >= key { return Ok(()) }
+    }
+    self.iter.seek(key)?;
+    self.advance()
+  }
+}
+
+/// Two record streams, merged by key
+///
+/// Both streams are sorted, so the merge advances them in 
+/// lockstep, reading each leaf once.  Records that are only on a
+/// side that isn't kept (e.g., the right side of `difference`) are
+/// never read: that side seeks straight to the other side's next
+/// key, skipping whole runs of leaves.
+///
+/// After an error, the merge ends.
+#[derive(Debug)]
+pub struct Merge<'a, 'b, T>
+{
+  left: Side<'a>,
+  right: Side<'b>,
+  /// Report keys that are only on the left (resp. right) side
+  keep_left: bool,
+  keep_right: bool,
+  map: fn(Step) -> Option<T>,
+  started: bool,
+  done: bool,
+}
+
+impl<'a, 'b, T> Merge<'a, 'b, T>
+{
+  fn new(left: BPlusTreeIterator<'a>, right: BPlusTreeIterator<'b>, keep_left: bool, keep_right: bool,
+         map: fn(Step) -> Option<T>) -> Merge<'a, 'b, T>
+  {
+    Merge {
+      left: Side { iter: left, head: None },
+      right: Side { iter: right, head: None },
+      keep_left,
+      keep_right,
+      map,
+      started: false,
+      done: false,
+    }
+  }
+
+  /// Line up the next key that either side has, or None once both
+  /// sides are out of (kept) records
+  fn step(&mut self) -> BPlusResult<Option<Step>>
+  {
+    if !self.started
+    {
+      self.started = true;
+      self.left.advance()?;
+      self.right.advance()?;
+    }
+    loop
+    {
+      match (self.left.head, self.right.head)
+      {
+        (Some(l), Some(r)) => match l.0.cmp(&r.0)
+        {
+          Ordering::Less if self.keep_left => 
+          {
+            self.left.advance()?;
+            return Ok(Some(Step::Left(l.0, l.1)))
+          }
+          Ordering::Less => self.left.seek(r.0)?,
+          Ordering::Greater if self.keep_right => 
+          {
+            self.right.advance()?;
+            return Ok(Some(Step::Right(r.0, r.1)))
+          }
+          Ordering::Greater => self.right.seek(l.0)?,
+          Ordering::Equal =>
+          {
+            self.left.advance()?;
+            self.right.advance()?;
+            return Ok(Some(Step::Both(l.0, l.1, r.1)))
+          }
+        },
+        (Some(l), None) if self.keep_left =>
+        {
+          self.left.advance()?;
+          return Ok(Some(Step::Left(l.0, l.1)))
+        }
+        (None, Some(r)) if self.keep_right =>
+        {
+          self.right.advance()?;
+          return Ok(Some(Step::Right(r.0, r.1)))
+        }
+        _ => return Ok(None)
+      }
+    }
+  }
+}
+
+impl<'a, 'b, T> Iterator for Merge<'a, 'b, T>
+{
+  type Item = BPlusResult<T>;
+
+  fn next(&mut self) -> Option<Self::Item>
+  {
+    while !self.done
+    {
+      match self.step()
+      {
+        Ok(Some(step)) => if let Some(ret) = (self.map)(step) { return Some(Ok(ret)) },
+        Ok(None) => self.done = true,
+        Err(err) =>
+        {
+          self.done = true;
+          return Some(Err(err))
+        }
+      }
+    }
+    None
+  }
+}
+
+#[allow(dead_code)]
+impl<'a> BPlusTreeIterator<'a>
+{
+  /// The keys in both this stream and `other`, as (key, this 
+  /// value, other value) triples
+  pub fn merge_join<'b>(self, other: BPlusTreeIterator<'b>) -> Merge<'a, 'b, (u32, u32, u32)>
+  {
+    Merge::new(self, other, false, false, |step| match step
+    {
+      Step::Both(k, l, r) => Some((k, l, r)),
+      _ => None
+    })
+  }
+
+  /// The records of this stream whose keys are also in `other`
+  pub fn intersection<'b>(self, other: BPlusTreeIterator<'b>) -> Merge<'a, 'b, (u32, u32)>
+  {
+    Merge::new(self, other, false, false, |step| match step
+    {
+      Step::Both(k, l, _) => Some((k, l)),
+      _ => None
+    })
+  }
+
+  /// The records of this stream whose keys are not in `other`
+  pub fn difference<'b>(self, other: BPlusTreeIterator<'b>) -> Merge<'a, 'b, (u32, u32)>
+  {
+    Merge::new(self, other, true, false, |step| match step
+    {
+      Step::Left(k, v) => Some((k, v)),
+      _ => None
+    })
+  }
+
+  /// The records of both streams.  Where a key is in both, the 
+  /// value from this stream wins.
+  pub fn union<'b>(self, other: BPlusTreeIterator<'b>) -> Merge<'a, 'b, (u32, u32)>
+  {
+    Merge::new(self, other, true, true, |step| match step
+    {
+      Step::Left(k, v) | Step::Right(k, v) | Step::Both(k, v, _) => Some((k, v)),
+    })
+  }
+
+  /// The changes that turn this (old) stream into `new`.  Keys 
+  /// with the same value in both aren't reported.
+  pub fn diff<'b>(self, new: BPlusTreeIterator<'b>) -> Merge<'a, 'b, Change>
+  {
+    Merge::new(self, new, true, true, |step| match step
+    {
+      Step::Left(key, value)  => Some(Change::Removed { key, value }),
+      Step::Right(key, value) => Some(Change::Added { key, value }),
+      Step::Both(key, old, new) if old != new => Some(Change::Changed { key, old, new }),
+      Step::Both(..) => None,
+    })
+  }
+}
@@ -0,0 +1,115 @@
+//! Byte-level helpers for the compressed page layouts enabled by
+//! the `key-compression` feature.
+//!
+//! With key compression, the second byte of every directory and
+//! leaf page records which of these layouts it is stored in:
+//! - RAW: The same layout as an uncompressed build.  Used for pages
+//!   whose keys are too far apart to store as deltas.
+//! - DELTA16 (leaf pages): Keys are stored as a u32 base key plus a
+//!   u16 delta per key.  Only possible if every key on the page is
+//!   within u16::MAX of the least key.
+//! - DELTA24 (directory pages): As DELTA16, with 3 byte deltas.
+//!   The keys of a directory page span all of the leaves under it,
+//!   so u16 deltas would rarely fit.
+//!
+//! In a build without the feature, that byte is padding, so trees 
+//! record whether they were written with key compression (see 
+//! LAYOUT_KEY_COMPRESSION), and neither build opens the other's 
+//! trees.
+//!
+//! Pages are always decoded into the usual in-memory structs, so
+//! the layout is invisible to everything but encode/decode.
+
+#[cfg(feature = "key-compression")]
+use super::PAGE_SIZE;
+
+/// Layout code: the uncompressed layout
+pub const RAW: u8 = 0;
+/// Layout code: base key plus u16 deltas
+#[allow(dead_code)]
+pub const DELTA16: u8 = 1;
+/// Layout code: base key plus 3 byte deltas
+#[allow(dead_code)]
+pub const DELTA24: u8 = 2;
+
+/// The greatest delta that fits in 3 bytes
+#[allow(dead_code)]
+pub const U24_MAX: u32 = (1 << 24) - 1;
+
+/// Return true if keys in the range [low, high] can be stored as
+/// u16 deltas from low
+pub fn fits(low: u32, high: u32) -> bool
+{
+  high - low <= u16::MAX as u32
+}
+
+/// Return true if keys in the range [low, high] can be stored as
+/// 3 byte deltas from low
+#[allow(dead_code)]
+pub fn fits24(low: u32, high: u32) -> bool
+{
+  high - low <= U24_MAX
+}
+
+/// Sequential reads from a page buffer
+#[cfg(feature = "key-compression")]
+pub struct Reader<'a>
+{
+  buffer: &'a [u8; PAGE_SIZE],
+  pos: usize,
+}
+
+#[cfg(feature = "key-compression")]
+impl<'a> Reader<'a>
+{
+  pub fn new(buffer: &'a [u8; PAGE_SIZE], pos: usize) -> Reader<'a>
+  {
+    Reader { buffer, pos }
+  }
+
+  fn take<const N: usize>(&mut self) -> [u8; N]
+  {
+    let ret = self.buffer[self.pos .. self.pos + N].try_into().unwrap();
+    self.pos += N;
+    ret
+  }
+
+  pub fn u8(&mut self) -> u8     { self.take::<1>()[0] }
+  pub fn u16(&mut self) -> u16   { u16::from_ne_bytes(self.take()) }
+  pub fn u24(&mut self) -> u32   { let [a, b, c] = self.take(); u32::from_le_bytes([a, b, c, 0]) }
+  pub fn u32(&mut self) -> u32   { u32::from_ne_bytes(self.take()) }
+  pub fn u64(&mut self) -> u64   { u64::from_ne_bytes(self.take()) }
+  pub fn usize(&mut self) -> usize { usize::from_ne_bytes(self.take()) }
+  pub fn seek(&mut self, pos: usize) { self.pos = pos; }
+}
+
+/// Sequential writes to a page buffer
+#[cfg(feature = "key-compression")]
+pub struct Writer<'a>
+{
+  buffer: &'a mut [u8; PAGE_SIZE],
+  pos: usize,
+}
+
+#[cfg(feature = "key-compression")]
+impl<'a> Writer<'a>
+{
+  pub fn new(buffer: &'a mut [u8; PAGE_SIZE]) -> Writer<'a>
+  {
+    Writer { buffer, pos: 0 }
+  }
+
+  fn put(&mut self, bytes: &[u8])
+  {
+    self.buffer[self.pos .. self.pos + bytes.len()].copy_from_slice(bytes);
+    self.pos += bytes.len();
+  }
+
+  pub fn u8(&mut self, v: u8)       { self.put(&[v]) }
+  pub fn u16(&mut self, v: u16)     { self.put(&v.to_ne_bytes()) }
+  pub fn u24(&mut self, v: u32)     { self.put(&v.to_le_bytes()[.. 3]) }
+  pub fn u32(&mut self, v: u32)     { self.put(&v.to_ne_bytes()) }
+  pub fn u64(&mut self, v: u64)     { self.put(&v.to_ne_bytes()) }
+  pub fn usize(&mut self, v: usize) { self.put(&v.to_ne_bytes()) }
+  pub fn seek(&mut self, pos: usize) { self.pos = pos; }
+}
@@ -0,0 +1,65 @@
+use super::{ Page, PagePointer, DELTA_PAGE_T, PAGE_SIZE };
+use static_assertions::const_assert;
+use std::mem::size_of;
+
+/// The number of page images that one delta header covers
+pub const DELTA_CAPACITY: usize = 500;
+
+/// The header of one chunk of an incremental backup
+///
+/// A delta is a series of chunks: each header is followed by the
+/// `count` page images it covers, which are destined for pages
+/// `pointers[0 .. count]` of the tree.  Every chunk but the last
+/// is full.  A chunk is only valid if `checksum` matches its 
+/// pointers and images.
+#[repr(C)]
+#[derive(Debug, Clone)]
+pub struct DeltaPage
+{
+  page_type: u8,
+  padding: [u8; 7],
+  pub count: usize,
+  pub checksum: u64,
+  /// The delta holds every page written after generation `base`...
+  pub base: u32,
+  /// ...up to and including generation `generation`
+  pub generation: u32,
+  pub pointers: [PagePointer; DELTA_CAPACITY],
+}
+const_assert!(PAGE_SIZE >= size_of::<DeltaPage>());
+
+impl DeltaPage
+{
+  /// Initialize an empty delta header
+  pub fn init(base: u32, generation: u32) -> DeltaPage
+  {
+    DeltaPage {
+      page_type: DELTA_PAGE_T,
+      padding: [0; 7],
+      count: 0,
+      checksum: 0,
+      base,
+      generation,
+      pointers: [0; DELTA_CAPACITY],
+    }
+  }
+
+  /// True if no more images fit in this chunk
+  pub fn is_full(&self) -> bool
+  {
+    self.count >= DELTA_CAPACITY
+  }
+}
+
+impl Page for DeltaPage
+{
+  const EXPECTED_PAGE_TYPE: u8 = DELTA_PAGE_T;
+
+  fn page_type(&self) -> u8 { self.page_type }
+
+  fn corruption(&self) -> Option<String>
+  {
+    if self.count > DELTA_CAPACITY { Some(format!("Covers {} images, but at most {} fit", self.count, DELTA_CAPACITY)) }
+    else                           { None }
+  }
+}
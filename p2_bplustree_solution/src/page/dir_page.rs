@@ -1,16 +1,32 @@
+use crate::error::{ BPlusError, BPlusResult };
 use crate::page::NULL_IDX;
 
-use super::{ Page, PageIsFullError, PagePointer, DIR_PAGE_T, PAGE_SIZE };
+use super::{ codec, Page, PagePointer, Summary, DIR_PAGE_T, PAGE_SIZE };
 use static_assertions::const_assert;
 use std::mem::size_of;
+use std::ops::Range;
 
 // You may wish to temporarily change the DIR_KEY_COUNT
 // parameter below to something smaller while debugging.
 // to make your life easier.
 
-pub const DIR_KEY_COUNT: usize     = 335;  // Max key/ptr pairs that will fit on one page
+#[cfg(not(feature = "aggregates"))]
+pub const DIR_KEY_COUNT: usize     = 251;  // Max key/ptr/count triples that will fit on one page
+#[cfg(feature = "aggregates")]
+pub const DIR_KEY_COUNT: usize     = 125;  // Max key/ptr/count/summary tuples that will fit on one page
+#[allow(dead_code)]
 pub const DIR_PTR_COUNT: usize     = DIR_KEY_COUNT+1;
 
+/// The most keys that a page can hold.  With key compression,
+/// pages whose keys are close together can hold more than
+/// DIR_KEY_COUNT keys.  DIR_KEY_COUNT still decides when a page 
+/// is underfull.
+#[cfg(not(feature = "key-compression"))]
+pub const DIR_MAX_KEYS: usize      = DIR_KEY_COUNT;
+#[cfg(feature = "key-compression")]
+pub const DIR_MAX_KEYS: usize      = (PAGE_SIZE - PACKED_HEADER - size_of::<PagePointer>() - size_of::<u32>() - size_of::<Summary>())
+                                      / (15 + size_of::<Summary>());
+
 
 /// A page containing directory data
 ///
@@ -29,24 +45,69 @@ pub const DIR_PTR_COUNT: usize     = DIR_KEY_COUNT+1;
 /// - pointers = [p0, p1, ...]
 /// Note that there is always exactly one more pointer than 
 /// there is key (count measures the number of **keys**).
+///
+/// Alongside each pointer is the number of records in the subtree
+/// it points to (see `BPlusTree::rank` and `BPlusTree::select`):
+/// - counts = [c0, c1, ...]
+///
+/// With the `aggregates` feature, each pointer also has a summary 
+/// of the values in its subtree:
+/// - summaries = [s0, s1, ...]
 #[repr(C)]
 #[derive(Debug, Clone)]
 pub struct DirectoryPage
 {
   page_type:    u8,
+
+  /// How the page is laid out on disk (see codec.rs); always RAW
+  /// for pages in memory
+  encoding:     u8,
   
   /// The number of keys in this page.  The number of 
   /// pointers is always 1 higher
   pub count:    usize,
 
   /// The array of keys
-  pub keys:     [u32; DIR_KEY_COUNT],
+  pub keys:     [u32; DIR_MAX_KEYS],
 
   /// The array of pointers
-  pub pointers: [PagePointer; DIR_PTR_COUNT],
+  pub pointers: [PagePointer; DIR_MAX_KEYS+1],
+
+  /// The number of records under each pointer
+  pub counts:   [u32; DIR_MAX_KEYS+1],
+
+  /// The values under each pointer (empty without the `aggregates`
+  /// feature)
+  pub summaries: [Summary; DIR_MAX_KEYS+1],
 }
+#[cfg(not(feature = "key-compression"))]
 const_assert!(PAGE_SIZE >= size_of::<DirectoryPage>());
 
+// On-disk offsets of the RAW layout; i.e., the DirectoryPage 
+// struct with DIR_KEY_COUNT keys
+#[cfg(feature = "key-compression")]
+const RAW_KEYS: usize = 16;
+#[cfg(feature = "key-compression")]
+const RAW_POINTERS: usize = (RAW_KEYS + DIR_KEY_COUNT * size_of::<u32>()).next_multiple_of(size_of::<PagePointer>());
+#[cfg(feature = "key-compression")]
+const RAW_COUNTS: usize = RAW_POINTERS + DIR_PTR_COUNT * size_of::<PagePointer>();
+#[cfg(feature = "key-compression")]
+const_assert!(PAGE_SIZE >= RAW_COUNTS + DIR_PTR_COUNT * size_of::<u32>());
+#[cfg(all(feature = "key-compression", feature = "aggregates"))]
+const RAW_SUMMARIES: usize = (RAW_COUNTS + DIR_PTR_COUNT * size_of::<u32>()).next_multiple_of(std::mem::align_of::<Summary>());
+#[cfg(all(feature = "key-compression", feature = "aggregates"))]
+const_assert!(PAGE_SIZE >= RAW_SUMMARIES + DIR_PTR_COUNT * size_of::<Summary>());
+
+// The DELTA24 layout is a PACKED_HEADER byte header of 
+//   page_type: u8, encoding: u8, count: u16, base: u32
+// followed by count+1 u64 pointers, count+1 u32 record counts, 
+// count+1 summaries (with the `aggregates` feature), and then 
+// count 3 byte key deltas.
+#[cfg(feature = "key-compression")]
+const_assert!(DIR_MAX_KEYS > DIR_KEY_COUNT);
+#[cfg(feature = "key-compression")]
+const PACKED_HEADER: usize = 8;
+
 #[allow(dead_code)]
 impl DirectoryPage
 {
@@ -55,12 +116,27 @@ impl DirectoryPage
   {
     DirectoryPage {
       page_type: DIR_PAGE_T, 
+      encoding: codec::RAW,
       count: 0, 
-      keys: [0 as u32; DIR_KEY_COUNT], 
-      pointers: [NULL_IDX; DIR_PTR_COUNT]
+      keys: [0 as u32; DIR_MAX_KEYS], 
+      pointers: [NULL_IDX; DIR_MAX_KEYS+1],
+      counts: [0; DIR_MAX_KEYS+1],
+      summaries: [Summary::EMPTY; DIR_MAX_KEYS+1],
     }
   }
 
+  /// The number of records under all of this page's pointers
+  pub fn total(&self) -> u64
+  {
+    self.counts[0 .. self.count+1].iter().map(|c| *c as u64).sum()
+  }
+
+  /// The summary of the values under all of this page's pointers
+  pub fn summary(&self) -> Summary
+  {
+    self.summaries[0 .. self.count+1].iter().fold(Summary::EMPTY, |s, t| s.combine(t))
+  }
+
   /// Find the index into `.pointers` that one would follow
   /// to retrieve the provided key.
   /// 
@@ -105,7 +181,17 @@ impl DirectoryPage
   /// to this directory page.
   pub fn is_full(&self) -> bool
   {
-    self.count >= DIR_KEY_COUNT
+    self.count >= DIR_MAX_KEYS
+  }
+
+  /// Return true if the provided key/pointer pair could be added
+  /// to this page without splitting it
+  pub fn can_insert(&self, key: u32) -> bool
+  {
+    if self.count < DIR_KEY_COUNT { return true }
+    // Past DIR_KEY_COUNT keys, the page must be compressible
+    if self.is_full() { return false }
+    codec::fits24(key.min(self.keys[0]), key.max(self.keys[self.count-1]))
   }
 
   /// Return true if this page has too few keys/pointers and 
@@ -131,29 +217,50 @@ impl DirectoryPage
   /// - split_key must be a value in the range [idx-1, idx)
   ///   where split_ptr is the idx'th key on this page.
   /// - new_ptr is the new pointer
+  /// - new_count is the number of records under new_ptr, all of
+  ///   which used to be under split_ptr
   ///
   /// Starting With `DirPage([p0 k0 p1 k1 p2 k2])`
   /// calling: `split_ptr(p1, k4, p4)` 
   /// would result in: `DirPage([p0 k0 p1 k4 p4 k1 p2 k2])`
   /// 
   /// Note that k0 < k4 < k1
-  pub fn split_at_ptr(&mut self, split_ptr: PagePointer, split_key: u32, new_ptr: PagePointer) 
-    -> Result<(), PageIsFullError>
+  ///
+  /// `new_count` records move from split_ptr to new_ptr, and
+  /// `summaries` are the new summaries of the values under 
+  /// split_ptr and new_ptr.
+  ///
+  /// A PageFull error is returned if the page has no room for
+  /// split_key, and a Corruption error if split_ptr isn't where
+  /// split_key says it should be.
+  pub fn split_at_ptr(&mut self, split_ptr: PagePointer, split_key: u32, new_ptr: PagePointer,
+                      new_count: u32, summaries: [Summary; 2]) 
+    -> BPlusResult<()>
   {
     // println!("{:?} <- Split {} @ {} to add {}", self, split_ptr, split_key, new_ptr);
-    if self.is_full() { return Err(PageIsFullError {  })}
+    if !self.can_insert(split_key) { return Err(BPlusError::PageFull) }
 
     let idx = self.find_pointer_idx(split_key);
 
     // println!("   @Idx: {}", idx);
-    assert!(self.pointers[idx] == split_ptr);
+    if self.pointers[idx] != split_ptr
+    {
+      return Err(BPlusError::corrupt_page(format!("Key {} leads to page {}, not page {}", split_key, self.pointers[idx], split_ptr)))
+    }
+    let remaining = self.counts[idx].checked_sub(new_count)
+      .ok_or_else(|| BPlusError::corrupt_page(format!("Pointer {} counts {} records, but {} were split from it", idx, self.counts[idx], new_count)))?;
     if idx < self.count
     {
       self.keys.copy_within(idx .. self.count, idx+1);
       self.pointers.copy_within(idx+1 .. self.count+1, idx+2);
+      self.counts.copy_within(idx+1 .. self.count+1, idx+2);
+      self.summaries.copy_within(idx+1 .. self.count+1, idx+2);
     }
     self.keys[idx] = split_key;
     self.pointers[idx+1] = new_ptr;
+    self.counts[idx] = remaining;
+    self.counts[idx+1] = new_count;
+    [self.summaries[idx], self.summaries[idx+1]] = summaries;
     self.count += 1;
     // println!("   AFTER: {:?}", self);
 
@@ -179,20 +286,29 @@ impl DirectoryPage
   pub fn split_page(&mut self) -> (u32, DirectoryPage)
   {
     let mut new_page = DirectoryPage::init();
-    let my_size = DIR_KEY_COUNT / 2;            // M = N/2
-    let new_size = DIR_KEY_COUNT - my_size -1;  // N-M-1
+    let old_size = self.count;
+    let my_size = old_size / 2;            // M = N/2
+    let new_size = old_size - my_size -1;  // N-M-1
 
     // println!("me: {}; new: {}", my_size, new_size);
 
     new_page.keys[0 .. new_size].copy_from_slice(
-      &self.keys[my_size+1 .. DIR_KEY_COUNT]
+      &self.keys[my_size+1 .. old_size]
     );
     new_page.pointers[0 .. new_size+1].copy_from_slice(
-      &self.pointers[my_size+1 .. DIR_PTR_COUNT]
+      &self.pointers[my_size+1 .. old_size+1]
+    );
+    new_page.counts[0 .. new_size+1].copy_from_slice(
+      &self.counts[my_size+1 .. old_size+1]
+    );
+    new_page.summaries[0 .. new_size+1].copy_from_slice(
+      &self.summaries[my_size+1 .. old_size+1]
     );
     // clear out the old k/p pairs to aid in debugging
-    for i in &mut self.keys[my_size+1 .. DIR_KEY_COUNT]     { *i = 0 }
-    for i in &mut self.pointers[my_size+1 .. DIR_PTR_COUNT] { *i = NULL_IDX }
+    for i in &mut self.keys[my_size+1 .. old_size]       { *i = 0 }
+    for i in &mut self.pointers[my_size+1 .. old_size+1] { *i = NULL_IDX }
+    for i in &mut self.counts[my_size+1 .. old_size+1]   { *i = 0 }
+    for i in &mut self.summaries[my_size+1 .. old_size+1] { *i = Summary::EMPTY }
 
     self.count = my_size;
     new_page.count = new_size;
@@ -208,16 +324,65 @@ impl DirectoryPage
   /// Before: DirPage([p0 k0 p1 k1 p2 k2 p3])
   /// Delete of index 2 (i.e., p2)
   /// After: DirPage([p0 k0 p1 k2 p3])
-  pub fn delete_idx(&mut self, idx: usize)
+  ///
+  /// The records under the deleted pointer are counted toward the
+  /// preceding pointer, whose page is expected to have absorbed
+  /// them (see `merge_with`).
+  pub fn delete_idx(&mut self, idx: usize) -> BPlusResult<()>
   {
-    assert!(idx > 0);
+    if idx == 0 || idx > self.count
+    {
+      return Err(BPlusError::corrupt_page(format!("Can't delete pointer {} of {}", idx, self.count+1)))
+    }
+    self.counts[idx-1] += self.counts[idx];
+    self.summaries[idx-1] = self.summaries[idx-1].combine(&self.summaries[idx]);
     self.keys.copy_within(idx..self.count, idx-1);
     self.pointers.copy_within((idx+1)..(self.count+1), idx);
+    self.counts.copy_within((idx+1)..(self.count+1), idx);
+    self.summaries.copy_within((idx+1)..(self.count+1), idx);
     self.count -= 1;
     // Technically not needed, but just for safety, let's clear
     // out the old values 
     self.keys[self.count] = 0;
     self.pointers[self.count+1] = NULL_IDX;
+    self.counts[self.count+1] = 0;
+    self.summaries[self.count+1] = Summary::EMPTY;
+    Ok(())
+  }
+
+  /// Delete the pointers in the specified index range, along with
+  /// the keys that separated them from the pointers that remain.
+  /// At least one pointer must remain.
+  ///
+  /// Before: DirPage([p0 k0 p1 k1 p2 k2 p3])
+  /// Delete of 1..3 (i.e., p1 and p2)
+  /// After: DirPage([p0 k2 p3])
+  /// Delete of 0..2 (i.e., p0 and p1) instead
+  /// After: DirPage([p2 k2 p3])
+  ///
+  /// Unlike `delete_idx`, the records under the deleted pointers 
+  /// are dropped from the counts; the caller is expected to have 
+  /// deleted them.
+  pub fn delete_ptr_range(&mut self, range: Range<usize>) -> BPlusResult<()>
+  {
+    if range.is_empty() { return Ok(()) }
+    if range.end > self.count+1 || range.len() > self.count
+    {
+      return Err(BPlusError::corrupt_page(format!("Can't delete pointers {:?} of {}", range, self.count+1)))
+    }
+    let n = range.len();
+    let keys = if range.start > 0 { range.start-1 .. range.end-1 } else { 0 .. range.end };
+    self.keys.copy_within(keys.end..self.count, keys.start);
+    self.pointers.copy_within(range.end..(self.count+1), range.start);
+    self.counts.copy_within(range.end..(self.count+1), range.start);
+    self.summaries.copy_within(range.end..(self.count+1), range.start);
+    self.count -= n;
+    // As in delete_idx, clear out the old values
+    self.keys[self.count .. self.count+n].fill(0);
+    self.pointers[self.count+1 .. self.count+n+1].fill(NULL_IDX);
+    self.counts[self.count+1 .. self.count+n+1].fill(0);
+    self.summaries[self.count+1 .. self.count+n+1].fill(Summary::EMPTY);
+    Ok(())
   }
 
   /// 'Steal' a key/pointer from the other page, assuming that
@@ -236,17 +401,18 @@ impl DirectoryPage
   ///  - k4 is returned for re-insertion into the parent
   ///  directory page.
   pub fn steal_high_from(&mut self, other: &mut DirectoryPage, parent_key: u32)
-    -> u32
+    -> BPlusResult<u32>
   {
-    assert!(self.count < DIR_KEY_COUNT);
-    assert!(other.count > 0);
+    DirectoryPage::check_steal(self, other)?;
     // free up space
     self.keys.copy_within(0..self.count, 1);
-    println!("{:?} -> {}", self.pointers, self.count);
     self.pointers.copy_within(0..self.count+1, 1);
-    println!("{:?}", self.pointers);
+    self.counts.copy_within(0..self.count+1, 1);
+    self.summaries.copy_within(0..self.count+1, 1);
     // move p5 (@other.count - 1 + 1)
     self.pointers[0] = other.pointers[other.count];
+    self.counts[0] = other.counts[other.count];
+    self.summaries[0] = other.summaries[other.count];
     // update k1
     self.keys[0] = parent_key;
     // retrieve the new parent pointer
@@ -258,11 +424,13 @@ impl DirectoryPage
     // of safety:
     other.keys[other.count-1] = 0;
     other.pointers[other.count] = NULL_IDX;
+    other.counts[other.count] = 0;
+    other.summaries[other.count] = Summary::EMPTY;
 
     other.count -= 1;
     self.count += 1;
 
-    return ret;
+    return Ok(ret);
   }
 
 
@@ -282,16 +450,17 @@ impl DirectoryPage
   ///  - k6 is returned for re-insertion into the parent
   ///  directory page.
   pub fn steal_low_from(&mut self, other: &mut DirectoryPage, parent_key: u32)
-   -> u32
+   -> BPlusResult<u32>
   {
-    assert!(self.count < DIR_KEY_COUNT);
-    assert!(other.count > 0);
+    DirectoryPage::check_steal(self, other)?;
     // preserve k6 to be returned
     let ret = other.keys[0];
     // insert k1
     self.keys[self.count] = parent_key;
     // insert p6
     self.pointers[self.count+1] = other.pointers[0];
+    self.counts[self.count+1] = other.counts[0];
+    self.summaries[self.count+1] = other.summaries[0];
 
     self.count += 1;
 
@@ -299,14 +468,28 @@ impl DirectoryPage
     // back into place.
     other.keys.copy_within(1..other.count, 0);
     other.pointers.copy_within(1..other.count+1, 0);
+    other.counts.copy_within(1..other.count+1, 0);
+    other.summaries.copy_within(1..other.count+1, 0);
     other.count -= 1;
 
     // Technically unnecessary, but just to aid in debugging
     // zero out the old keys.
     other.keys[other.count] = 0;
     other.pointers[other.count+1] = NULL_IDX;
+    other.counts[other.count+1] = 0;
+    other.summaries[other.count+1] = Summary::EMPTY;
 
-    return ret;
+    return Ok(ret);
+  }
+
+  /// Return an error unless `to` can take a key from `from`
+  fn check_steal(to: &DirectoryPage, from: &DirectoryPage) -> BPlusResult<()>
+  {
+    if to.count >= DIR_KEY_COUNT || from.count == 0
+    {
+      return Err(BPlusError::corrupt_page(format!("Can't steal a key from a page with {} keys for one with {}", from.count, to.count)))
+    }
+    Ok(())
   }
 
   /// 'Merge' this directory page with it's immediately 
@@ -321,20 +504,129 @@ impl DirectoryPage
   ///  After calling p1.merge_with(p2, k1)...
   ///  - p1: DirPage( [p4 k4 p5 k1 p6 k6 p7] )
   ///  - p2: unchanged
-  pub fn merge_with(&mut self, other: & DirectoryPage, parent_key: u32)
+  pub fn merge_with(&mut self, other: & DirectoryPage, parent_key: u32) -> BPlusResult<()>
   {
-    assert!(self.count + other.count <= DIR_KEY_COUNT);
+    if self.count + other.count > DIR_KEY_COUNT
+    {
+      return Err(BPlusError::corrupt_page(format!("Can't merge pages with {} and {} keys", self.count, other.count)))
+    }
     self.keys[self.count] = parent_key;
     self.pointers[(self.count+1)..(self.count+1+other.count+1)]
         .copy_from_slice(&other.pointers[0..(other.count+1)]);
+    self.counts[(self.count+1)..(self.count+1+other.count+1)]
+        .copy_from_slice(&other.counts[0..(other.count+1)]);
+    self.summaries[(self.count+1)..(self.count+1+other.count+1)]
+        .copy_from_slice(&other.summaries[0..(other.count+1)]);
     self.keys[(self.count+1)..(self.count+1+other.count)]
         .copy_from_slice(&other.keys[0..(other.count)]);
     self.count += other.count + 1;
+    Ok(())
+  }
+}
+
+#[cfg(feature = "key-compression")]
+impl DirectoryPage
+{
+  /// Decode either on-disk layout (see codec.rs)
+  fn decode_packed(buffer: &[u8; PAGE_SIZE]) -> DirectoryPage
+  {
+    let mut page = DirectoryPage::init();
+    let mut r = codec::Reader::new(buffer, 0);
+    page.page_type = r.u8();
+    // Don't try to make sense of anything that isn't a directory;
+    // the caller will reject it based on the page type.
+    if page.page_type != DIR_PAGE_T { return page }
+
+    if r.u8() == codec::DELTA24
+    {
+      page.count = (r.u16() as usize).min(DIR_MAX_KEYS);
+      let base = r.u32();
+      for i in 0 .. page.count+1 { page.pointers[i] = r.u64(); }
+      for i in 0 .. page.count+1 { page.counts[i] = r.u32(); }
+      #[cfg(feature = "aggregates")]
+      for i in 0 .. page.count+1 { page.summaries[i] = Summary::read(&mut r); }
+      for i in 0 .. page.count   { page.keys[i] = base.wrapping_add(r.u24()); }
+    }
+    else
+    {
+      r.seek(8);
+      page.count = r.usize().min(DIR_KEY_COUNT);
+      for i in 0 .. page.count { page.keys[i] = r.u32(); }
+      r.seek(RAW_POINTERS);
+      for i in 0 .. page.count+1 { page.pointers[i] = r.u64(); }
+      r.seek(RAW_COUNTS);
+      for i in 0 .. page.count+1 { page.counts[i] = r.u32(); }
+      #[cfg(feature = "aggregates")]
+      {
+        r.seek(RAW_SUMMARIES);
+        for i in 0 .. page.count+1 { page.summaries[i] = Summary::read(&mut r); }
+      }
+    }
+    page
+  }
+
+  /// Encode the page in the smallest layout that it fits in
+  fn encode_packed(&self, buffer: &mut [u8; PAGE_SIZE]) -> BPlusResult<()>
+  {
+    if self.count > DIR_MAX_KEYS
+    {
+      return Err(BPlusError::corrupt_page(format!("Can't store a directory page with {} keys", self.count)))
+    }
+    let mut w = codec::Writer::new(buffer);
+    w.u8(self.page_type);
+    let base = if self.count > 0 { self.keys[0] } else { 0 };
+    let high = if self.count > 0 { self.keys[self.count-1] } else { 0 };
+    if base <= high && codec::fits24(base, high)
+    {
+      w.u8(codec::DELTA24);
+      w.u16(self.count as u16);
+      w.u32(base);
+      for ptr in &self.pointers[0 .. self.count+1] { w.u64(*ptr); }
+      for c in &self.counts[0 .. self.count+1]     { w.u32(*c); }
+      #[cfg(feature = "aggregates")]
+      for s in &self.summaries[0 .. self.count+1]  { s.write(&mut w); }
+      for key in &self.keys[0 .. self.count]       { w.u24(key - base); }
+    }
+    else
+    {
+      if self.count > DIR_KEY_COUNT
+      {
+        return Err(BPlusError::corrupt_page(format!("Can't store {} keys from {} to {} on a directory page", self.count, base, high)))
+      }
+      w.u8(codec::RAW);
+      w.seek(8);
+      w.usize(self.count);
+      for key in &self.keys[0 .. self.count] { w.u32(*key); }
+      w.seek(RAW_POINTERS);
+      for ptr in &self.pointers[0 .. self.count+1] { w.u64(*ptr); }
+      w.seek(RAW_COUNTS);
+      for c in &self.counts[0 .. self.count+1] { w.u32(*c); }
+      #[cfg(feature = "aggregates")]
+      {
+        w.seek(RAW_SUMMARIES);
+        for s in &self.summaries[0 .. self.count+1] { s.write(&mut w); }
+      }
+    }
+    Ok(())
   }
 }
 
 impl Page for DirectoryPage
 {
   const EXPECTED_PAGE_TYPE: u8 = DIR_PAGE_T;
+  const VIEWABLE: bool = cfg!(not(feature = "key-compression"));
+
   fn page_type(&self) -> u8 { self.page_type }
+
+  fn corruption(&self) -> Option<String>
+  {
+    if self.count > DIR_MAX_KEYS { Some(format!("Holds {} keys, but at most {} fit", self.count, DIR_MAX_KEYS)) }
+    else                         { None }
+  }
+
+  #[cfg(feature = "key-compression")]
+  fn decode(buffer: &[u8; PAGE_SIZE]) -> DirectoryPage { DirectoryPage::decode_packed(buffer) }
+
+  #[cfg(feature = "key-compression")]
+  fn encode(&self, buffer: &mut [u8; PAGE_SIZE]) -> BPlusResult<()> { self.encode_packed(buffer) }
 }
\ No newline at end of file
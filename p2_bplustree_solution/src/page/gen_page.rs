@@ -0,0 +1,40 @@
+use super::{ Page, GENERATION_PAGE_T, PAGE_SIZE };
+use static_assertions::const_assert;
+use std::mem::size_of;
+
+/// The number of pages whose generations fit on one page
+pub const GENERATION_CAPACITY: usize = 1011;
+
+/// A page of a generation map (see generations.rs)
+///
+/// Page `i` of the map holds the generations of tree pages 
+/// `[i * GENERATION_CAPACITY, (i+1) * GENERATION_CAPACITY)`.
+#[repr(C)]
+#[derive(Debug, Clone)]
+pub struct GenerationPage
+{
+  page_type: u8,
+  padding: [u8; 3],
+  pub generations: [u32; GENERATION_CAPACITY],
+}
+const_assert!(PAGE_SIZE >= size_of::<GenerationPage>());
+
+impl GenerationPage
+{
+  /// Initialize a page of generations, all 0
+  pub fn init() -> GenerationPage
+  {
+    GenerationPage {
+      page_type: GENERATION_PAGE_T,
+      padding: [0; 3],
+      generations: [0; GENERATION_CAPACITY],
+    }
+  }
+}
+
+impl Page for GenerationPage
+{
+  const EXPECTED_PAGE_TYPE: u8 = GENERATION_PAGE_T;
+
+  fn page_type(&self) -> u8 { self.page_type }
+}
@@ -0,0 +1,46 @@
+use super::{ Page, PagePointer, JOURNAL_PAGE_T, PAGE_SIZE };
+use static_assertions::const_assert;
+use std::mem::size_of;
+
+/// The number of page images that one journal batch can hold
+pub const JOURNAL_CAPACITY: usize = 502;
+
+/// The header of a journal (page 0 of the journal storage)
+///
+/// The journal holds a single batch of page images: the images
+/// themselves are on journal pages 1 ..= count, and are destined
+/// for pages `pointers[0 .. count]` of the tree.  The batch is only
+/// valid if `checksum` matches the pointers and the images.
+#[repr(C)]
+#[derive(Debug, Clone)]
+pub struct JournalPage
+{
+  page_type: u8,
+  padding: [u8; 7],
+  pub count: usize,
+  pub checksum: u64,
+  pub pointers: [PagePointer; JOURNAL_CAPACITY],
+}
+const_assert!(PAGE_SIZE >= size_of::<JournalPage>());
+
+impl JournalPage
+{
+  /// Initialize an empty journal header
+  pub fn init() -> JournalPage
+  {
+    JournalPage { 
+      page_type: JOURNAL_PAGE_T, 
+      padding: [0; 7],
+      count: 0, 
+      checksum: 0, 
+      pointers: [0; JOURNAL_CAPACITY] 
+    }
+  }
+}
+
+impl Page for JournalPage
+{
+  const EXPECTED_PAGE_TYPE: u8 = JOURNAL_PAGE_T;
+
+  fn page_type(&self) -> u8 { self.page_type }
+}
@@ -1,6 +1,6 @@
-use crate::page::PageIsFullError;
+use crate::error::{ BPlusError, BPlusResult };
 
-use super::{ Page, PagePointer, LEAF_PAGE_T, NULL_IDX, PAGE_SIZE };
+use super::{ codec, Page, PagePointer, Summary, LEAF_PAGE_T, NULL_IDX, PAGE_SIZE };
 use static_assertions::const_assert;
 use std::{mem::size_of, ops::Index};
 
@@ -10,18 +10,47 @@ use std::{mem::size_of, ops::Index};
 
 pub const LEAF_RECORD_COUNT: usize = 502;  // Max key/value pairs that will fit on one page
 
+/// The most key/value pairs that a page can hold.  With key 
+/// compression, pages whose keys are close together can hold more
+/// than LEAF_RECORD_COUNT records.  LEAF_RECORD_COUNT still decides
+/// when a page is underfull.
+#[cfg(not(feature = "key-compression"))]
+pub const LEAF_MAX_RECORDS: usize = LEAF_RECORD_COUNT;
+#[cfg(feature = "key-compression")]
+pub const LEAF_MAX_RECORDS: usize = (PAGE_SIZE - PACKED_HEADER) / 6;
+
 #[repr(C)]
 #[derive(Debug, Clone)]
 pub struct LeafPage
 {
   page_type: u8,
+  /// How the page is laid out on disk (see codec.rs); always RAW
+  /// for pages in memory
+  encoding: u8,
   pub count:     usize,
-  pub key_value: [(u32, u32); LEAF_RECORD_COUNT],
+  pub key_value: [(u32, u32); LEAF_MAX_RECORDS],
   pub next:      PagePointer,
   pub prev:      PagePointer
 }
+#[cfg(not(feature = "key-compression"))]
 const_assert!(PAGE_SIZE >= size_of::<LeafPage>());
 
+// On-disk offsets of the RAW layout; i.e., the LeafPage struct with
+// LEAF_RECORD_COUNT records
+#[cfg(feature = "key-compression")]
+const RAW_KEY_VALUE: usize = 16;
+#[cfg(feature = "key-compression")]
+const RAW_NEXT: usize = RAW_KEY_VALUE + LEAF_RECORD_COUNT * size_of::<(u32, u32)>();
+#[cfg(feature = "key-compression")]
+const_assert!(PAGE_SIZE >= RAW_NEXT + 2 * size_of::<PagePointer>());
+
+// The DELTA16 layout is a PACKED_HEADER byte header of 
+//   page_type: u8, encoding: u8, count: u16, base: u32, 
+//   next: u64, prev: u64
+// followed by count u16 key deltas and then count u32 values.
+#[cfg(feature = "key-compression")]
+const PACKED_HEADER: usize = 24;
+
 #[allow(dead_code)]
 impl LeafPage
 {
@@ -30,8 +59,9 @@ impl LeafPage
   {
     LeafPage { 
       page_type: LEAF_PAGE_T,
+      encoding: codec::RAW,
       count: 0, 
-      key_value: [(0,0); LEAF_RECORD_COUNT], 
+      key_value: [(0,0); LEAF_MAX_RECORDS], 
       next: NULL_IDX,
       prev: NULL_IDX,
     }
@@ -41,7 +71,19 @@ impl LeafPage
   /// to this directory page.
   pub fn is_full(&self) -> bool
   {
-    self.count >= LEAF_RECORD_COUNT
+    self.count >= LEAF_MAX_RECORDS
+  }
+
+  /// Return true if the provided key could be put on this page
+  /// without splitting it
+  pub fn can_insert(&self, key: u32) -> bool
+  {
+    if self.count < LEAF_RECORD_COUNT || self.find_index(key).is_ok() { return true }
+    // Past LEAF_RECORD_COUNT records, the page must be compressible
+    if self.is_full() { return false }
+    let low = key.min(self.key_value[0].0);
+    let high = key.max(self.key_value[self.count-1].0);
+    codec::fits(low, high)
   }
 
   /// Return true if this page has too few key/value pairs and 
@@ -90,16 +132,17 @@ impl LeafPage
   pub fn split(&mut self) -> LeafPage
   {
     let mut new_page = LeafPage::init();
-    let my_size = LEAF_RECORD_COUNT / 2;
-    let new_size = LEAF_RECORD_COUNT - my_size;
+    let old_size = self.count;
+    let my_size = old_size / 2;
+    let new_size = old_size - my_size;
 
     new_page.key_value[0 .. new_size].copy_from_slice(
-      &self.key_value[my_size .. LEAF_RECORD_COUNT]
+      &self.key_value[my_size .. old_size]
     );
     self.count = my_size;
     new_page.count = new_size;
     // For easier debugging, zero out the deleted values
-    for i in my_size .. LEAF_RECORD_COUNT
+    for i in my_size .. old_size
     {
       self.key_value[i] = (0,0)
     }
@@ -107,6 +150,28 @@ impl LeafPage
     return new_page
   }
 
+  /// Split this leaf page at the provided key
+  ///
+  /// Removes the key/value pairs with keys greater than or equal
+  /// to `key`, and returns them on a new leaf page.  Like `split`,
+  /// this does not manage the next/prev pointers.
+  pub fn split_off(&mut self, key: u32) -> LeafPage
+  {
+    let mut new_page = LeafPage::init();
+    let (Ok(idx) | Err(idx)) = self.find_index(key);
+    let new_size = self.count - idx;
+    new_page.key_value[0 .. new_size].copy_from_slice(
+      &self.key_value[idx .. self.count]
+    );
+    new_page.count = new_size;
+    for i in idx .. self.count
+    {
+      self.key_value[i] = (0,0)
+    }
+    self.count = idx;
+    new_page
+  }
+
   /// Find the value for the specified key in the index
   /// if it exists, or None otherwise.
   pub fn find_value(&self, key: u32) -> Option<u32>
@@ -123,9 +188,9 @@ impl LeafPage
   /// - If the key already exists on this page, the corresponding
   ///   value is updated.
   /// - If the key does not already exist on this page, it is
-  ///   inserted.  A PageIsFullError is returned if insufficient
+  ///   inserted.  A PageFull error is returned if insufficient
   ///   space exists in this case.
-  pub fn put(&mut self, key: u32, value: u32) -> Result<(), PageIsFullError>
+  pub fn put(&mut self, key: u32, value: u32) -> BPlusResult<()>
   {
     match self.find_index(key)
     {
@@ -135,7 +200,7 @@ impl LeafPage
       }
       Err(idx) =>
       {
-        if self.is_full() { return Err(PageIsFullError {}) }
+        if !self.can_insert(key) { return Err(BPlusError::PageFull) }
         self.key_value.copy_within(idx..self.count, idx+1);
         self.key_value[idx] = (key, value);
         self.count += 1;
@@ -161,30 +226,57 @@ impl LeafPage
     }
   }
 
+  /// Delete every key in [low, high] from this page.
+  /// Return the number of keys deleted.
+  pub fn delete_range(&mut self, low: u32, high: u32) -> usize
+  {
+    let (Ok(start) | Err(start)) = self.find_index(low);
+    let end = match self.find_index(high) { Ok(idx) => idx+1, Err(idx) => idx };
+    if end <= start { return 0 }
+    self.key_value.copy_within(end..self.count, start);
+    let removed = end - start;
+    self.count -= removed;
+    for i in self.count .. self.count + removed
+    {
+      self.key_value[i] = (0,0)
+    }
+    removed
+  }
+
   /// 'Steal' the greatest key from this page and return
   /// the corresponding key/value pair.  The pair is
   /// removed from this page.
-  pub fn steal_high(&mut self) -> (u32, u32)
+  pub fn steal_high(&mut self) -> BPlusResult<(u32, u32)>
   {
-    assert!(self.can_allow_stolen_key());
+    self.check_steal()?;
     self.count -= 1;
     let kv = self.key_value[self.count];
     // to aid in debugging set the stolen value to 0
     self.key_value[self.count] = (0, 0);
-    return kv
+    return Ok(kv)
   }
 
   /// 'Steal' the least key from this page and return
   /// the corresponding key/value pair.  The pair is
   /// removed from this page.
-  pub fn steal_low(&mut self) -> (u32, u32)
+  pub fn steal_low(&mut self) -> BPlusResult<(u32, u32)>
   {
-    assert!(self.can_allow_stolen_key());
+    self.check_steal()?;
     let kv = self.key_value[0];
     self.key_value.copy_within(1..self.count, 0);
     self.count -= 1;
     self.key_value[self.count] = (0, 0);
-    return kv
+    return Ok(kv)
+  }
+
+  /// Return an error unless a key can be stolen from this page
+  fn check_steal(&self) -> BPlusResult<()>
+  {
+    if !self.can_allow_stolen_key()
+    {
+      return Err(BPlusError::corrupt_page(format!("Can't steal a record from a page with {} records", self.count)))
+    }
+    Ok(())
   }
 
   /// Update this page by appending the contents of another 
@@ -192,13 +284,17 @@ impl LeafPage
   ///
   /// This page must contain the **lesser** of the two sets of
   /// keys.  
-  pub fn merge_with(&mut self, other: &LeafPage)
+  pub fn merge_with(&mut self, other: &LeafPage) -> BPlusResult<()>
   {
-    assert!(self.count + other.count <= LEAF_RECORD_COUNT);
+    if self.count + other.count > LEAF_RECORD_COUNT
+    {
+      return Err(BPlusError::corrupt_page(format!("Can't merge pages with {} and {} records", self.count, other.count)))
+    }
 
     self.key_value[self.count .. self.count + other.count]
         .copy_from_slice(&other.key_value[0 .. other.count]);
     self.count += other.count;
+    Ok(())
   }
 
   /// Obtain an iterator over the elements of this page.
@@ -206,13 +302,106 @@ impl LeafPage
   {
     Box::new(self.key_value.iter().take(self.count))
   }
+
+  /// The summary of the values on this page
+  pub fn summary(&self) -> Summary
+  {
+    Summary::of(self.key_value[0 .. self.count].iter().map(|(_, v)| *v))
+  }
+}
+
+#[cfg(feature = "key-compression")]
+impl LeafPage
+{
+  /// Decode either on-disk layout (see codec.rs)
+  fn decode_packed(buffer: &[u8; PAGE_SIZE]) -> LeafPage
+  {
+    let mut page = LeafPage::init();
+    let mut r = codec::Reader::new(buffer, 0);
+    page.page_type = r.u8();
+    // Don't try to make sense of anything that isn't a leaf; the
+    // caller will reject it based on the page type.
+    if page.page_type != LEAF_PAGE_T { return page }
+
+    if r.u8() == codec::DELTA16
+    {
+      page.count = (r.u16() as usize).min(LEAF_MAX_RECORDS);
+      let base = r.u32();
+      page.next = r.u64();
+      page.prev = r.u64();
+      for i in 0 .. page.count { page.key_value[i].0 = base + r.u16() as u32; }
+      for i in 0 .. page.count { page.key_value[i].1 = r.u32(); }
+    }
+    else
+    {
+      r.seek(8);
+      page.count = r.usize().min(LEAF_RECORD_COUNT);
+      for i in 0 .. page.count { page.key_value[i] = (r.u32(), r.u32()); }
+      r.seek(RAW_NEXT);
+      page.next = r.u64();
+      page.prev = r.u64();
+    }
+    page
+  }
+
+  /// Encode the page in the smallest layout that it fits in
+  fn encode_packed(&self, buffer: &mut [u8; PAGE_SIZE]) -> BPlusResult<()>
+  {
+    if self.count > LEAF_MAX_RECORDS
+    {
+      return Err(BPlusError::corrupt_page(format!("Can't store a leaf page with {} records", self.count)))
+    }
+    let mut w = codec::Writer::new(buffer);
+    w.u8(self.page_type);
+    let base = if self.count > 0 { self.key_value[0].0 } else { 0 };
+    let high = if self.count > 0 { self.key_value[self.count-1].0 } else { 0 };
+    if base <= high && codec::fits(base, high)
+    {
+      w.u8(codec::DELTA16);
+      w.u16(self.count as u16);
+      w.u32(base);
+      w.u64(self.next);
+      w.u64(self.prev);
+      for (k, _) in self.iter() { w.u16((k - base) as u16); }
+      for (_, v) in self.iter() { w.u32(*v); }
+    }
+    else
+    {
+      if self.count > LEAF_RECORD_COUNT
+      {
+        return Err(BPlusError::corrupt_page(format!("Can't store {} records with keys from {} to {} on a leaf page", self.count, base, high)))
+      }
+      w.u8(codec::RAW);
+      w.seek(8);
+      w.usize(self.count);
+      for (k, v) in self.iter() { w.u32(*k); w.u32(*v); }
+      w.seek(RAW_NEXT);
+      w.u64(self.next);
+      w.u64(self.prev);
+    }
+    Ok(())
+  }
 }
 
 impl Page for LeafPage
 {
   const EXPECTED_PAGE_TYPE: u8 = LEAF_PAGE_T;
 
+  const VIEWABLE: bool = cfg!(not(feature = "key-compression"));
+
   fn page_type(&self) -> u8 { self.page_type }
+
+  fn corruption(&self) -> Option<String>
+  {
+    if self.count > LEAF_MAX_RECORDS { Some(format!("Holds {} records, but at most {} fit", self.count, LEAF_MAX_RECORDS)) }
+    else                             { None }
+  }
+
+  #[cfg(feature = "key-compression")]
+  fn decode(buffer: &[u8; PAGE_SIZE]) -> LeafPage { LeafPage::decode_packed(buffer) }
+
+  #[cfg(feature = "key-compression")]
+  fn encode(&self, buffer: &mut [u8; PAGE_SIZE]) -> BPlusResult<()> { self.encode_packed(buffer) }
 }
 
 impl Index<usize> for LeafPage
@@ -0,0 +1,70 @@
+use super::{ Page, LOG_PAGE_T, PAGE_SIZE };
+use static_assertions::const_assert;
+use std::mem::size_of;
+
+/// The number of entries on one change log page
+pub const LOG_CAPACITY: usize = 252;
+
+/// One logged mutation (see changelog.rs for the meaning of `op`,
+/// `key`, and `value`), and the time it was made (see Clock)
+#[repr(C)]
+#[derive(Debug, Clone, Copy, Default)]
+pub struct LogEntry
+{
+  pub op: u32,
+  pub key: u32,
+  pub value: u32,
+  pub time: u32,
+}
+
+/// A page of a change log
+///
+/// Entry `i` has sequence number `first_seq + i`.  Every page but
+/// the last is full, so the page holding a sequence number can be
+/// computed from the first page's `first_seq`.
+#[repr(C)]
+#[derive(Debug, Clone)]
+pub struct LogPage
+{
+  page_type: u8,
+  padding: [u8; 3],
+  pub count: u32,
+  pub first_seq: u64,
+  pub entries: [LogEntry; LOG_CAPACITY],
+}
+const_assert!(PAGE_SIZE >= size_of::<LogPage>());
+
+impl LogPage
+{
+  /// Initialize an empty page, whose first entry will have the
+  /// provided sequence number
+  pub fn init(first_seq: u64) -> LogPage
+  {
+    LogPage {
+      page_type: LOG_PAGE_T,
+      padding: [0; 3],
+      count: 0,
+      first_seq,
+      entries: [LogEntry::default(); LOG_CAPACITY],
+    }
+  }
+
+  /// True if no more entries fit on this page
+  pub fn is_full(&self) -> bool
+  {
+    self.count as usize >= LOG_CAPACITY
+  }
+}
+
+impl Page for LogPage
+{
+  const EXPECTED_PAGE_TYPE: u8 = LOG_PAGE_T;
+
+  fn page_type(&self) -> u8 { self.page_type }
+
+  fn corruption(&self) -> Option<String>
+  {
+    if self.count as usize > LOG_CAPACITY { Some(format!("Holds {} entries, but at most {} fit", self.count, LOG_CAPACITY)) }
+    else                                  { None }
+  }
+}
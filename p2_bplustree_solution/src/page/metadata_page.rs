@@ -1,4 +1,4 @@
-use super::{ Page, PagePointer, META_PAGE_T, PAGE_SIZE };
+use super::{ Page, PagePointer, CIPHER_NONE, LAYOUT, META_PAGE_T, PAGE_SIZE };
 use static_assertions::const_assert;
 use std::mem::size_of;
 
@@ -7,13 +7,43 @@ use std::mem::size_of;
 pub struct MetadataPage
 {
   page_type: u8,
+  // The metadata page isn't encrypted (see EncryptedStorage), so 
+  // its padding is explicit, to keep uninitialized memory out of it
+  padding: [u8; 7],
   pub next_free_page: PagePointer,
   pub root_page: PagePointer,
   pub data_head: PagePointer,
   pub data_tail: PagePointer,
   pub pages_allocated: PagePointer,
   pub depth: u16,
+
+  /// The cipher that the tree's pages are encrypted with (see
+  /// Storage::cipher).  Older trees have CIPHER_NONE here.
+  pub cipher: u8,
+  /// Identifies the key that the pages are encrypted with, so 
+  /// that opening the tree with the wrong key can be detected
+  pub key_check: [u8; 16],
+  /// The layout of the tree's directory and leaf pages (see 
+  /// LAYOUT).  Trees from before the layout was recorded have 
+  /// LAYOUT_PLAIN here, and are refused by `BPlusTree::open_with`.
+  pub layout: u8,
+  trailing_padding: [u8; 4],
+
+  /// The sequence number of the last logged change to the tree 
+  /// (see ChangeLog).  Older trees have 0 here.
+  pub last_seq: u64,
+
+  /// The generation that pages written now belong to, which each
+  /// backup ends (see `BPlusTree::backup_incremental`).  Older 
+  /// trees have 0 here.
+  pub generation: u32,
+  final_padding: [u8; 4],
+
+  /// The number of batches committed through the journal (see 
+  /// `BPlusTree::apply_batch`).  Older trees have 0 here.
+  pub commits: u64,
 }
+const_assert!(size_of::<MetadataPage>() == 96);
 const_assert!(PAGE_SIZE >= size_of::<MetadataPage>());
 
 impl MetadataPage
@@ -29,12 +59,21 @@ impl MetadataPage
   {
     MetadataPage {
       page_type: META_PAGE_T,
+      padding: [0; 7],
       next_free_page,
       root_page,
       data_head,
       data_tail,
       pages_allocated,
-      depth
+      depth,
+      cipher: CIPHER_NONE,
+      key_check: [0; 16],
+      layout: LAYOUT,
+      trailing_padding: [0; 4],
+      last_seq: 0,
+      generation: 0,
+      final_padding: [0; 4],
+      commits: 0,
     }
   }
 }
@@ -1,18 +1,24 @@
+mod codec;
+mod delta_page;
 mod dir_page;
 mod leaf_page;
 mod metadata_page;
 mod free_page;
+mod gen_page;
+mod journal_page;
+mod log_page;
+mod summary;
 
-use std::error::Error;
-use std::fmt;
-use std::fs::File;
-use std::io::Write;
-use std::io::Read;
+use crate::error::{ BPlusError, BPlusResult };
+use crate::storage::Storage;
 use core::slice;
-use std::mem::{ transmute_copy, size_of };
+use static_assertions::const_assert;
+use std::mem::{ transmute_copy, size_of, align_of };
 
 /// The number of bytes in a page
 pub const PAGE_SIZE: usize         = 4048; 
+// Pages viewed in place (see Page::view) must stay aligned
+const_assert!(PAGE_SIZE.is_multiple_of(align_of::<u64>()));
 
 /// The expected index of the metadata page
 pub const METADATA_IDX: PagePointer = 0;
@@ -30,6 +36,23 @@ pub const DIR_KEY_COUNT: usize = dir_page::DIR_KEY_COUNT;
 /// The number of records in a leaf page (see leaf_page.rs)
 #[allow(dead_code)]
 pub const LEAF_RECORD_COUNT: usize = leaf_page::LEAF_RECORD_COUNT;
+/// The most keys that fit in a directory page, with key compression
+#[allow(dead_code)]
+pub const DIR_MAX_KEYS: usize = dir_page::DIR_MAX_KEYS;
+/// The most records that fit in a leaf page, with key compression
+#[allow(dead_code)]
+pub const LEAF_MAX_RECORDS: usize = leaf_page::LEAF_MAX_RECORDS;
+
+/// The number of page images in one journal batch (see journal_page.rs)
+pub const JOURNAL_CAPACITY: usize = journal_page::JOURNAL_CAPACITY;
+/// The number of entries on one change log page (see log_page.rs)
+pub const LOG_CAPACITY: usize = log_page::LOG_CAPACITY;
+/// The number of pages whose generations fit on one generation 
+/// map page (see gen_page.rs)
+pub const GENERATION_CAPACITY: usize = gen_page::GENERATION_CAPACITY;
+/// The number of page images in one chunk of an incremental backup
+/// (see delta_page.rs)
+pub const DELTA_CAPACITY: usize = delta_page::DELTA_CAPACITY;
 
 /// The index of a page
 pub type PagePointer = u64;
@@ -41,6 +64,19 @@ pub type DirectoryPage = dir_page::DirectoryPage;
 pub type LeafPage = leaf_page::LeafPage;
 /// An empty 'free' page
 pub type FreePage = free_page::FreePage;
+/// The header of a journal of pending page writes
+pub type JournalPage = journal_page::JournalPage;
+/// A page of a change log
+pub type LogPage = log_page::LogPage;
+/// One entry of a change log page
+pub type LogEntry = log_page::LogEntry;
+/// A page of a map from tree pages to the generation in which they
+/// were last written
+pub type GenerationPage = gen_page::GenerationPage;
+/// The header of one chunk of an incremental backup
+pub type DeltaPage = delta_page::DeltaPage;
+/// A summary of the values under a directory pointer
+pub type Summary = summary::Summary;
 
 /// Type constant for metadata pages
 pub const META_PAGE_T:u8 = 0;
@@ -50,6 +86,58 @@ pub const DIR_PAGE_T:u8 = 1;
 pub const LEAF_PAGE_T:u8 = 2;
 /// Type constant for free pages
 pub const FREE_PAGE_T:u8 = 3;
+/// Type constant for journal header pages
+pub const JOURNAL_PAGE_T:u8 = 4;
+/// Type constant for change log pages
+pub const LOG_PAGE_T:u8 = 5;
+/// Type constant for generation map pages
+pub const GENERATION_PAGE_T:u8 = 6;
+/// Type constant for incremental backup headers
+pub const DELTA_PAGE_T:u8 = 7;
+
+/// Cipher constant for unencrypted pages
+pub const CIPHER_NONE:u8 = 0;
+/// Cipher constant for XChaCha20-Poly1305 encrypted pages
+#[allow(dead_code)]
+pub const CIPHER_XCHACHA20_POLY1305:u8 = 1;
+
+/// Layout constant for trees written before the layout was 
+/// recorded, whose directory pages hold DIR_KEY_COUNT=335 keys and
+/// no record counts.  Such trees can't be opened.
+#[allow(dead_code)]
+pub const LAYOUT_PLAIN:u8 = 0;
+/// Layout flag for directory pages with summaries (see the
+/// `aggregates` feature)
+#[allow(dead_code)]
+pub const LAYOUT_AGGREGATES:u8 = 1;
+/// Layout flag for directory and leaf pages that may be stored
+/// with compressed keys (see the `key-compression` feature)
+#[allow(dead_code)]
+pub const LAYOUT_KEY_COMPRESSION:u8 = 2;
+/// Layout flag for directory pages with the record count of each
+/// subtree (see `BPlusTree::rank`); set by every build
+pub const LAYOUT_COUNTS:u8 = 4;
+/// The page layout of this build
+pub const LAYOUT:u8 = LAYOUT_COUNTS
+  | if cfg!(feature = "aggregates")      { LAYOUT_AGGREGATES }      else { 0 }
+  | if cfg!(feature = "key-compression") { LAYOUT_KEY_COMPRESSION } else { 0 };
+
+/// A PAGE_SIZE buffer, aligned so that any page type can be 
+/// viewed in place in it (see Page::view)
+#[repr(C, align(8))]
+#[derive(Debug, Clone)]
+pub struct PageBuffer(pub [u8; PAGE_SIZE]);
+
+impl PageBuffer
+{
+  /// Encode a page into a fresh buffer
+  pub fn encode<T: Page>(page: &T) -> BPlusResult<PageBuffer>
+  {
+    let mut buffer = PageBuffer([0; PAGE_SIZE]);
+    page.encode(&mut buffer.0)?;
+    Ok(buffer)
+  }
+}
 
 /// A 'page'; a PAGE_SIZE kb-sized chunk of memory that can be
 /// written to disk.  This trait implements most of the general
@@ -60,9 +148,35 @@ pub trait Page<T = Self>
   /// Instances of this page must have the following type code
   const EXPECTED_PAGE_TYPE: u8;
 
+  /// False if the on-disk layout differs from the in-memory one,
+  /// in which case the page can't be viewed in place.
+  const VIEWABLE: bool = true;
+
   /// The type code for this page type
   fn page_type(&self) -> u8;
 
+  /// Describe what is wrong with a decoded page, if its contents
+  /// can't be trusted (e.g., it claims to hold more records than
+  /// fit in a page)
+  fn corruption(&self) -> Option<String> { None }
+
+  /// Check that a page read from `ptr` is of this type and isn't
+  /// obviously corrupt
+  fn check(&self, ptr: PagePointer) -> BPlusResult<()>
+  {
+    if self.page_type() != Self::EXPECTED_PAGE_TYPE
+    {
+      return Err(BPlusError::PageTypeMismatch {
+        page: ptr, expected: Self::EXPECTED_PAGE_TYPE, found: self.page_type()
+      })
+    }
+    match self.corruption()
+    {
+      Some(reason) => Err(BPlusError::corrupt(ptr, reason)),
+      None => Ok(())
+    }
+  }
+
   /// Decode the contents of a buffer into an instance of this
   /// page type
   fn decode(buffer: &[u8; PAGE_SIZE]) -> T
@@ -72,8 +186,31 @@ pub trait Page<T = Self>
     }
   }
 
+  /// View the contents of a buffer in place as an instance of
+  /// this page type, without copying it.
+  ///
+  /// The buffer must be suitably aligned for this page type (e.g.,
+  /// a page-sized slice of a memory map; PAGE_SIZE is a multiple 
+  /// of 8).
+  fn view(buffer: &[u8; PAGE_SIZE]) -> BPlusResult<&T>
+  {
+    if !Self::VIEWABLE || size_of::<T>() > PAGE_SIZE
+    {
+      return Err(BPlusError::Unsupported(format!("Pages of type {} can not be viewed in place", Self::EXPECTED_PAGE_TYPE)))
+    }
+    if !(buffer.as_ptr() as usize).is_multiple_of(align_of::<T>())
+    {
+      return Err(BPlusError::corrupt_page("Page buffer is not aligned for viewing in place"))
+    }
+    unsafe {
+      Ok(&*(buffer.as_ptr() as *const T))
+    }
+  }
+
   /// Encode this instance into a provided buffer.
-  fn encode(&self, buffer: &mut [u8; PAGE_SIZE])
+  ///
+  /// Fails if the page can't be stored in any on-disk layout.
+  fn encode(&self, buffer: &mut [u8; PAGE_SIZE]) -> BPlusResult<()>
   {
     let data: &[u8] = 
       unsafe {
@@ -82,47 +219,27 @@ pub trait Page<T = Self>
           size_of::<T>()
         )
       };
-    assert!(data.len() <= PAGE_SIZE);
+    if data.len() > PAGE_SIZE
+    {
+      return Err(BPlusError::corrupt_page(format!("A page of type {} takes {} bytes, but pages are {} bytes", Self::EXPECTED_PAGE_TYPE, data.len(), PAGE_SIZE)))
+    }
     buffer[..size_of::<T>()].copy_from_slice(&data);
+    Ok(())
   }
 
-  /// Read this page from a file
-  ///
-  /// **Note:** You must seek to the correct position in the
-  /// file before calling this function.
-  fn read(file: &mut File) -> Result<T, Box<dyn Error>>
+  /// Read this page from the provided storage
+  fn read(storage: &mut dyn Storage, ptr: PagePointer) -> BPlusResult<T>
   {
     let mut buffer = [0 as u8; PAGE_SIZE];
-    file.read_exact(&mut buffer)?;
+    storage.read_page(ptr, &mut buffer)?;
     Ok(Self::decode(&buffer))
   }
 
-  /// Write this page to a file
-  ///
-  /// **Note:** You must seek to the correct position in the
-  /// file before calling this function.
-  fn write(&self, file: &mut File) -> Result<(), Box<dyn Error>>
+  /// Write this page to the provided storage
+  fn write(&self, storage: &mut dyn Storage, ptr: PagePointer) -> BPlusResult<()>
   {
     let mut buffer = [0 as u8; PAGE_SIZE];
-    self.encode(&mut buffer);
-    file.write_all(&buffer)?;
-    Ok(())
-  }
-}
-
-#[derive(Debug)]
-pub struct PageIsFullError
-{
-}
-
-impl fmt::Display for PageIsFullError
-{
-  fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
-    write!(f, "Page is full!")
+    self.encode(&mut buffer)?;
+    storage.write_page(ptr, &buffer)
   }
 }
-
-impl Error for PageIsFullError
-{
-  fn source(&self) -> Option<&(dyn Error + 'static)> { None }
-}
\ No newline at end of file
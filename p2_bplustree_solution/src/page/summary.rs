@@ -0,0 +1,89 @@
+#[cfg(all(feature = "aggregates", feature = "key-compression"))]
+use super::codec;
+
+/// A summary of the values in a subtree, kept alongside each
+/// directory pointer (see `BPlusTree::aggregate`)
+///
+/// Only kept with the `aggregates` feature.  Without it, a Summary
+/// is empty, takes up no space in directory pages, and costs 
+/// nothing to compute.
+#[cfg(feature = "aggregates")]
+#[repr(C)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Summary
+{
+  /// The sum of the values
+  pub sum: u64,
+  /// The least value, or u32::MAX if there are none
+  pub min: u32,
+  /// The greatest value, or 0 if there are none
+  pub max: u32,
+}
+
+#[cfg(not(feature = "aggregates"))]
+#[repr(C)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Summary;
+
+#[allow(dead_code)]
+impl Summary
+{
+  /// True if summaries are actually kept
+  pub const KEPT: bool = cfg!(feature = "aggregates");
+
+  /// The summary of no values at all
+  #[cfg(feature = "aggregates")]
+  pub const EMPTY: Summary = Summary { sum: 0, min: u32::MAX, max: 0 };
+  #[cfg(not(feature = "aggregates"))]
+  pub const EMPTY: Summary = Summary;
+
+  /// The summary of the provided values
+  #[cfg(feature = "aggregates")]
+  pub fn of(values: impl Iterator<Item = u32>) -> Summary
+  {
+    values.fold(Summary::EMPTY, |s, v| Summary { 
+      sum: s.sum.wrapping_add(v as u64), 
+      min: s.min.min(v), 
+      max: s.max.max(v) 
+    })
+  }
+  #[cfg(not(feature = "aggregates"))]
+  pub fn of(_values: impl Iterator<Item = u32>) -> Summary
+  {
+    Summary
+  }
+
+  /// The summary of the values in both this summary and `other`
+  #[cfg(feature = "aggregates")]
+  pub fn combine(&self, other: &Summary) -> Summary
+  {
+    Summary {
+      sum: self.sum.wrapping_add(other.sum),
+      min: self.min.min(other.min),
+      max: self.max.max(other.max),
+    }
+  }
+  #[cfg(not(feature = "aggregates"))]
+  pub fn combine(&self, _other: &Summary) -> Summary
+  {
+    Summary
+  }
+}
+
+#[cfg(all(feature = "aggregates", feature = "key-compression"))]
+impl Summary
+{
+  /// Read a summary from a packed page (see codec.rs)
+  pub fn read(r: &mut codec::Reader) -> Summary
+  {
+    Summary { sum: r.u64(), min: r.u32(), max: r.u32() }
+  }
+
+  /// Write a summary to a packed page (see codec.rs)
+  pub fn write(&self, w: &mut codec::Writer)
+  {
+    w.u64(self.sum);
+    w.u32(self.min);
+    w.u32(self.max);
+  }
+}
@@ -0,0 +1,256 @@
+use std::error::Error;
+use std::io::{ BufRead, Write };
+
+use super::bplus_tree::BPlusTree;
+use super::error::BPlusResult;
+use super::export::{ self, ExportOptions };
+use super::page::PagePointer;
+use super::page::{ LeafPage, DirectoryPage, MetadataPage, FreePage };
+use super::page::{ META_PAGE_T, DIR_PAGE_T, LEAF_PAGE_T, FREE_PAGE_T };
+
+const HELP: &str = "\
+Commands:
+  put K V        Insert or update key K with value V
+  del K          Delete key K
+  get K          Look up key K
+  range A B      List the records with keys in [A, B)
+  tree           Print every page in the tree
+  page N         Print page N
+  dot [depth=D] [A..B]
+                 Export the tree structure in Graphviz DOT format,
+                 optionally cut off at depth D or to keys in [A, B)
+  json [depth=D] [A..B]
+                 Export the tree structure as JSON
+  check          Sanity check the tree
+  sync           Make every write so far durable
+  autocheck on|off
+                 Check the tree after every put/del
+  history        List previously entered commands
+  !N             Re-run command N from the history
+  help           Print this message
+  quit           Exit the shell";
+
+/// An interactive shell over an open BPlusTree
+///
+/// Commands are read one line at a time from the input and the
+/// results are written to the output.  Every command entered
+/// (other than `history` and `!N`) is recorded in the history.
+pub struct Repl<'a>
+{
+  tree: &'a mut BPlusTree,
+  history: Vec<String>,
+
+  /// If set, every mutation is followed by a call to check_tree
+  pub auto_check: bool,
+}
+
+/// What the shell should do after running a command
+#[derive(Debug, PartialEq, Eq)]
+enum Next
+{
+  Continue,
+  Quit,
+}
+
+/// The result of a command; bad input is reported alongside tree
+/// errors
+type CommandResult<T> = Result<T, Box<dyn Error>>;
+
+fn parse_u32(arg: Option<&str>) -> Result<u32, String>
+{
+  match arg
+  {
+    Some(arg) => arg.parse::<u32>().map_err(|_| format!("Not a key/value: {}", arg)),
+    None => Err("Missing argument".to_string())
+  }
+}
+
+#[allow(dead_code)]
+impl<'a> Repl<'a>
+{
+  pub fn new(tree: &'a mut BPlusTree) -> Repl<'a>
+  {
+    Repl { tree, history: Vec::new(), auto_check: false }
+  }
+
+  /// The commands entered so far
+  pub fn history(&self) -> &[String]
+  {
+    &self.history
+  }
+
+  /// Read and evaluate commands until the input is exhausted or
+  /// a `quit` command is entered.
+  pub fn run(&mut self, input: &mut dyn BufRead, out: &mut dyn Write) -> BPlusResult<()>
+  {
+    let mut line = String::new();
+    loop
+    {
+      write!(out, "bplus> ")?;
+      out.flush()?;
+      line.clear();
+      if input.read_line(&mut line)? == 0 { writeln!(out)?; return Ok(()) }
+      if self.eval(line.trim(), out)? == Next::Quit { return Ok(()) }
+    }
+  }
+
+  /// Evaluate a single command line, recording it in the history
+  ///
+  /// Errors from the tree are reported to the output rather than
+  /// returned, so that a bad command doesn't end the session.
+  /// Only failures to write to `out` are returned.
+  pub fn exec(&mut self, line: &str, out: &mut dyn Write) -> BPlusResult<()>
+  {
+    self.eval(line.trim(), out)?;
+    Ok(())
+  }
+
+  fn eval(&mut self, line: &str, out: &mut dyn Write) -> BPlusResult<Next>
+  {
+    if line.is_empty() { return Ok(Next::Continue) }
+
+    if line == "history"
+    {
+      for (i, cmd) in self.history.iter().enumerate()
+      {
+        writeln!(out, "{:>4}  {}", i+1, cmd)?;
+      }
+      return Ok(Next::Continue)
+    }
+
+    let line =
+      if let Some(n) = line.strip_prefix('!')
+      {
+        match n.parse::<usize>().ok().and_then(|n| n.checked_sub(1)).and_then(|n| self.history.get(n))
+        {
+          Some(cmd) => { writeln!(out, "{}", cmd)?; cmd.clone() }
+          None => { writeln!(out, "No such command in history: {}", n)?; return Ok(Next::Continue) }
+        }
+      } else { line.to_string() };
+    self.history.push(line.clone());
+
+    match self.dispatch(&line, out)
+    {
+      Ok(next) => Ok(next),
+      Err(err) => { writeln!(out, "Error: {}", err)?; Ok(Next::Continue) }
+    }
+  }
+
+  fn dispatch(&mut self, line: &str, out: &mut dyn Write) -> CommandResult<Next>
+  {
+    let mut args = line.split_whitespace();
+    let cmd = args.next().unwrap_or("");
+    let mut mutated = false;
+
+    match cmd
+    {
+      "put" =>
+      {
+        let key = parse_u32(args.next())?;
+        let value = parse_u32(args.next())?;
+        self.tree.put(key, value)?;
+        mutated = true;
+      }
+      "del" =>
+      {
+        let key = parse_u32(args.next())?;
+        self.tree.delete(key)?;
+        mutated = true;
+      }
+      "get" =>
+      {
+        let key = parse_u32(args.next())?;
+        match self.tree.get(key)?
+        {
+          Some(value) => writeln!(out, "{}", value)?,
+          None        => writeln!(out, "(not found)")?,
+        }
+      }
+      "range" =>
+      {
+        let low = parse_u32(args.next())?;
+        let high = parse_u32(args.next())?;
+        let mut count = 0;
+        for record in self.tree.range(low..high)?
+        {
+          let (key, value) = record?;
+          writeln!(out, "{} -> {}", key, value)?;
+          count += 1;
+        }
+        writeln!(out, "({} records)", count)?;
+      }
+      "tree" => self.tree.write_tree(out)?,
+      "page" =>
+      {
+        let ptr = parse_u32(args.next())? as PagePointer;
+        self.write_page(ptr, out)?;
+      }
+      "check" => self.check(out)?,
+      "sync" => self.tree.sync()?,
+      "dot" | "json" =>
+      {
+        let mut opts = ExportOptions::default();
+        for arg in args
+        {
+          if let Some(depth) = arg.strip_prefix("depth=")
+          {
+            opts = opts.with_max_depth(depth.parse().map_err(|_| format!("Not a depth: {}", depth))?);
+          }
+          else if let Some((low, high)) = arg.split_once("..")
+          {
+            opts = opts.with_keys(parse_u32(Some(low))? .. parse_u32(Some(high))?);
+          }
+          else
+          {
+            return Err(format!("Unexpected argument: {}", arg).into())
+          }
+        }
+        if cmd == "dot" { export::to_dot(self.tree, &opts, out)?; }
+        else            { export::to_json(self.tree, &opts, out)?; }
+      }
+      "autocheck" =>
+      {
+        match args.next()
+        {
+          Some("on")  => self.auto_check = true,
+          Some("off") => self.auto_check = false,
+          _           => return Err("Expected 'autocheck on' or 'autocheck off'".into())
+        }
+      }
+      "help" => writeln!(out, "{}", HELP)?,
+      "quit" | "exit" => return Ok(Next::Quit),
+      _ => return Err(format!("Unknown command '{}' (try 'help')", cmd).into())
+    }
+
+    if mutated && self.auto_check { self.check(out)?; }
+    Ok(Next::Continue)
+  }
+
+  fn check(&mut self, out: &mut dyn Write) -> BPlusResult<()>
+  {
+    match self.tree.check_tree()?
+    {
+      None => writeln!(out, "ok")?,
+      Some(err) =>
+      {
+        writeln!(out, "Error in tree: {}", err)?;
+        self.tree.write_tree(out)?;
+      }
+    }
+    Ok(())
+  }
+
+  fn write_page(&mut self, ptr: PagePointer, out: &mut dyn Write) -> BPlusResult<()>
+  {
+    match self.tree.page_type(ptr)?
+    {
+      META_PAGE_T => writeln!(out, "PAGE[{}] = {:?}", ptr, self.tree.get_page::<MetadataPage>(ptr)?)?,
+      DIR_PAGE_T  => writeln!(out, "PAGE[{}] = {:?}", ptr, self.tree.get_page::<DirectoryPage>(ptr)?)?,
+      LEAF_PAGE_T => writeln!(out, "PAGE[{}] = {:?}", ptr, self.tree.get_page::<LeafPage>(ptr)?)?,
+      FREE_PAGE_T => writeln!(out, "PAGE[{}] = {:?}", ptr, self.tree.get_page::<FreePage>(ptr)?)?,
+      t           => writeln!(out, "PAGE[{}] has unknown page type {}", ptr, t)?,
+    }
+    Ok(())
+  }
+}
+
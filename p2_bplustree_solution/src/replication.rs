@@ -0,0 +1,41 @@
+use std::fmt::Debug;
+use std::io;
+use std::sync::mpsc::Sender;
+
+use super::error::{ BPlusError, BPlusResult };
+use super::page::{ PagePointer, PageBuffer };
+
+/// One batch of page writes committed by a leader tree, for its
+/// followers to apply (see `BPlusTree::set_replica`)
+#[derive(Debug, Clone)]
+pub struct PageBatch
+{
+  /// The leader's commit number for this batch.  Followers apply
+  /// batches in commit order, without gaps.
+  pub commit: u64,
+  /// The generation the pages were written in (see 
+  /// `BPlusTree::backup_incremental`)
+  pub generation: u32,
+  /// The pages written, other than the metadata page
+  pub pages: Vec<(PagePointer, PageBuffer)>,
+  /// The leader's metadata page as of this batch
+  pub meta: PageBuffer,
+}
+
+/// Where a leader tree sends the batches it commits
+pub trait Replica: Debug
+{
+  /// Send a committed batch on to the followers
+  fn send(&mut self, batch: &PageBatch) -> BPlusResult<()>;
+}
+
+/// Batches are sent to a follower over a channel, e.g., to one
+/// on another thread (see `BPlusTree::catch_up`)
+impl Replica for Sender<PageBatch>
+{
+  fn send(&mut self, batch: &PageBatch) -> BPlusResult<()>
+  {
+    Sender::send(self, batch.clone())
+      .map_err(|_| BPlusError::Io(io::Error::new(io::ErrorKind::BrokenPipe, "The follower has gone away")))
+  }
+}
@@ -0,0 +1,385 @@
+use std::collections::BTreeMap;
+
+use crate::error::{ BPlusError, BPlusResult };
+use crate::page::{ PagePointer, DIR_PAGE_T, PAGE_SIZE };
+use super::{ check_buffer, Storage };
+
+/// Identifies the header block of a compressed storage
+const MAGIC: u64 = 0x4250_4c5a_3401_0000;
+
+/// The number of bytes in one table entry
+const ENTRY_SIZE: usize = 16;
+
+/// How the bytes of an extent encode a page
+const UNWRITTEN: u8 = 0;  // Never written; reads as zeros
+const RAW: u8       = 1;  // Stored as-is
+const LZ4: u8       = 2;  // LZ4 block compressed
+
+/// Where a page's bytes live in the inner storage
+#[derive(Debug, Clone, Copy, Default)]
+struct Extent
+{
+  /// The first block of the extent
+  start: u64,
+  /// The number of bytes stored, starting at `start`
+  len: u32,
+  /// How the bytes encode the page (UNWRITTEN, RAW, or LZ4)
+  codec: u8,
+}
+
+impl Extent
+{
+  fn blocks(&self) -> u64
+  {
+    blocks_for(self.len as usize)
+  }
+}
+
+fn blocks_for(bytes: usize) -> u64
+{
+  bytes.div_ceil(CompressedStorage::BLOCK_SIZE) as u64
+}
+
+/// Pages stored in variable-sized extents of an inner storage,
+/// with leaf (and free) pages LZ4 compressed
+///
+/// The inner storage is addressed in BLOCK_SIZE blocks.  A page
+/// mapping table records the extent holding each page, in place of
+/// the fixed `idx * PAGE_SIZE` addressing of FileStorage.  Block 0
+/// is a header pointing to the table as of the last `sync`:
+/// ```
+/// [ magic: u64 | table start: u64 | table entries: u64 ]
+/// ```
+/// Directory pages are read on every lookup, and are stored as-is.
+/// So is any other page whose compressed size doesn't fit in fewer
+/// blocks.
+///
+/// The table is only written on `sync` (and when the storage is
+/// dropped), and a rewritten page may move in the meantime.  As
+/// with FileStorage, a crash between syncs may leave pages torn;
+/// the tree's journal repairs them.
+#[derive(Debug)]
+pub struct CompressedStorage
+{
+  inner: Box<dyn Storage>,
+
+  /// The extent of every page, indexed by PagePointer
+  table: Vec<Extent>,
+  /// The blocks holding the table as of the last sync
+  table_extent: Extent,
+  /// True if `table` has changes that haven't been written yet
+  dirty: bool,
+
+  /// Unused runs of blocks, as start -> length
+  free: BTreeMap<u64, u64>,
+  /// The first block past every extent in use
+  end: u64,
+}
+
+#[allow(dead_code)]
+impl CompressedStorage
+{
+  /// The number of bytes in each block of the inner storage
+  pub const BLOCK_SIZE: usize = 512;
+
+  /// Use the provided storage for compressed pages, discarding
+  /// anything in it.
+  pub fn init(inner: Box<dyn Storage>) -> BPlusResult<CompressedStorage>
+  {
+    let mut ret = CompressedStorage::new(inner)?;
+    ret.dirty = true;
+    ret.sync()?;
+    Ok(ret)
+  }
+
+  /// Open compressed pages previously written to the provided
+  /// storage.
+  pub fn open(inner: Box<dyn Storage>) -> BPlusResult<CompressedStorage>
+  {
+    let mut ret = CompressedStorage::new(inner)?;
+
+    let mut header = [0u8; CompressedStorage::BLOCK_SIZE];
+    ret.inner.read_page(0, &mut header)?;
+    let field = |i: usize| u64::from_le_bytes(header[i*8 .. (i+1)*8].try_into().unwrap());
+    if field(0) != MAGIC
+    {
+      return Err(BPlusError::IncompatibleStorage("Storage does not hold compressed pages".to_string()))
+    }
+    let entries = field(2) as usize;
+    ret.table_extent = Extent { start: field(1), len: (entries * ENTRY_SIZE) as u32, codec: RAW };
+
+    let bytes = ret.read_extent(&ret.table_extent.clone())?;
+    ret.table =
+      bytes.chunks_exact(ENTRY_SIZE)
+           .take(entries)
+           .map(|e| Extent {
+             start: u64::from_le_bytes(e[0..8].try_into().unwrap()),
+             len:   u32::from_le_bytes(e[8..12].try_into().unwrap()),
+             codec: e[12],
+           })
+           .collect();
+
+    // Every block that isn't the header, the table, or a page
+    // is free.
+    let mut used: Vec<(u64, u64)> =
+      ret.table.iter()
+         .filter(|e| e.codec != UNWRITTEN)
+         .map(|e| (e.start, e.blocks()))
+         .collect();
+    used.push((0, 1));
+    used.push((ret.table_extent.start, ret.table_extent.blocks()));
+    used.sort();
+    for (start, blocks) in used
+    {
+      if start > ret.end { ret.free.insert(ret.end, start - ret.end); }
+      ret.end = ret.end.max(start + blocks);
+    }
+    Ok(ret)
+  }
+
+  fn new(inner: Box<dyn Storage>) -> BPlusResult<CompressedStorage>
+  {
+    if inner.page_size() != CompressedStorage::BLOCK_SIZE
+    {
+      return Err(BPlusError::IncompatibleStorage(format!("Compressed pages need storage with {} byte blocks, not {}",
+                         CompressedStorage::BLOCK_SIZE, inner.page_size())))
+    }
+    Ok(CompressedStorage {
+      inner,
+      table: Vec::new(),
+      table_extent: Extent { start: 1, len: 0, codec: RAW },
+      dirty: false,
+      free: BTreeMap::new(),
+      end: 1,
+    })
+  }
+
+  /// Find room for an extent of the provided number of blocks
+  fn alloc_blocks(&mut self, blocks: u64) -> u64
+  {
+    match self.take_free(blocks, u64::MAX)
+    {
+      Some(start) => start,
+      None =>
+      {
+        self.end += blocks;
+        self.end - blocks
+      }
+    }
+  }
+
+  /// Take the first free run of the provided number of blocks that
+  /// starts before `limit`, if there is one
+  fn take_free(&mut self, blocks: u64, limit: u64) -> Option<u64>
+  {
+    let (start, len) = 
+      self.free.range(.. limit).find(|(_, len)| **len >= blocks).map(|(s, l)| (*s, *l))?;
+    self.free.remove(&start);
+    if len > blocks { self.free.insert(start + blocks, len - blocks); }
+    Some(start)
+  }
+
+  /// Return an extent's blocks to the free list
+  fn release_blocks(&mut self, start: u64, mut blocks: u64)
+  {
+    if blocks == 0 { return }
+    let mut start = start;
+    // Coalesce with the neighbors on either side
+    if let Some((&prev, &len)) = self.free.range(..start).next_back()
+    {
+      if prev + len == start { self.free.remove(&prev); start = prev; blocks += len; }
+    }
+    if let Some(len) = self.free.remove(&(start + blocks))
+    {
+      blocks += len;
+    }
+    if start + blocks == self.end { self.end = start; }
+    else                          { self.free.insert(start, blocks); }
+  }
+
+  fn read_extent(&mut self, extent: &Extent) -> BPlusResult<Vec<u8>>
+  {
+    let mut bytes = vec![0u8; extent.blocks() as usize * CompressedStorage::BLOCK_SIZE];
+    for (i, block) in bytes.chunks_exact_mut(CompressedStorage::BLOCK_SIZE).enumerate()
+    {
+      self.inner.read_page(extent.start + i as u64, block)?;
+    }
+    bytes.truncate(extent.len as usize);
+    Ok(bytes)
+  }
+
+  fn write_extent(&mut self, start: u64, bytes: &[u8]) -> BPlusResult<()>
+  {
+    let mut block = [0u8; CompressedStorage::BLOCK_SIZE];
+    for (i, chunk) in bytes.chunks(CompressedStorage::BLOCK_SIZE).enumerate()
+    {
+      block[..chunk.len()].copy_from_slice(chunk);
+      block[chunk.len()..].fill(0);
+      self.inner.write_page(start + i as u64, &block)?;
+    }
+    Ok(())
+  }
+
+  /// Write the page table to fresh blocks, and then point the
+  /// header at it.
+  fn write_table(&mut self) -> BPlusResult<()>
+  {
+    let mut bytes = vec![0u8; self.table.len() * ENTRY_SIZE];
+    for (entry, extent) in bytes.chunks_exact_mut(ENTRY_SIZE).zip(self.table.iter())
+    {
+      entry[0..8].copy_from_slice(&extent.start.to_le_bytes());
+      entry[8..12].copy_from_slice(&extent.len.to_le_bytes());
+      entry[12] = extent.codec;
+    }
+    let old = self.table_extent;
+    let start = self.alloc_blocks(blocks_for(bytes.len()));
+    self.write_extent(start, &bytes)?;
+    self.inner.sync()?;
+
+    let mut header = [0u8; CompressedStorage::BLOCK_SIZE];
+    header[0..8].copy_from_slice(&MAGIC.to_le_bytes());
+    header[8..16].copy_from_slice(&start.to_le_bytes());
+    header[16..24].copy_from_slice(&(self.table.len() as u64).to_le_bytes());
+    self.inner.write_page(0, &header)?;
+    self.inner.sync()?;
+
+    // The old table is unreachable now that the header is durable
+    self.table_extent = Extent { start, len: bytes.len() as u32, codec: RAW };
+    self.release_blocks(old.start, old.blocks());
+    self.dirty = false;
+    Ok(())
+  }
+}
+
+impl Storage for CompressedStorage
+{
+  fn read_page(&mut self, ptr: PagePointer, buffer: &mut [u8]) -> BPlusResult<()>
+  {
+    check_buffer(self, buffer)?;
+    let extent = match self.table.get(ptr as usize)
+    {
+      Some(extent) => *extent,
+      None => return Err(BPlusError::PageOutOfBounds { page: ptr, len: self.len() })
+    };
+    match extent.codec
+    {
+      UNWRITTEN => buffer.fill(0),
+      RAW => buffer.copy_from_slice(&self.read_extent(&extent)?),
+      LZ4 =>
+      {
+        let len = lz4_flex::block::decompress_into(&self.read_extent(&extent)?, buffer)
+                    .map_err(|err| BPlusError::corrupt(ptr, err.to_string()))?;
+        if len != PAGE_SIZE
+        {
+          return Err(BPlusError::corrupt(ptr, format!("Decompressed to {} bytes", len)))
+        }
+      }
+      codec => return Err(BPlusError::corrupt(ptr, format!("Unknown encoding {}", codec)))
+    }
+    Ok(())
+  }
+
+  fn write_page(&mut self, ptr: PagePointer, buffer: &[u8]) -> BPlusResult<()>
+  {
+    check_buffer(self, buffer)?;
+    self.allocate(ptr+1)?;
+
+    let mut compressed = Vec::new();
+    if buffer[0] != DIR_PAGE_T
+    {
+      compressed.resize(lz4_flex::block::get_maximum_output_size(PAGE_SIZE), 0);
+      let len = lz4_flex::block::compress_into(buffer, &mut compressed)
+                  .map_err(|err| BPlusError::Io(std::io::Error::other(err)))?;
+      compressed.truncate(len);
+    }
+    let (codec, bytes) =
+      if !compressed.is_empty() && blocks_for(compressed.len()) < blocks_for(PAGE_SIZE)
+           { (LZ4, compressed.as_slice()) }
+      else { (RAW, buffer) };
+
+    // Rewrite the page in place if it still needs as many blocks
+    let old = self.table[ptr as usize];
+    let start =
+      if old.codec != UNWRITTEN && old.blocks() == blocks_for(bytes.len()) { old.start }
+      else
+      {
+        self.release_blocks(old.start, if old.codec == UNWRITTEN { 0 } else { old.blocks() });
+        self.alloc_blocks(blocks_for(bytes.len()))
+      };
+    self.write_extent(start, bytes)?;
+    self.table[ptr as usize] = Extent { start, len: bytes.len() as u32, codec };
+    self.dirty = true;
+    Ok(())
+  }
+
+  fn allocate(&mut self, pages: PagePointer) -> BPlusResult<()>
+  {
+    if (self.table.len() as PagePointer) < pages
+    {
+      self.table.resize(pages as usize, Extent::default());
+      self.dirty = true;
+    }
+    Ok(())
+  }
+
+  /// The extents of the discarded pages are freed, and the pages
+  /// that are left are moved into the lowest free blocks they fit
+  /// in, so that the inner storage can shrink.  The table is 
+  /// written right away, before the blocks that the moved pages 
+  /// leave behind can be reused.
+  fn truncate(&mut self, pages: PagePointer) -> BPlusResult<()>
+  {
+    if self.len() <= pages { return Ok(()) }
+    for extent in self.table.split_off(pages as usize)
+    {
+      if extent.codec != UNWRITTEN { self.release_blocks(extent.start, extent.blocks()); }
+    }
+    let mut moved = Vec::new();
+    for ptr in 0 .. self.table.len()
+    {
+      let extent = self.table[ptr];
+      if extent.codec == UNWRITTEN { continue }
+      let Some(start) = self.take_free(extent.blocks(), extent.start) else { continue };
+      let bytes = self.read_extent(&extent)?;
+      self.write_extent(start, &bytes)?;
+      self.table[ptr].start = start;
+      moved.push(extent);
+    }
+    self.write_table()?;
+    for extent in moved
+    {
+      self.release_blocks(extent.start, extent.blocks());
+    }
+    self.inner.truncate(self.end)
+  }
+
+  fn sync(&mut self) -> BPlusResult<()>
+  {
+    if self.dirty 
+    { 
+      self.write_table()?;
+      // Nothing the header points to is past the last extent
+      self.inner.truncate(self.end)?;
+    }
+    self.inner.sync()
+  }
+
+  fn len(&self) -> PagePointer
+  {
+    self.table.len() as PagePointer
+  }
+}
+
+impl Drop for CompressedStorage
+{
+  fn drop(&mut self)
+  {
+    if self.dirty
+    {
+      if let Err(err) = self.write_table()
+      {
+        eprintln!("Error writing the page table: {}", err);
+      }
+    }
+  }
+}
@@ -0,0 +1,221 @@
+use std::fmt;
+
+use chacha20poly1305::{ XChaCha20Poly1305, XNonce, Key, Tag, KeyInit, AeadInPlace };
+
+use crate::error::{ BPlusError, BPlusResult };
+use crate::page::{ Page, PagePointer, MetadataPage, CIPHER_XCHACHA20_POLY1305, METADATA_IDX, PAGE_SIZE };
+use super::{ check_buffer, Storage };
+
+/// The number of bytes of nonce and tag stored after each page
+const OVERHEAD: usize = 32;
+
+/// Associated data for the key check value
+const KEY_CHECK_AD: &[u8] = b"bplus_tree key check";
+
+/// Pages stored encrypted with XChaCha20-Poly1305
+///
+/// Each page is stored in a PAGE_SIZE + 32 byte page of the inner
+/// storage:
+/// ```
+/// [ ciphertext: PAGE_SIZE | counter: u64 | session: u64 | tag: 16 bytes ]
+/// ```
+/// The nonce is the page pointer, a counter bumped on every write,
+/// and a random session id picked every time the storage is opened
+/// (so that counters can restart at 0 without reusing a nonce).  The
+/// page pointer is also authenticated, so pages can't be swapped.
+/// Every page of the inner storage is written through the cipher,
+/// even when it is only allocated, so a counter of 0 always means
+/// the page was tampered with.
+///
+/// In a tree's own storage (see `for_tree`), the metadata page is 
+/// stored in the clear, but still authenticated, so that the 
+/// cipher and key check recorded in it can be read before the key
+/// is known to be right.  Every other page, including page 0 of 
+/// any other storage (e.g., the journal header), is encrypted.
+pub struct EncryptedStorage
+{
+  inner: Box<dyn Storage>,
+  aead: XChaCha20Poly1305,
+  session: u64,
+  counter: u64,
+  /// True if the metadata page is stored in the clear
+  clear_metadata: bool,
+  /// The tag of an empty message under a fixed nonce; only someone
+  /// holding the key can produce it.
+  key_check: [u8; 16],
+}
+
+#[allow(dead_code)]
+impl EncryptedStorage
+{
+  /// The number of bytes in each page of the inner storage
+  pub const INNER_PAGE_SIZE: usize = PAGE_SIZE + OVERHEAD;
+
+  /// Encrypt every page with the provided key on its way to the 
+  /// inner storage
+  pub fn new(inner: Box<dyn Storage>, key: &[u8; 32]) -> BPlusResult<EncryptedStorage>
+  {
+    if inner.page_size() != EncryptedStorage::INNER_PAGE_SIZE
+    {
+      return Err(BPlusError::IncompatibleStorage(format!("Encrypted pages need storage with {} byte pages, not {}",
+                         EncryptedStorage::INNER_PAGE_SIZE, inner.page_size())))
+    }
+    let aead = XChaCha20Poly1305::new(Key::from_slice(key));
+    let key_check = aead.encrypt_in_place_detached(&XNonce::default(), KEY_CHECK_AD, &mut [])
+                        .map_err(|_| BPlusError::Io(std::io::Error::other("Failed to compute the key check")))?;
+    Ok(EncryptedStorage {
+      inner,
+      aead,
+      session: rand::random(),
+      counter: 0,
+      clear_metadata: false,
+      key_check: key_check.into(),
+    })
+  }
+
+  /// Like `new`, for the storage of a tree, whose metadata page is
+  /// kept in the clear
+  pub fn for_tree(inner: Box<dyn Storage>, key: &[u8; 32]) -> BPlusResult<EncryptedStorage>
+  {
+    let mut ret = EncryptedStorage::new(inner, key)?;
+    ret.clear_metadata = true;
+    Ok(ret)
+  }
+
+  /// Open a tree's encrypted pages, checking the key against the
+  /// one recorded in the tree's metadata page.
+  pub fn open(inner: Box<dyn Storage>, key: &[u8; 32]) -> BPlusResult<EncryptedStorage>
+  {
+    let mut ret = EncryptedStorage::for_tree(inner, key)?;
+    let mut buffer = [0u8; EncryptedStorage::INNER_PAGE_SIZE];
+    ret.inner.read_page(METADATA_IDX, &mut buffer)?;
+    let meta = MetadataPage::decode(buffer.first_chunk().unwrap());
+    if meta.cipher != CIPHER_XCHACHA20_POLY1305
+    {
+      return Err(BPlusError::IncompatibleStorage(format!("Tree is not encrypted with XChaCha20-Poly1305 (cipher {})", meta.cipher)))
+    }
+    if meta.key_check != ret.key_check()
+    {
+      return Err(BPlusError::WrongKey)
+    }
+    Ok(ret)
+  }
+
+  fn nonce(ptr: PagePointer, counter: u64, session: u64) -> XNonce
+  {
+    let mut nonce = XNonce::default();
+    nonce[0..8].copy_from_slice(&ptr.to_le_bytes());
+    nonce[8..16].copy_from_slice(&counter.to_le_bytes());
+    nonce[16..24].copy_from_slice(&session.to_le_bytes());
+    nonce
+  }
+}
+
+// Not derived, to keep the key out of debug output
+impl fmt::Debug for EncryptedStorage
+{
+  fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result
+  {
+    f.debug_struct("EncryptedStorage")
+     .field("inner", &self.inner)
+     .field("session", &self.session)
+     .field("counter", &self.counter)
+     .finish_non_exhaustive()
+  }
+}
+
+impl Storage for EncryptedStorage
+{
+  fn read_page(&mut self, ptr: PagePointer, buffer: &mut [u8]) -> BPlusResult<()>
+  {
+    check_buffer(self, buffer)?;
+    let mut stored = [0u8; EncryptedStorage::INNER_PAGE_SIZE];
+    self.inner.read_page(ptr, &mut stored)?;
+    let (data, trailer) = stored.split_at_mut(PAGE_SIZE);
+    let counter = u64::from_le_bytes(trailer[0..8].try_into().unwrap());
+    let session = u64::from_le_bytes(trailer[8..16].try_into().unwrap());
+    if counter == 0
+    {
+      // Even allocated pages are written, so this one was zeroed
+      return Err(BPlusError::corrupt(ptr, "Failed authentication (page was never written, or tampered with)"))
+    }
+
+    let nonce = EncryptedStorage::nonce(ptr, counter, session);
+    let tag = Tag::from_slice(&trailer[16..32]);
+    let ad = ptr.to_le_bytes();
+    let ok =
+      if ptr == METADATA_IDX && self.clear_metadata
+      {
+        let ad = [ad.as_slice(), data].concat();
+        self.aead.decrypt_in_place_detached(&nonce, &ad, &mut [], tag).is_ok()
+      }
+      else
+      {
+        self.aead.decrypt_in_place_detached(&nonce, &ad, data, tag).is_ok()
+      };
+    if !ok
+    {
+      return Err(BPlusError::corrupt(ptr, "Failed authentication (wrong key, or tampered with)"))
+    }
+    buffer.copy_from_slice(data);
+    Ok(())
+  }
+
+  fn write_page(&mut self, ptr: PagePointer, buffer: &[u8]) -> BPlusResult<()>
+  {
+    check_buffer(self, buffer)?;
+    // Don't let the inner storage fill a gap with unwritten pages
+    if ptr > self.inner.len() { self.allocate(ptr)?; }
+    self.counter += 1;
+    let nonce = EncryptedStorage::nonce(ptr, self.counter, self.session);
+
+    let mut stored = [0u8; EncryptedStorage::INNER_PAGE_SIZE];
+    let (data, trailer) = stored.split_at_mut(PAGE_SIZE);
+    data.copy_from_slice(buffer);
+    let ad = ptr.to_le_bytes();
+    let tag =
+      if ptr == METADATA_IDX && self.clear_metadata
+      {
+        let ad = [ad.as_slice(), data].concat();
+        self.aead.encrypt_in_place_detached(&nonce, &ad, &mut [])
+      }
+      else
+      {
+        self.aead.encrypt_in_place_detached(&nonce, &ad, data)
+      }.map_err(|_| BPlusError::Io(std::io::Error::other(format!("Failed to encrypt page {}", ptr))))?;
+    trailer[0..8].copy_from_slice(&self.counter.to_le_bytes());
+    trailer[8..16].copy_from_slice(&self.session.to_le_bytes());
+    trailer[16..32].copy_from_slice(&tag);
+    self.inner.write_page(ptr, &stored)
+  }
+
+  /// New pages are written as encrypted zeroes, so that they are
+  /// authenticated like any other
+  fn allocate(&mut self, pages: PagePointer) -> BPlusResult<()>
+  {
+    for ptr in self.inner.len() .. pages
+    {
+      self.write_page(ptr, &[0; PAGE_SIZE])?;
+    }
+    Ok(())
+  }
+
+  fn sync(&mut self) -> BPlusResult<()>
+  {
+    self.inner.sync()
+  }
+
+  fn truncate(&mut self, pages: PagePointer) -> BPlusResult<()>
+  {
+    self.inner.truncate(pages)
+  }
+
+  fn len(&self) -> PagePointer
+  {
+    self.inner.len()
+  }
+
+  fn cipher(&self) -> u8 { CIPHER_XCHACHA20_POLY1305 }
+
+  fn key_check(&self) -> [u8; 16] { self.key_check }
+}
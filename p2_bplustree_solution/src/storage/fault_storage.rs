@@ -0,0 +1,204 @@
+use std::cell::RefCell;
+use std::collections::BTreeMap;
+use std::io;
+use std::rc::Rc;
+
+use crate::error::{ BPlusError, BPlusResult };
+use crate::page::{ PagePointer, PAGE_SIZE };
+use super::{ check_buffer, MemStorage, Storage };
+
+/// What becomes of the write that a crash interrupts (a sync that
+/// a crash interrupts always fails)
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Fault
+{
+  /// The write never reaches the disk
+  Fail,
+  /// Only the first half of the page reaches the disk, and the
+  /// rest is zeroed, as if the file were cut short.  A page that
+  /// would have grown the file is lost, and the file ends before it.
+  Truncate,
+  /// Only the first half of the page reaches the disk, and the
+  /// rest holds what it did before
+  Tear,
+}
+
+#[derive(Debug)]
+struct Disk
+{
+  /// Writes and syncs since the disk was last armed
+  writes: u64,
+  /// The write or sync to crash at, and what becomes of it
+  crash: Option<(u64, Fault)>,
+  /// If true, writes that haven't been synced are lost in a crash
+  drop_unsynced: bool,
+  crashed: bool,
+}
+
+/// A simulated disk that crashes at a chosen write or sync, shared
+/// by the FaultStorages on it (e.g., a tree and its journal), so
+/// that they crash together
+///
+/// Each file's `durable` storage holds what survives the crash;
+/// opening a clone of it afterwards simulates a restart.  Once the
+/// disk has crashed, every write and sync fails.
+#[derive(Debug, Clone)]
+pub struct FaultDisk(Rc<RefCell<Disk>>);
+
+impl FaultDisk
+{
+  /// A disk that never crashes until armed.  If `drop_unsynced`
+  /// is true, a crash loses every write since the last sync of its
+  /// file; otherwise, writes reach the disk as they are made.
+  pub fn new(drop_unsynced: bool) -> FaultDisk
+  {
+    FaultDisk(Rc::new(RefCell::new(Disk { writes: 0, crash: None, drop_unsynced, crashed: false })))
+  }
+
+  /// Start counting writes and syncs from 0, and crash at the
+  /// `n`th from now (1 being the next) if `crash` is 
+  /// `Some((n, fault))`
+  pub fn arm(&self, crash: Option<(u64, Fault)>)
+  {
+    let mut disk = self.0.borrow_mut();
+    disk.writes = 0;
+    disk.crash = crash;
+  }
+
+  /// The number of writes and syncs since the disk was last armed
+  pub fn writes(&self) -> u64
+  {
+    self.0.borrow().writes
+  }
+
+  /// Return true if the disk has crashed
+  pub fn crashed(&self) -> bool
+  {
+    self.0.borrow().crashed
+  }
+
+  /// A file on this disk, starting out with (and keeping what
+  /// survives a crash in) `durable`
+  pub fn file(&self, durable: MemStorage) -> FaultStorage
+  {
+    let len = durable.len();
+    FaultStorage { disk: self.clone(), durable, unsynced: BTreeMap::new(), len }
+  }
+
+  /// Count a write or sync, and return the fault it suffers if the
+  /// disk crashes at it
+  fn write(&self) -> BPlusResult<Option<Fault>>
+  {
+    let mut disk = self.0.borrow_mut();
+    if disk.crashed { return Err(crash_error()) }
+    disk.writes += 1;
+    match disk.crash
+    {
+      Some((n, fault)) if n == disk.writes => { disk.crashed = true; Ok(Some(fault)) }
+      _                                    => Ok(None)
+    }
+  }
+}
+
+fn crash_error() -> BPlusError
+{
+  BPlusError::Io(io::Error::other("Simulated crash"))
+}
+
+/// A file on a FaultDisk
+#[derive(Debug)]
+pub struct FaultStorage
+{
+  disk: FaultDisk,
+  durable: MemStorage,
+  /// Writes that a crash would lose (if the disk drops them)
+  unsynced: BTreeMap<PagePointer, Vec<u8>>,
+  len: PagePointer,
+}
+
+impl FaultStorage
+{
+  /// The durable contents of a page (zeroed past the end)
+  fn durable_page(&mut self, ptr: PagePointer) -> BPlusResult<Vec<u8>>
+  {
+    let mut page = vec![0; PAGE_SIZE];
+    if ptr < self.durable.len() { self.durable.read_page(ptr, &mut page)?; }
+    Ok(page)
+  }
+}
+
+impl Storage for FaultStorage
+{
+  fn read_page(&mut self, ptr: PagePointer, buffer: &mut [u8]) -> BPlusResult<()>
+  {
+    check_buffer(self, buffer)?;
+    if ptr >= self.len { return Err(BPlusError::PageOutOfBounds { page: ptr, len: self.len }) }
+    match self.unsynced.get(&ptr)
+    {
+      Some(page) => buffer.copy_from_slice(page),
+      None       => buffer.copy_from_slice(&self.durable_page(ptr)?),
+    }
+    Ok(())
+  }
+
+  fn write_page(&mut self, ptr: PagePointer, buffer: &[u8]) -> BPlusResult<()>
+  {
+    check_buffer(self, buffer)?;
+    let Some(fault) = self.disk.write()? else
+    {
+      if self.disk.0.borrow().drop_unsynced { self.unsynced.insert(ptr, buffer.to_vec()); }
+      else                                  { self.durable.write_page(ptr, buffer)?; }
+      self.len = self.len.max(ptr + 1);
+      return Ok(())
+    };
+    let mut page = self.durable_page(ptr)?;
+    let half = PAGE_SIZE / 2;
+    match fault
+    {
+      Fault::Fail => return Err(crash_error()),
+      Fault::Truncate if ptr >= self.durable.len() => 
+      {
+        self.len = self.durable.len();
+        return Err(crash_error())
+      }
+      Fault::Truncate => { page[.. half].copy_from_slice(&buffer[.. half]); page[half ..].fill(0); }
+      Fault::Tear     => page[.. half].copy_from_slice(&buffer[.. half]),
+    }
+    self.durable.write_page(ptr, &page)?;
+    Err(crash_error())
+  }
+
+  fn allocate(&mut self, pages: PagePointer) -> BPlusResult<()>
+  {
+    if self.disk.crashed() { return Err(crash_error()) }
+    self.len = self.len.max(pages);
+    if !self.disk.0.borrow().drop_unsynced { self.durable.allocate(pages)?; }
+    Ok(())
+  }
+
+  fn truncate(&mut self, pages: PagePointer) -> BPlusResult<()>
+  {
+    if self.disk.crashed() { return Err(crash_error()) }
+    self.len = self.len.min(pages);
+    self.unsynced.retain(|ptr, _| *ptr < pages);
+    if !self.disk.0.borrow().drop_unsynced { self.durable.truncate(pages)?; }
+    Ok(())
+  }
+
+  fn sync(&mut self) -> BPlusResult<()>
+  {
+    if self.disk.write()?.is_some() { return Err(crash_error()) }
+    self.durable.truncate(self.len)?;
+    self.durable.allocate(self.len)?;
+    for (ptr, page) in std::mem::take(&mut self.unsynced)
+    {
+      self.durable.write_page(ptr, &page)?;
+    }
+    self.durable.sync()
+  }
+
+  fn len(&self) -> PagePointer
+  {
+    self.len
+  }
+}
@@ -0,0 +1,125 @@
+use std::fs::{ File, OpenOptions };
+use std::io::{ Read, Seek, SeekFrom, Write };
+
+use crate::error::BPlusResult;
+use crate::page::{ PagePointer, PAGE_SIZE };
+use super::{ check_buffer, Storage };
+
+#[derive(Debug)]
+pub struct FileStorage
+{
+  file: File,
+  page_size: usize,
+}
+
+#[allow(dead_code)]
+impl FileStorage
+{
+  /// Create (or truncate) the file at the provided path
+  pub fn create(path: &String) -> BPlusResult<FileStorage>
+  {
+    let file = 
+      OpenOptions::new()
+                 .create(true)   // Create file if not present
+                 .truncate(true) // Empty the file if it is
+                 .read(true)     // Allow reads
+                 .write(true)    // Allow writes
+                 .open(path)?;
+    Ok(FileStorage::new(file))
+  }
+
+  /// Open the existing file at the provided path
+  pub fn open(path: &String) -> BPlusResult<FileStorage>
+  {
+    let file = 
+      OpenOptions::new()
+                 .read(true)     // Allow reads
+                 .write(true)    // Allow writes
+                 .open(path)?;
+    Ok(FileStorage::new(file))
+  }
+
+  /// Use an already open file with PAGE_SIZE pages
+  pub fn new(file: File) -> FileStorage
+  {
+    FileStorage::with_page_size(file, PAGE_SIZE)
+  }
+
+  /// Use an already open file with a non-standard page size
+  pub fn with_page_size(file: File, page_size: usize) -> FileStorage
+  {
+    FileStorage { file, page_size }
+  }
+
+  /// Address the same file in pages of a different size
+  pub fn resized(mut self, page_size: usize) -> FileStorage
+  {
+    self.page_size = page_size;
+    self
+  }
+
+  /// The underlying file
+  pub fn file(&self) -> &File
+  {
+    &self.file
+  }
+
+  fn seek_addr(&self, ptr: PagePointer) -> SeekFrom
+  {
+    SeekFrom::Start(ptr * (self.page_size as u64))
+  }
+}
+
+impl Storage for FileStorage
+{
+  fn page_size(&self) -> usize { self.page_size }
+
+  fn read_page(&mut self, ptr: PagePointer, buffer: &mut [u8]) -> BPlusResult<()>
+  {
+    check_buffer(self, buffer)?;
+    self.file.seek(self.seek_addr(ptr))?;
+    self.file.read_exact(buffer)?;
+    Ok(())
+  }
+
+  fn write_page(&mut self, ptr: PagePointer, buffer: &[u8]) -> BPlusResult<()>
+  {
+    check_buffer(self, buffer)?;
+    self.file.seek(self.seek_addr(ptr))?;
+    self.file.write_all(buffer)?;
+    Ok(())
+  }
+
+  fn allocate(&mut self, pages: PagePointer) -> BPlusResult<()>
+  {
+    if self.len() < pages
+    {
+      self.file.set_len(pages * (self.page_size as u64))?;
+    }
+    Ok(())
+  }
+
+  fn truncate(&mut self, pages: PagePointer) -> BPlusResult<()>
+  {
+    if self.len() > pages
+    {
+      self.file.set_len(pages * (self.page_size as u64))?;
+    }
+    Ok(())
+  }
+
+  fn sync(&mut self) -> BPlusResult<()>
+  {
+    self.file.sync_data()?;
+    Ok(())
+  }
+
+  fn len(&self) -> PagePointer
+  {
+    match self.file.metadata()
+    {
+      Ok(meta) => meta.len() / (self.page_size as u64),
+      Err(_) => 0
+    }
+  }
+}
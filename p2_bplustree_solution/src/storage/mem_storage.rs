@@ -0,0 +1,92 @@
+use std::cell::RefCell;
+use std::rc::Rc;
+
+use crate::error::{ BPlusError, BPlusResult };
+use crate::page::{ PagePointer, PAGE_SIZE };
+use super::{ check_buffer, Storage };
+
+/// Pages stored in memory
+///
+/// Clones share the same buffer, so a tree can be "closed" and
+/// "re-opened" by opening a clone of its storage.
+#[derive(Debug, Clone)]
+pub struct MemStorage
+{
+  data: Rc<RefCell<Vec<u8>>>,
+  page_size: usize,
+}
+
+#[allow(dead_code)]
+impl MemStorage
+{
+  /// Create a new, empty storage with PAGE_SIZE pages
+  pub fn new() -> MemStorage
+  {
+    MemStorage::with_page_size(PAGE_SIZE)
+  }
+
+  /// Create a new, empty storage with a non-standard page size
+  pub fn with_page_size(page_size: usize) -> MemStorage
+  {
+    MemStorage { data: Rc::new(RefCell::new(Vec::new())), page_size }
+  }
+
+  fn range(&self, ptr: PagePointer) -> std::ops::Range<usize>
+  {
+    let start = ptr as usize * self.page_size;
+    start .. start + self.page_size
+  }
+}
+
+impl Default for MemStorage
+{
+  fn default() -> MemStorage { MemStorage::new() }
+}
+
+impl Storage for MemStorage
+{
+  fn page_size(&self) -> usize { self.page_size }
+
+  fn read_page(&mut self, ptr: PagePointer, buffer: &mut [u8]) -> BPlusResult<()>
+  {
+    check_buffer(self, buffer)?;
+    match self.data.borrow().get(self.range(ptr))
+    {
+      Some(bytes) => { buffer.copy_from_slice(bytes); Ok(()) }
+      None => Err(BPlusError::PageOutOfBounds { page: ptr, len: self.len() })
+    }
+  }
+
+  fn write_page(&mut self, ptr: PagePointer, buffer: &[u8]) -> BPlusResult<()>
+  {
+    check_buffer(self, buffer)?;
+    self.allocate(ptr+1)?;
+    let range = self.range(ptr);
+    self.data.borrow_mut()[range].copy_from_slice(buffer);
+    Ok(())
+  }
+
+  fn allocate(&mut self, pages: PagePointer) -> BPlusResult<()>
+  {
+    let mut data = self.data.borrow_mut();
+    let len = pages as usize * self.page_size;
+    if data.len() < len { data.resize(len, 0); }
+    Ok(())
+  }
+
+  fn truncate(&mut self, pages: PagePointer) -> BPlusResult<()>
+  {
+    self.data.borrow_mut().truncate(pages as usize * self.page_size);
+    Ok(())
+  }
+
+  fn sync(&mut self) -> BPlusResult<()>
+  {
+    Ok(())
+  }
+
+  fn len(&self) -> PagePointer
+  {
+    (self.data.borrow().len() / self.page_size) as PagePointer
+  }
+}
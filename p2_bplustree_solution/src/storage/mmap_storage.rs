@@ -0,0 +1,110 @@
+use memmap2::Mmap;
+
+use crate::error::{ BPlusError, BPlusResult };
+use crate::page::PagePointer;
+use super::{ check_buffer, FileStorage, Storage };
+
+/// Pages stored in a file and read through a memory map
+///
+/// Reads are served from a read-only, shared mapping of the file,
+/// and can borrow pages in place (see `Storage::view`).  Writes go
+/// through the file, and show up in the mapping without remapping.
+/// The mapping is re-created whenever the file grows.
+#[derive(Debug)]
+pub struct MmapStorage
+{
+  file: FileStorage,
+  map: Mmap,
+}
+
+#[allow(dead_code)]
+impl MmapStorage
+{
+  /// Create (or truncate) the file at the provided path
+  pub fn create(path: &String) -> BPlusResult<MmapStorage>
+  {
+    MmapStorage::new(FileStorage::create(path)?)
+  }
+
+  /// Open the existing file at the provided path
+  pub fn open(path: &String) -> BPlusResult<MmapStorage>
+  {
+    MmapStorage::new(FileStorage::open(path)?)
+  }
+
+  /// Map an already open file
+  pub fn new(file: FileStorage) -> BPlusResult<MmapStorage>
+  {
+    let map = MmapStorage::map(&file)?;
+    Ok(MmapStorage { file, map })
+  }
+
+  fn map(file: &FileStorage) -> BPlusResult<Mmap>
+  {
+    // Views into the mapping borrow self, so it can't be replaced
+    // while one is live.
+    Ok(unsafe { Mmap::map(file.file())? })
+  }
+
+  /// Re-create the mapping if the file has outgrown it
+  fn remap(&mut self) -> BPlusResult<()>
+  {
+    if self.map.len() < self.file.len() as usize * self.page_size()
+    {
+      self.map = MmapStorage::map(&self.file)?;
+    }
+    Ok(())
+  }
+}
+
+impl Storage for MmapStorage
+{
+  fn page_size(&self) -> usize { self.file.page_size() }
+
+  fn read_page(&mut self, ptr: PagePointer, buffer: &mut [u8]) -> BPlusResult<()>
+  {
+    check_buffer(self, buffer)?;
+    match self.view(ptr)
+    {
+      Some(bytes) => { buffer.copy_from_slice(bytes); Ok(()) }
+      None => Err(BPlusError::PageOutOfBounds { page: ptr, len: self.len() })
+    }
+  }
+
+  fn write_page(&mut self, ptr: PagePointer, buffer: &[u8]) -> BPlusResult<()>
+  {
+    self.file.write_page(ptr, buffer)?;
+    self.remap()
+  }
+
+  fn allocate(&mut self, pages: PagePointer) -> BPlusResult<()>
+  {
+    self.file.allocate(pages)?;
+    self.remap()
+  }
+
+  fn truncate(&mut self, pages: PagePointer) -> BPlusResult<()>
+  {
+    self.file.truncate(pages)?;
+    // Pages past the end of the file can't be touched through the
+    // mapping any more
+    self.map = MmapStorage::map(&self.file)?;
+    Ok(())
+  }
+
+  fn sync(&mut self) -> BPlusResult<()>
+  {
+    self.file.sync()
+  }
+
+  fn len(&self) -> PagePointer
+  {
+    self.file.len()
+  }
+
+  fn view(&self, ptr: PagePointer) -> Option<&[u8]>
+  {
+    let start = ptr as usize * self.page_size();
+    self.map.get(start .. start + self.page_size())
+  }
+}
@@ -0,0 +1,100 @@
+#[cfg(feature = "compression")]
+mod compressed_storage;
+#[cfg(feature = "encryption")]
+mod encrypted_storage;
+#[cfg(test)]
+mod fault_storage;
+mod file_storage;
+mod mem_storage;
+#[cfg(feature = "mmap")]
+mod mmap_storage;
+
+use std::fmt::Debug;
+
+use crate::error::{ BPlusError, BPlusResult };
+use crate::page::{ PagePointer, CIPHER_NONE, PAGE_SIZE };
+
+/// Pages stored in a regular file
+pub type FileStorage = file_storage::FileStorage;
+/// Pages stored in an in-memory buffer
+pub type MemStorage = mem_storage::MemStorage;
+/// Pages stored compressed in variable-sized extents of another
+/// storage
+#[cfg(feature = "compression")]
+pub type CompressedStorage = compressed_storage::CompressedStorage;
+/// Pages stored encrypted in another storage
+#[cfg(feature = "encryption")]
+pub type EncryptedStorage = encrypted_storage::EncryptedStorage;
+/// A simulated disk that crashes on cue, holding files of pages
+#[cfg(test)]
+pub type FaultDisk = fault_storage::FaultDisk;
+/// What becomes of the write that a FaultDisk crashes at
+#[cfg(test)]
+pub type Fault = fault_storage::Fault;
+/// Pages stored in a file, and read through a memory map
+#[cfg(feature = "mmap")]
+pub type MmapStorage = mmap_storage::MmapStorage;
+
+/// The medium that a BPlusTree's pages are kept in.
+///
+/// Storage is addressed in fixed-size pages; page `idx` occupies
+/// bytes `[idx * page_size, (idx+1) * page_size)`.  Implementations 
+/// may wrap other storage (e.g., to encrypt or compress pages on 
+/// their way to disk).
+#[allow(dead_code)]
+pub trait Storage: Debug
+{
+  /// The number of bytes in each page.  BPlusTree requires storage
+  /// with pages of PAGE_SIZE bytes.
+  fn page_size(&self) -> usize { PAGE_SIZE }
+
+  /// Read the page at the provided index into `buffer`, which must
+  /// be exactly `page_size()` bytes long.
+  fn read_page(&mut self, ptr: PagePointer, buffer: &mut [u8]) -> BPlusResult<()>;
+
+  /// Write the page at the provided index from `buffer`, which
+  /// must be exactly `page_size()` bytes long.  Writing past the 
+  /// end of the storage grows it.
+  fn write_page(&mut self, ptr: PagePointer, buffer: &[u8]) -> BPlusResult<()>;
+
+  /// Ensure that the storage holds at least `pages` pages.  Newly
+  /// allocated pages are zeroed.
+  fn allocate(&mut self, pages: PagePointer) -> BPlusResult<()>;
+
+  /// Discard every page from `pages` on, if the storage holds 
+  /// more.  Storage that can't shrink keeps them, to be overwritten
+  /// as they are reused.
+  fn truncate(&mut self, _pages: PagePointer) -> BPlusResult<()> { Ok(()) }
+
+  /// Ensure that all writes so far have reached durable storage.
+  fn sync(&mut self) -> BPlusResult<()>;
+
+  /// The number of pages in the storage
+  fn len(&self) -> PagePointer;
+
+  /// Return true if the storage holds no pages
+  fn is_empty(&self) -> bool { self.len() == 0 }
+
+  /// Borrow the bytes of a page in place, if this storage supports
+  /// it (e.g., because it is memory mapped).  Returns None if the 
+  /// storage can't, or the page doesn't exist.
+  fn view(&self, _ptr: PagePointer) -> Option<&[u8]> { None }
+
+  /// The cipher that pages are encrypted with, as recorded in the
+  /// metadata page (e.g., CIPHER_NONE)
+  fn cipher(&self) -> u8 { CIPHER_NONE }
+
+  /// A value identifying the encryption key, as recorded in the 
+  /// metadata page.  Never reveals the key itself.
+  fn key_check(&self) -> [u8; 16] { [0; 16] }
+}
+
+/// Return an error unless the buffer is one page long
+fn check_buffer(storage: &dyn Storage, buffer: &[u8]) -> BPlusResult<()>
+{
+  if buffer.len() != storage.page_size()
+  {
+    return Err(BPlusError::IncompatibleStorage(format!("Expected a {} byte page buffer, but got {} bytes", storage.page_size(), buffer.len())))
+  }
+  Ok(())
+}
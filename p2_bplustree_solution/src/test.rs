@@ -1,6 +1,9 @@
-use std::{collections::HashSet, error::Error, ops::Range};
+use std::{cell::Cell, collections::{BTreeMap, HashMap, HashSet}, error::Error, ops::Range, rc::Rc};
 
-use crate::{bplus_tree::{BPlusResult, BPlusTree}, page::{FreePage, PagePointer}};
+use crate::{bplus_tree::{BPlusTree, SyncPolicy}, error::{BPlusError, BPlusResult}, page::{FreePage, PagePointer, FREE_PAGE_T, PAGE_SIZE}, repl::Repl};
+use crate::export::{ self, ExportOptions };
+use crate::storage::{ Fault, FaultDisk, MemStorage, Storage };
+use crate::ttl::Clock;
 
 use rand::{ rngs::StdRng, RngCore, SeedableRng };
 
@@ -14,10 +17,10 @@ fn test_allocation() -> BPlusResult<()>
 {
   let mut tests: Vec<(PagePointer, PagePointer)> = Vec::new();
 
-  let path = "target/test_allocation.btree".to_string();
+  let storage = MemStorage::new();
   {
     println!("Init tree");
-    let mut tree = BPlusTree::init(&path)?;
+    let mut tree = BPlusTree::init_with(Box::new(storage.clone()))?;
 
     // Use FreePage as a placeholder that we can store stuff in
     let page = FreePage::init(0xfeed);
@@ -96,7 +99,7 @@ fn test_allocation() -> BPlusResult<()>
   // close the block, 'tree' should be freed and closed.
   // open up a new block where we can test the new tree
   {
-    let mut tree = BPlusTree::open(&path)?;
+    let mut tree = BPlusTree::open_with(Box::new(storage))?;
 
     for (ptr, value) in tests
     {
@@ -112,7 +115,7 @@ fn test_allocation() -> BPlusResult<()>
 
 /// Utility function: Invokes tree.check_tree and asserts if
 /// an error is found after printing out the current tree.
-fn check_tree(tree: &mut BPlusTree) -> Result<(), Box<dyn Error>>
+fn check_tree(tree: &mut BPlusTree) -> BPlusResult<()>
 {
   match tree.check_tree()
   {
@@ -133,14 +136,29 @@ fn check_tree(tree: &mut BPlusTree) -> Result<(), Box<dyn Error>>
   Ok(())
 }
 
+/// Utility function: Every record in the tree, in order
+fn contents(tree: &mut BPlusTree) -> BPlusResult<BTreeMap<u32, u32>>
+{
+  tree.iter()?.collect()
+}
+
+/// Utility function: A new journaled tree, along with handles on
+/// its storage and journal
+fn journaled_tree() -> BPlusResult<(BPlusTree, MemStorage, MemStorage)>
+{
+  let storage = MemStorage::new();
+  let journal = MemStorage::new();
+  let tree = BPlusTree::init_journaled(Box::new(storage.clone()), Box::new(journal.clone()))?;
+  Ok((tree, storage, journal))
+}
+
 /// Development Step 2:
 /// 
 /// Implement put
 #[test]
 fn test_read_write() -> Result<(), Box<dyn Error>>
 {
-  let path = "target/test_read_write.btree".to_string();
-  let mut tree = BPlusTree::init(&path)?;
+  let mut tree = BPlusTree::init_in_memory()?;
 
     check_tree(&mut tree)?;
   tree.put(10, 111)?;
@@ -156,7 +174,7 @@ fn test_read_write() -> Result<(), Box<dyn Error>>
   tree.put(14, 666)?;
     check_tree(&mut tree)?;
 
-  let elems: Vec<(u32, u32)> = tree.iter()?.collect();
+  let elems: Vec<(u32, u32)> = tree.iter()?.collect::<BPlusResult<_>>()?;
 
   println!("Elems after insert: {:?}", elems);
   assert!(elems.len() == 6);
@@ -194,8 +212,7 @@ fn test_read_write() -> Result<(), Box<dyn Error>>
 #[test]
 fn test_delete() -> Result<(), Box<dyn Error>>
 {
-  let path = "target/test_delete.btree".to_string();
-  let mut tree = BPlusTree::init(&path)?;
+  let mut tree = BPlusTree::init_in_memory()?;
 
   tree.put(50000, 12345)?;
 
@@ -239,4 +256,1877 @@ fn test_delete() -> Result<(), Box<dyn Error>>
   assert!(tree.depth() == 1);
 
   Ok(())
-}
\ No newline at end of file
+}
+/// Drive the interactive shell with a scripted session
+#[test]
+fn test_repl() -> Result<(), Box<dyn Error>>
+{
+  let mut tree = BPlusTree::init_in_memory()?;
+  let mut output: Vec<u8> = Vec::new();
+  {
+    let mut repl = Repl::new(&mut tree);
+    let mut input = "autocheck on\nput 10 100\nput 20 200\nput 30 300\n\
+                     del 20\nget 10\nget 20\nrange 0 100\n!6\npage 0\n\
+                     frobnicate\nhistory\nquit\nput 40 400\n".as_bytes();
+    repl.run(&mut input, &mut output)?;
+    assert_eq!(repl.history().len(), 12);
+    assert_eq!(repl.history()[8], "get 10");
+  }
+  let output = String::from_utf8(output)?;
+  println!("{}", output);
+
+  assert_eq!(output.matches("ok\n").count(), 4);
+  assert!(output.contains("100\n"));
+  assert!(output.contains("(not found)\n"));
+  assert!(output.contains("10 -> 100\n30 -> 300\n(2 records)\n"));
+  assert!(output.contains("PAGE[0] = MetadataPage"));
+  assert!(output.contains("Error: Unknown command 'frobnicate'"));
+
+  // The shell stops at 'quit'
+  assert!(tree.get(40)?.is_none());
+  assert_eq!(tree.get(30)?, Some(300));
+
+  // Pages that haven't been committed yet are shown as they are now
+  let (mut tree, _, _) = journaled_tree()?;
+  tree.set_sync_policy(SyncPolicy::EveryOps(100000))?;
+  let mut output: Vec<u8> = Vec::new();
+  let input: String = (0 .. 20000).map(|k| format!("put {} {}\n", k, k)).collect();
+  Repl::new(&mut tree).run(&mut input.as_bytes(), &mut output)?;
+  let (_, tail) = tree.data_bounds();
+  assert!(tail > 2);
+  Repl::new(&mut tree).run(&mut format!("page {}\n", tail).as_bytes(), &mut output)?;
+  let output = String::from_utf8(output)?;
+  assert!(!output.contains("Error"));
+  assert!(output.contains(&format!("PAGE[{}] = LeafPage", tail)));
+
+  Ok(())
+}
+
+/// Export the structure of a small multi-leaf tree
+#[test]
+fn test_export() -> Result<(), Box<dyn Error>>
+{
+  let mut tree = BPlusTree::init_in_memory()?;
+  for k in 0 .. 2000
+  {
+    tree.put(k, k)?;
+  }
+  let (head, tail) = tree.data_bounds();
+
+  let mut dot: Vec<u8> = Vec::new();
+  export::to_dot(&mut tree, &ExportOptions::default(), &mut dot)?;
+  let dot = String::from_utf8(dot)?;
+  println!("{}", dot);
+  assert!(dot.starts_with("digraph bplus_tree {"));
+  assert!(dot.contains(&format!("page{} [shape=box, label=\"#{}\\n[0, ", head, head)));
+  assert!(dot.contains(&format!("page{}:p0 -> page{};", tree.root_page(), head)));
+  assert!(dot.contains(&format!("page{} -> page", head)));
+  assert!(dot.contains(", 1999]\\n"));
+
+  let mut json: Vec<u8> = Vec::new();
+  export::to_json(&mut tree, &ExportOptions::default(), &mut json)?;
+  let json = String::from_utf8(json)?;
+  println!("{}", json);
+  assert!(json.starts_with(&format!("{{\"depth\":1,\"root\":{},\"data_head\":{},\"data_tail\":{},", tree.root_page(), head, tail)));
+  let total: usize = 
+    json.match_indices("\"count\":")
+        .map(|(i, _)| {
+          json[i+8 ..].split(',').next().unwrap().parse::<usize>().unwrap()
+        })
+        .sum();
+  assert_eq!(total, 2000);
+  assert!(json.contains(&format!("\"page\":{},\"type\":\"leaf\",\"count\":", head)));
+  assert!(json.contains(",\"prev\":null,"));
+  assert!(json.contains(",\"next\":null}"));
+
+  // Only the leaf holding key 5 should be exported
+  let mut json: Vec<u8> = Vec::new();
+  export::to_json(&mut tree, &ExportOptions::default().with_keys(5..6), &mut json)?;
+  let json = String::from_utf8(json)?;
+  assert_eq!(json.matches("\"type\":\"leaf\"").count(), 1);
+  assert!(json.contains("\"min_key\":0,"));
+  assert!(json.contains(",null"));
+
+  // Cutting off at the root truncates every leaf
+  let mut json: Vec<u8> = Vec::new();
+  export::to_json(&mut tree, &ExportOptions::default().with_max_depth(0), &mut json)?;
+  let json = String::from_utf8(json)?;
+  assert_eq!(json.matches("\"type\":\"leaf\"").count(), 0);
+  assert!(json.contains(&format!("{{\"page\":{},\"type\":\"truncated\"}}", head)));
+
+  Ok(())
+}
+
+/// Reads through a memory map must track writes that grow the file
+#[cfg(all(feature = "mmap", not(feature = "key-compression")))]
+#[test]
+fn test_mmap() -> BPlusResult<()>
+{
+  let path = "target/test_mmap.btree".to_string();
+  BPlusTree::init(&path)?;
+  let mut tree = BPlusTree::open_mmap(&path)?;
+  assert!(tree.can_view_pages());
+
+  let mut rng = StdRng::seed_from_u64(410);
+  let mut tests: Vec<u32> = Vec::new();
+  for _i in 0 .. 3000
+  {
+    let k = rng.next_u32();
+    tree.put(k, k % 10000)?;
+    tests.push(k);
+  }
+  check_tree(&mut tree)?;
+
+  for k in tests.iter()
+  {
+    assert_eq!(tree.get(*k)?, Some(k % 10000));
+  }
+  let leaf = tree.data_bounds().0;
+  assert!(tree.view_page::<crate::page::LeafPage>(leaf)?.count > 0);
+
+  for k in tests.iter().take(1000)
+  {
+    tree.delete(*k)?;
+    assert!(tree.get(*k)?.is_none());
+  }
+  check_tree(&mut tree)?;
+
+  Ok(())
+}
+
+/// Group commit: writes only reach storage once the policy says so
+#[test]
+fn test_sync_policy() -> BPlusResult<()>
+{
+  let (mut tree, storage, journal) = journaled_tree()?;
+
+  // Without a journal, only SyncPolicy::Never is allowed
+  let mut plain = BPlusTree::init_with(Box::new(MemStorage::new()))?;
+  assert!(plain.set_sync_policy(SyncPolicy::Always).is_err());
+
+  tree.set_sync_policy(SyncPolicy::EveryOps(10))?;
+  for k in 0 .. 9
+  {
+    tree.put(k, k * 10)?;
+  }
+  assert!(tree.pending_pages() > 0);
+  {
+    let mut snapshot = BPlusTree::open_with(Box::new(storage.clone()))?;
+    assert!(snapshot.get(0)?.is_none());
+    assert_eq!(snapshot.iter()?.count(), 0);
+  }
+
+  tree.put(9, 90)?;
+  assert_eq!(tree.pending_pages(), 0);
+  {
+    let mut snapshot = BPlusTree::open_with(Box::new(storage.clone()))?;
+    assert_eq!(snapshot.iter()?.count(), 10);
+    assert_eq!(snapshot.get(9)?, Some(90));
+  }
+
+  // Fill several leaves, then "crash" before the next commit
+  tree.set_sync_policy(SyncPolicy::EveryOps(1000))?;
+  for k in 10 .. 2000
+  {
+    tree.put(k, k * 10)?;
+  }
+  tree.sync()?;
+  for k in 0 .. 500
+  {
+    tree.delete(k)?;
+  }
+  assert!(tree.pending_pages() > 0);
+  std::mem::forget(tree);
+
+  let mut tree = BPlusTree::open_journaled(Box::new(storage.clone()), Box::new(journal.clone()))?;
+  check_tree(&mut tree)?;
+  assert_eq!(tree.iter()?.count(), 2000);
+  assert_eq!(tree.get(0)?, Some(0));
+
+  Ok(())
+}
+
+/// Storage that starts failing every write once `fail` is set
+#[derive(Debug)]
+struct FailingStorage
+{
+  inner: MemStorage,
+  fail: Rc<Cell<bool>>,
+}
+
+impl Storage for FailingStorage
+{
+  fn read_page(&mut self, ptr: PagePointer, buffer: &mut [u8]) -> BPlusResult<()>
+    { self.inner.read_page(ptr, buffer) }
+  fn write_page(&mut self, ptr: PagePointer, buffer: &[u8]) -> BPlusResult<()>
+  {
+    if self.fail.get() { return Err(BPlusError::Io(std::io::Error::other("Injected write failure"))) }
+    self.inner.write_page(ptr, buffer)
+  }
+  fn allocate(&mut self, pages: PagePointer) -> BPlusResult<()>
+    { self.inner.allocate(pages) }
+  fn sync(&mut self) -> BPlusResult<()>
+    { self.inner.sync() }
+  fn len(&self) -> PagePointer
+    { self.inner.len() }
+}
+
+/// A commit interrupted after reaching the journal is redone when
+/// the tree is next opened
+#[test]
+fn test_journal_recovery() -> BPlusResult<()>
+{
+  let storage = MemStorage::new();
+  let journal = MemStorage::new();
+  let fail = Rc::new(Cell::new(false));
+
+  let mut tree = BPlusTree::init_journaled(
+    Box::new(FailingStorage { inner: storage.clone(), fail: fail.clone() }),
+    Box::new(journal.clone())
+  )?;
+  tree.set_sync_policy(SyncPolicy::EveryOps(100))?;
+  for k in 0 .. 1100
+  {
+    tree.put(k, k + 1)?;
+  }
+  let expected: Vec<(u32, u32)> = tree.iter()?.collect::<BPlusResult<_>>()?;
+
+  // The next commit reaches the journal, but not the tree
+  for k in 1100 .. 1199
+  {
+    tree.put(k, k + 1)?;
+  }
+  fail.set(true);
+  assert!(tree.put(1199, 1200).is_err());
+  std::mem::forget(tree);
+
+  // Without the journal, the tree is as of the last commit
+  {
+    let mut tree = BPlusTree::open_with(Box::new(storage.clone()))?;
+    check_tree(&mut tree)?;
+    assert_eq!(tree.iter()?.collect::<BPlusResult<Vec<_>>>()?, expected);
+  }
+
+  let mut tree = BPlusTree::open_journaled(Box::new(storage.clone()), Box::new(journal.clone()))?;
+  check_tree(&mut tree)?;
+  assert_eq!(tree.iter()?.count(), 1200);
+  assert_eq!(tree.get(1199)?, Some(1200));
+
+  Ok(())
+}
+
+/// Pages with nearby keys must hold more records than the raw
+/// layout allows, and survive a round trip through storage
+#[cfg(feature = "key-compression")]
+#[test]
+fn test_key_compression() -> BPlusResult<()>
+{
+  use crate::page::{ DirectoryPage, LeafPage, NULL_IDX, DIR_KEY_COUNT, LEAF_RECORD_COUNT };
+  use rand::seq::SliceRandom;
+
+  let storage = MemStorage::new();
+  let mut rng = StdRng::seed_from_u64(410);
+  let mut sparse: Vec<u32> = Vec::new();
+  {
+    let mut tree = BPlusTree::init_with(Box::new(storage.clone()))?;
+    let mut dense: Vec<u32> = (0 .. 20000).collect();
+    dense.shuffle(&mut rng);
+    for k in dense
+    {
+      tree.put(k, k * 2)?;
+    }
+    // Keys too far apart to compress fall back to the raw layout
+    for _i in 0 .. 2000
+    {
+      let k = rng.next_u32() | 0x8000_0000;
+      tree.put(k, 7)?;
+      sparse.push(k);
+    }
+    check_tree(&mut tree)?;
+  }
+
+  let mut tree = BPlusTree::open_with(Box::new(storage))?;
+  check_tree(&mut tree)?;
+  let mut most = 0;
+  let mut ptr = tree.data_bounds().0;
+  while ptr != NULL_IDX
+  {
+    let leaf = tree.get_page::<LeafPage>(ptr)?;
+    most = most.max(leaf.count);
+    ptr = leaf.next;
+  }
+  assert!(most > LEAF_RECORD_COUNT);
+
+  for k in (0 .. 20000).step_by(97)
+  {
+    assert_eq!(tree.get(k)?, Some(k * 2));
+  }
+  for k in sparse.iter()
+  {
+    assert_eq!(tree.get(*k)?, Some(7));
+  }
+  for k in 0 .. 15000
+  {
+    tree.delete(k)?;
+  }
+  check_tree(&mut tree)?;
+  assert_eq!(tree.iter()?.count(), 5000 + sparse.len());
+
+  // Directory pages over leaves of nearby keys hold more keys than
+  // the raw layout allows, too
+  let mut tree = BPlusTree::init_with(Box::new(MemStorage::new()))?;
+  let depth = tree.depth();
+  let mut most = 0;
+  let mut k = 0;
+  while tree.depth() == depth
+  {
+    let records: Vec<(u32, u32)> = (k .. k + 100).map(|k| (k, k)).collect();
+    tree.put_many(&records)?;
+    k += 100;
+    let root = tree.root_page();
+    most = most.max(tree.get_page::<DirectoryPage>(root)?.count);
+  }
+  assert!(most > DIR_KEY_COUNT);
+  check_tree(&mut tree)?;
+
+  Ok(())
+}
+
+/// Compressed leaves must take less room than raw ones, and the
+/// page table must survive reopening the tree
+#[cfg(feature = "compression")]
+#[test]
+fn test_compression() -> BPlusResult<()>
+{
+  let path = "target/test_compression.btree".to_string();
+  let raw_path = "target/test_compression_raw.btree".to_string();
+  for (mut tree, path) in [ (BPlusTree::init_compressed(&path)?, &path), (BPlusTree::init(&raw_path)?, &raw_path) ]
+  {
+    tree.set_sync_policy(SyncPolicy::EveryOps(1000))?;
+    for k in 0 .. 10000
+    {
+      tree.put(k, k % 100)?;
+    }
+    for k in (0 .. 10000).step_by(3)
+    {
+      tree.delete(k)?;
+    }
+    check_tree(&mut tree)?;
+    println!("{} records in {}", tree.iter()?.count(), path);
+  }
+  let bytes = std::fs::metadata(&path)?.len();
+  let raw_bytes = std::fs::metadata(&raw_path)?.len();
+  println!("{} bytes compressed, {} bytes raw", bytes, raw_bytes);
+  assert!(bytes * 3 < raw_bytes * 2);
+
+  let mut tree = BPlusTree::open_compressed(&path)?;
+  tree.set_sync_policy(SyncPolicy::EveryOps(1000))?;
+  check_tree(&mut tree)?;
+  for k in 0 .. 10000
+  {
+    let expected = if k % 3 == 0 { None } else { Some(k % 100) };
+    assert_eq!(tree.get(k)?, expected);
+  }
+  for k in 0 .. 5000
+  {
+    tree.put(k, 1)?;
+  }
+  check_tree(&mut tree)?;
+  let expected = 5000 + (5000 .. 10000).filter(|k| k % 100 == 1 && k % 3 != 0).count();
+  assert_eq!(tree.iter()?.filter(|r| matches!(r, Ok((_, 1)))).count(), expected);
+
+  // Clearing the tree gives back the blocks of the pages it drops
+  tree.clear()?;
+  drop(tree);
+  println!("{} bytes after clearing", std::fs::metadata(&path)?.len());
+  assert!(std::fs::metadata(&path)?.len() * 4 < bytes);
+  let mut tree = BPlusTree::open_compressed(&path)?;
+  check_tree(&mut tree)?;
+  assert_eq!(tree.iter()?.count(), 0);
+
+  Ok(())
+}
+
+/// Encrypted trees must not leak records to disk, and must only
+/// open with the right key
+#[cfg(feature = "encryption")]
+#[test]
+fn test_encryption() -> BPlusResult<()>
+{
+  let path = "target/test_encryption.btree".to_string();
+  let key = [7u8; 32];
+  {
+    let mut tree = BPlusTree::init_encrypted(&path, &key)?;
+    tree.set_sync_policy(SyncPolicy::EveryOps(100))?;
+    for k in 0 .. 1500
+    {
+      tree.put(k, 0xdeadbeef)?;
+    }
+    check_tree(&mut tree)?;
+  }
+  let bytes = std::fs::read(&path)?;
+  assert!(!bytes.windows(4).any(|w| w == 0xdeadbeef_u32.to_ne_bytes()));
+
+  let err = BPlusTree::open_encrypted(&path, &[8u8; 32]).unwrap_err();
+  assert!(err.to_string().contains("Wrong key"));
+  assert!(BPlusTree::open(&path).is_err());
+
+  {
+    let mut tree = BPlusTree::open_encrypted(&path, &key)?;
+    tree.set_sync_policy(SyncPolicy::EveryOps(100))?;
+    check_tree(&mut tree)?;
+    assert_eq!(tree.iter()?.count(), 1500);
+    for k in 0 .. 500
+    {
+      tree.delete(k)?;
+    }
+    assert_eq!(tree.get(1000)?, Some(0xdeadbeef));
+  }
+
+  // Tampering with a page is detected
+  let mut bytes = std::fs::read(&path)?;
+  let leaf = crate::storage::EncryptedStorage::INNER_PAGE_SIZE * 2;
+  bytes[leaf + 100] ^= 1;
+  std::fs::write(&path, bytes)?;
+  let mut tree = BPlusTree::open_encrypted(&path, &key)?;
+  assert!(tree.iter().is_err() || tree.check_tree().is_err());
+  drop(tree);
+
+  // So is zeroing a page, trailer and all
+  let mut bytes = std::fs::read(&path)?;
+  bytes[leaf .. leaf + crate::storage::EncryptedStorage::INNER_PAGE_SIZE].fill(0);
+  std::fs::write(&path, bytes)?;
+  let mut tree = BPlusTree::open_encrypted(&path, &key)?;
+  assert!(tree.iter().is_err() || tree.check_tree().is_err());
+
+  // Only the tree's metadata page is in the clear; page 0 of any
+  // other storage (e.g., the journal header) is encrypted, and
+  // allocated pages are authenticated like any other
+  let inner = MemStorage::with_page_size(crate::storage::EncryptedStorage::INNER_PAGE_SIZE);
+  let mut storage = crate::storage::EncryptedStorage::new(Box::new(inner.clone()), &key)?;
+  storage.write_page(0, &[0xab; PAGE_SIZE])?;
+  storage.allocate(3)?;
+  let mut raw = vec![0; crate::storage::EncryptedStorage::INNER_PAGE_SIZE];
+  inner.clone().read_page(0, &mut raw)?;
+  assert!(!raw.windows(8).any(|w| w == [0xab; 8]));
+  let mut page = [1; PAGE_SIZE];
+  storage.read_page(2, &mut page)?;
+  assert_eq!(page, [0; PAGE_SIZE]);
+  inner.clone().write_page(2, &vec![0; crate::storage::EncryptedStorage::INNER_PAGE_SIZE])?;
+  assert!(storage.read_page(2, &mut page).is_err());
+
+  Ok(())
+}
+
+/// Batched puts and gets must agree with one-at-a-time operations
+#[test]
+fn test_many() -> BPlusResult<()>
+{
+  let mut tree = BPlusTree::init_in_memory()?;
+  tree.set_sync_policy(SyncPolicy::EveryOps(100))?;
+  let mut rng = StdRng::seed_from_u64(410);
+  let mut expected: HashMap<u32, u32> = HashMap::new();
+
+  for _batch in 0 .. 5
+  {
+    // Small keys, so that batches overwrite each other
+    let records: Vec<(u32, u32)> = 
+      (0 .. 2000).map(|_| (rng.next_u32() % 20000, rng.next_u32())).collect();
+    tree.put_many(&records)?;
+    for (key, value) in records
+    {
+      expected.insert(key, value);
+    }
+    check_tree(&mut tree)?;
+  }
+  assert_eq!(tree.iter()?.count(), expected.len());
+
+  let keys: Vec<u32> = (0 .. 3000).map(|_| rng.next_u32() % 25000).collect();
+  let values = tree.get_many(&keys)?;
+  for (key, value) in keys.iter().zip(values)
+  {
+    assert_eq!(value, expected.get(key).cloned());
+    assert_eq!(value, tree.get(*key)?);
+  }
+  assert!(tree.get_many(&[])?.is_empty());
+
+  // A batch that fits in the journal is committed as one operation
+  tree.set_sync_policy(SyncPolicy::Always)?;
+  let commits = tree.commits();
+  let records: Vec<(u32, u32)> = (0 .. 300).map(|k| (k * 80, k)).collect();
+  tree.put_many(&records)?;
+  assert_eq!(tree.commits(), commits + 1);
+  check_tree(&mut tree)?;
+
+  Ok(())
+}
+
+/// Read-modify-write operations must see and replace the current
+/// value
+#[test]
+fn test_update() -> BPlusResult<()>
+{
+  let mut tree = BPlusTree::init_in_memory()?;
+  for k in 0 .. 2000
+  {
+    tree.put(k, k)?;
+  }
+
+  assert_eq!(tree.compare_and_swap(10, Some(10), 100)?, Ok(Some(10)));
+  assert_eq!(tree.compare_and_swap(10, Some(10), 200)?, Err(Some(100)));
+  assert_eq!(tree.compare_and_swap(5000, None, 1)?, Ok(None));
+  assert_eq!(tree.compare_and_swap(5001, Some(1), 1)?, Err(None));
+  assert_eq!(tree.get(10)?, Some(100));
+  assert_eq!(tree.get(5000)?, Some(1));
+  assert_eq!(tree.get(5001)?, None);
+
+  for _i in 0 .. 10
+  {
+    tree.fetch_add(6000, 3)?;
+  }
+  assert_eq!(tree.fetch_add(6000, u32::MAX)?, 30);
+  assert_eq!(tree.get(6000)?, Some(29));
+
+  // Delete every even key, and double every odd one
+  for k in 0 .. 2000
+  {
+    assert_eq!(tree.update(k, |v| v.filter(|v| v % 2 == 1).map(|v| v * 2))?, 
+               if k == 10 { Some(100) } else { Some(k) });
+  }
+  check_tree(&mut tree)?;
+  assert_eq!(tree.get(7)?, Some(14));
+  assert_eq!(tree.get(8)?, None);
+  assert_eq!(tree.range(0 .. 2000)?.count(), 1000);
+
+  Ok(())
+}
+
+/// insert/insert_new/remove must report what was there before
+#[test]
+fn test_insert_remove() -> BPlusResult<()>
+{
+  let mut tree = BPlusTree::init_in_memory()?;
+  for k in 0 .. 1500
+  {
+    assert_eq!(tree.insert(k, k)?, None);
+  }
+  assert_eq!(tree.insert(3, 30)?, Some(3));
+
+  match tree.insert_new(3, 300)
+  {
+    Err(BPlusError::KeyExists { key, value }) => assert_eq!((key, value), (3, 30)),
+    other => panic!("Expected a KeyExists error, got {:?}", other),
+  }
+  assert_eq!(tree.get(3)?, Some(30));
+  tree.insert_new(2000, 1)?;
+
+  for k in 0 .. 1500
+  {
+    assert_eq!(tree.remove(k)?, Some(if k == 3 { 30 } else { k }));
+  }
+  assert_eq!(tree.remove(3)?, None);
+  check_tree(&mut tree)?;
+  assert_eq!(tree.iter()?.collect::<BPlusResult<Vec<_>>>()?, vec![(2000, 1)]);
+
+  Ok(())
+}
+
+/// Damaged pages are reported as errors rather than panics
+#[test]
+fn test_corruption() -> BPlusResult<()>
+{
+  let path = "target/test_corruption.btree".to_string();
+  let leaf;
+  {
+    let mut tree = BPlusTree::init(&path)?;
+    for k in 0 .. 2000
+    {
+      tree.put(k, k)?;
+    }
+    leaf = *tree.find_page(1000)?.last().unwrap();
+  }
+
+  // Make one leaf look like a free page
+  let mut bytes = std::fs::read(&path)?;
+  bytes[leaf as usize * PAGE_SIZE] = FREE_PAGE_T;
+  std::fs::write(&path, bytes)?;
+
+  let mut tree = BPlusTree::open(&path)?;
+  assert!(matches!(tree.get(1000), Err(BPlusError::PageTypeMismatch { page, .. }) if page == leaf));
+  assert!(tree.put(1000, 0).is_err());
+  assert_eq!(tree.get(0)?, Some(0));
+
+  // Iteration stops at the damaged page
+  let records: Vec<BPlusResult<(u32, u32)>> = tree.iter()?.collect();
+  let (last, ok) = records.split_last().unwrap();
+  assert!(ok.iter().all(|record| record.is_ok()));
+  assert!(matches!(last, Err(BPlusError::PageTypeMismatch { .. })));
+  drop(tree);
+
+  // A tree whose root is a leaf is reported, not a panic
+  let mut bytes = std::fs::read(&path)?;
+  let (root, depth, data_head) = (16, 48, 24);
+  bytes.copy_within(data_head .. data_head + 8, root);
+  bytes[depth .. depth + 2].fill(0);
+  std::fs::write(&path, bytes)?;
+  let mut tree = BPlusTree::open(&path)?;
+  assert!(matches!(tree.check_tree(), Err(BPlusError::Corruption { .. })));
+
+  Ok(())
+}
+
+/// rank and select must agree with a sorted list of the keys as the
+/// tree grows past two directory levels and shrinks again
+#[test]
+fn test_rank_select() -> BPlusResult<()>
+{
+  use crate::page::{ MetadataPage, Page, LAYOUT_PLAIN, METADATA_IDX };
+  use rand::seq::SliceRandom;
+
+  let mut rng = StdRng::seed_from_u64(410);
+  let mut tree = BPlusTree::init_in_memory()?;
+  let mut keys: Vec<u32> = (0 .. 80000).map(|k| k * 50000).collect();
+  keys.shuffle(&mut rng);
+  for k in &keys
+  {
+    tree.put(*k, *k + 1)?;
+  }
+  assert!(tree.depth() >= 2);
+  check_tree(&mut tree)?;
+
+  let check = |tree: &mut BPlusTree, sorted: &[u32]| -> BPlusResult<()>
+  {
+    assert_eq!(tree.record_count()?, sorted.len() as u64);
+    for i in (0 .. sorted.len()).step_by(397)
+    {
+      let k = sorted[i];
+      assert_eq!(tree.select(i as u64)?, Some((k, k + 1)));
+      assert_eq!(tree.rank(k)?, i as u64);
+      assert_eq!(tree.rank(k + 1)?, i as u64 + 1);
+    }
+    assert_eq!(tree.select(sorted.len() as u64)?, None);
+    Ok(())
+  };
+  let mut sorted = keys.clone();
+  sorted.sort();
+  check(&mut tree, &sorted)?;
+
+  // Shrink back down to a single directory level
+  let (gone, kept) = keys.split_at(75000);
+  for k in gone
+  {
+    tree.delete(*k)?;
+  }
+  assert_eq!(tree.depth(), 1);
+  check_tree(&mut tree)?;
+  let mut sorted = kept.to_vec();
+  sorted.sort();
+  check(&mut tree, &sorted)?;
+
+  // Trees from before directory pages held counts are refused
+  let mut storage = MemStorage::new();
+  BPlusTree::init_with(Box::new(storage.clone()))?;
+  let mut meta = MetadataPage::read(&mut storage, METADATA_IDX)?;
+  meta.layout = LAYOUT_PLAIN;
+  meta.write(&mut storage, METADATA_IDX)?;
+  assert!(matches!(BPlusTree::open_with(Box::new(storage)), Err(BPlusError::IncompatibleStorage(_))));
+
+  Ok(())
+}
+
+/// Neighbor lookups must match a BTreeSet, including across leaves
+#[test]
+fn test_neighbors() -> BPlusResult<()>
+{
+  use std::collections::BTreeSet;
+  use std::ops::Bound;
+
+  let mut tree = BPlusTree::init_in_memory()?;
+  assert_eq!(tree.first()?, None);
+  assert_eq!(tree.last()?, None);
+  assert_eq!(tree.ceiling(0)?, None);
+  assert_eq!(tree.floor(u32::MAX)?, None);
+
+  let mut rng = StdRng::seed_from_u64(410);
+  let mut keys = BTreeSet::new();
+  for _i in 0 .. 3000
+  {
+    let k = rng.next_u32() % 100000;
+    tree.put(k, k / 2)?;
+    keys.insert(k);
+  }
+  let record = |k: Option<&u32>| k.map(|k| (*k, *k / 2));
+
+  assert_eq!(tree.first()?, record(keys.first()));
+  assert_eq!(tree.last()?, record(keys.last()));
+  let mut probes: Vec<u32> = (0 .. 2000).map(|_| rng.next_u32() % 101000).collect();
+  // Every key on either side of a leaf boundary
+  probes.extend(keys.iter().flat_map(|k| [*k, k.saturating_sub(1), k + 1]));
+  probes.extend([0, u32::MAX]);
+  for k in probes
+  {
+    assert_eq!(tree.ceiling(k)?, record(keys.range(k ..).next()));
+    assert_eq!(tree.floor(k)?, record(keys.range(..= k).next_back()));
+    assert_eq!(tree.successor(k)?, record(keys.range((Bound::Excluded(k), Bound::Unbounded)).next()));
+    assert_eq!(tree.predecessor(k)?, record(keys.range(.. k).next_back()));
+  }
+
+  Ok(())
+}
+
+/// Range deletes must match a BTreeMap, free the pages they empty,
+/// and keep the tree balanced
+#[test]
+fn test_delete_range() -> BPlusResult<()>
+{
+  use std::ops::Bound;
+
+  let storage = MemStorage::new();
+  let mut tree = BPlusTree::init_journaled(Box::new(storage.clone()), Box::new(MemStorage::new()))?;
+  tree.set_sync_policy(SyncPolicy::EveryOps(1000))?;
+  let records: Vec<(u32, u32)> = (0 .. 80000).map(|k| (k * 50000, k)).collect();
+  tree.put_many(&records)?;
+  tree.sync()?;
+  assert!(tree.depth() >= 2);
+  let full_len = storage.len();
+  let mut expected: BTreeMap<u32, u32> = records.iter().copied().collect();
+
+  let ranges = [
+    (Bound::Included(100 * 50000), Bound::Included(150 * 50000)),
+    (Bound::Excluded(1000 * 50000), Bound::Excluded(3000 * 50000 + 1)),
+    // More than one operation's worth of records
+    (Bound::Included(10000 * 50000 - 1), Bound::Excluded(70000 * 50000)),
+    (Bound::Included(5), Bound::Excluded(6)),
+    (Bound::Included(79000 * 50000), Bound::Unbounded),
+  ];
+  for range in ranges
+  {
+    let gone: Vec<u32> = expected.range(range).map(|(k, _)| *k).collect();
+    for k in &gone { expected.remove(k); }
+    assert_eq!(tree.delete_range(range)?, gone.len() as u64);
+    check_tree(&mut tree)?;
+    assert_eq!(tree.iter()?.collect::<BPlusResult<Vec<_>>>()?, expected.iter().map(|(k, v)| (*k, *v)).collect::<Vec<_>>());
+  }
+
+  // Deleting everything frees every page, and refilling reuses them
+  assert_eq!(tree.delete_range(..)?, expected.len() as u64);
+  check_tree(&mut tree)?;
+  assert_eq!(tree.depth(), 1);
+  assert_eq!(tree.record_count()?, 0);
+  tree.put_many(&records)?;
+  tree.sync()?;
+  check_tree(&mut tree)?;
+  assert_eq!(storage.len(), full_len);
+
+  Ok(())
+}
+
+/// clear() must leave an empty, reusable tree behind
+#[test]
+fn test_clear() -> BPlusResult<()>
+{
+  let (mut tree, storage, journal) = journaled_tree()?;
+  tree.set_sync_policy(SyncPolicy::Always)?;
+  for k in 0 .. 5000
+  {
+    tree.put(k, k)?;
+  }
+  let full_len = storage.len();
+  tree.clear()?;
+  assert_eq!(storage.len(), 3);
+  assert_eq!(tree.depth(), 1);
+  assert_eq!(tree.iter()?.count(), 0);
+  check_tree(&mut tree)?;
+
+  let mut tree = BPlusTree::open_journaled(Box::new(storage.clone()), Box::new(journal.clone()))?;
+  assert_eq!(tree.get(10)?, None);
+  assert_eq!(tree.record_count()?, 0);
+  for k in 0 .. 5000
+  {
+    tree.put(k, k + 1)?;
+  }
+  check_tree(&mut tree)?;
+  assert_eq!(tree.get(10)?, Some(11));
+  assert_eq!(storage.len(), full_len);
+
+  Ok(())
+}
+
+/// Splitting a tree and appending the halves back together must
+/// preserve every record, whatever the heights of the halves
+#[test]
+fn test_split_append() -> BPlusResult<()>
+{
+  let mut tree = BPlusTree::init_in_memory()?;
+  tree.set_sync_policy(SyncPolicy::EveryOps(1000))?;
+  let records: Vec<(u32, u32)> = (0 .. 80000).map(|k| (k * 50000, k)).collect();
+  tree.put_many(&records)?;
+  assert!(tree.depth() >= 2);
+
+  let contents = |tree: &mut BPlusTree| tree.iter()?.collect::<BPlusResult<Vec<_>>>();
+  // Pivots in the middle, on a record, near either end, and past
+  // either end
+  for pivot in [40000 * 50000 + 1, 1234 * 50000, 100 * 50000, 79900 * 50000, 0, u32::MAX]
+  {
+    let split = records.partition_point(|(k, _)| *k < pivot);
+    let mut high = tree.split_off_into(pivot, BPlusTree::init_in_memory()?)?;
+    check_tree(&mut tree)?;
+    check_tree(&mut high)?;
+    assert_eq!(contents(&mut tree)?, records[.. split]);
+    assert_eq!(contents(&mut high)?, records[split ..]);
+    assert_eq!(high.record_count()?, (records.len() - split) as u64);
+
+    if split > 0 && split < records.len()
+    {
+      assert!(matches!(high.append(&mut tree), Err(BPlusError::Overlap { .. })));
+    }
+    tree.append(&mut high)?;
+    check_tree(&mut tree)?;
+    check_tree(&mut high)?;
+    assert_eq!(high.record_count()?, 0);
+    assert_eq!(contents(&mut tree)?, records);
+  }
+
+  // The new half of split_off is a tree of its own
+  let path = "target/test_split_append.btree".to_string();
+  tree.split_off(60000 * 50000, &path)?;
+  let mut high = BPlusTree::open(&path)?;
+  check_tree(&mut high)?;
+  assert_eq!(contents(&mut high)?, records[60000 ..]);
+
+  Ok(())
+}
+
+/// Merge joins and set operations must match the same operations
+/// over BTreeMaps, including when one side has long runs of keys
+/// that the other lacks
+#[test]
+fn test_set_ops() -> BPlusResult<()>
+{
+  use crate::merge::Change;
+
+  let mut rng = StdRng::seed_from_u64(410);
+  let mut old = BPlusTree::init_in_memory()?;
+  let mut new = BPlusTree::init_in_memory()?;
+  let mut old_map = BTreeMap::new();
+  let mut new_map = BTreeMap::new();
+  for _i in 0 .. 20000
+  {
+    let (k, v) = (rng.next_u32() % 50000, rng.next_u32() % 4);
+    old.put(k, v)?;
+    old_map.insert(k, v);
+  }
+  // Shares only a few runs of keys with `old`
+  for k in 0 .. 50000
+  {
+    let v = rng.next_u32() % 80;
+    if k % 10000 >= 500 && v >= 4 { continue }
+    let v = v % 4;
+    new.put(k, v)?;
+    new_map.insert(k, v);
+  }
+
+  let join: Vec<(u32, u32, u32)> = old_map.iter().filter_map(|(k, v)| new_map.get(k).map(|w| (*k, *v, *w))).collect();
+  assert_eq!(old.iter()?.merge_join(new.iter()?).collect::<BPlusResult<Vec<_>>>()?, join);
+  let both: Vec<(u32, u32)> = join.iter().map(|(k, v, _)| (*k, *v)).collect();
+  assert_eq!(old.iter()?.intersection(new.iter()?).collect::<BPlusResult<Vec<_>>>()?, both);
+  let only_old: Vec<(u32, u32)> = old_map.iter().filter(|(k, _)| !new_map.contains_key(k)).map(|(k, v)| (*k, *v)).collect();
+  assert_eq!(old.iter()?.difference(new.iter()?).collect::<BPlusResult<Vec<_>>>()?, only_old);
+  let mut union = new_map.clone();
+  union.extend(old_map.iter());
+  assert_eq!(old.iter()?.union(new.iter()?).collect::<BPlusResult<Vec<_>>>()?, union.into_iter().collect::<Vec<_>>());
+
+  let mut changes = Vec::new();
+  for k in old_map.keys().chain(new_map.keys()).copied().collect::<std::collections::BTreeSet<u32>>()
+  {
+    match (old_map.get(&k), new_map.get(&k))
+    {
+      (Some(v), None) => changes.push(Change::Removed { key: k, value: *v }),
+      (None, Some(v)) => changes.push(Change::Added { key: k, value: *v }),
+      (Some(o), Some(n)) if o != n => changes.push(Change::Changed { key: k, old: *o, new: *n }),
+      _ => {}
+    }
+  }
+  assert_eq!(old.iter()?.diff(new.iter()?).collect::<BPlusResult<Vec<_>>>()?, changes);
+
+  // Operators work on ranges too, and seek never moves backwards
+  let in_range: Vec<(u32, u32)> = both.iter().copied().filter(|(k, _)| (10000 .. 30000).contains(k)).collect();
+  assert_eq!(old.range(10000 .. 30000)?.intersection(new.iter()?).collect::<BPlusResult<Vec<_>>>()?, in_range);
+  let mut iter = old.iter()?;
+  iter.seek(25000)?;
+  iter.seek(100)?;
+  assert_eq!(iter.next().transpose()?, old_map.range(25000 ..).next().map(|(k, v)| (*k, *v)));
+
+  Ok(())
+}
+
+/// Cursors must walk the tree in both directions, and stay valid
+/// across their own updates and deletes
+#[test]
+fn test_cursor() -> BPlusResult<()>
+{
+
+  let mut rng = StdRng::seed_from_u64(410);
+  let mut tree = BPlusTree::init_in_memory()?;
+  let mut expected = BTreeMap::new();
+  for _i in 0 .. 5000
+  {
+    let k = rng.next_u32() % 100000;
+    tree.put(k, k)?;
+    expected.insert(k, k);
+  }
+  let records: Vec<(u32, u32)> = expected.iter().map(|(k, v)| (*k, *v)).collect();
+
+  // Forwards from the start, then backwards from past the end
+  let mut cursor = tree.cursor()?;
+  let mut seen = Vec::new();
+  while let Some(record) = cursor.current()
+  {
+    seen.push(record);
+    cursor.next()?;
+  }
+  assert_eq!(seen, records);
+  assert_eq!(cursor.next()?, None);
+  let mut seen = Vec::new();
+  while let Some(record) = cursor.prev()?
+  {
+    seen.push(record);
+  }
+  seen.reverse();
+  assert_eq!(seen, records);
+  assert_eq!(cursor.current(), None);
+  assert_eq!(cursor.next()?, Some(records[0]));
+
+  // Seeks land on the first key >= the target
+  for k in [0, 500, 50000, 99999, u32::MAX]
+  {
+    let record = expected.range(k ..).next().map(|(k, v)| (*k, *v));
+    assert_eq!(cursor.seek(k)?, record);
+    assert_eq!(cursor.current(), record);
+  }
+  assert_eq!(cursor.prev()?, records.last().copied());
+
+  // Update every third record and delete every other one, in a 
+  // single pass, through enough deletes to merge leaves
+  cursor.seek(0)?;
+  let mut i = 0;
+  while let Some((k, v)) = cursor.current()
+  {
+    if i % 2 == 0
+    {
+      assert_eq!(cursor.delete_current()?, Some((k, v)));
+      expected.remove(&k);
+      assert_eq!(cursor.current(), expected.range(k ..).next().map(|(k, v)| (*k, *v)));
+    }
+    else
+    {
+      if i % 3 == 0
+      {
+        assert_eq!(cursor.update_value(v + 1)?, Some(v));
+        expected.insert(k, v + 1);
+      }
+      cursor.next()?;
+    }
+    i += 1;
+  }
+  assert_eq!(cursor.update_value(0)?, None);
+  assert_eq!(cursor.delete_current()?, None);
+  check_tree(&mut tree)?;
+  assert_eq!(tree.iter()?.collect::<BPlusResult<Vec<_>>>()?, expected.into_iter().collect::<Vec<_>>());
+
+  Ok(())
+}
+
+/// Aggregates over ranges must match the same aggregates over a 
+/// BTreeMap, as the values under them are updated, deleted, and 
+/// moved between trees
+#[test]
+fn test_aggregate() -> BPlusResult<()>
+{
+  use crate::bplus_tree::Aggregate;
+
+  let mut rng = StdRng::seed_from_u64(410);
+  let mut tree = BPlusTree::init_in_memory()?;
+  let mut expected = BTreeMap::new();
+  let records: Vec<(u32, u32)> = (0 .. 80000).map(|k| (k * 50000, rng.next_u32() % 1000000)).collect();
+  tree.put_many(&records)?;
+  expected.extend(records.iter().copied());
+  assert!(tree.depth() >= 2);
+
+  let check = |tree: &mut BPlusTree, expected: &BTreeMap<u32, u32>| -> BPlusResult<()>
+  {
+    check_tree(tree)?;
+    let ranges = [(0, u32::MAX), (1, 50000), (50000, 50000), (1234 * 50000, 70000 * 50000),
+                  (3 * 50000 + 1, 3 * 50000 + 2), (79000 * 50000, u32::MAX)];
+    for (low, high) in ranges
+    {
+      let mut agg = Aggregate::default();
+      for v in expected.range(low ..= high).map(|(_, v)| *v)
+      {
+        agg.count += 1;
+        agg.sum += v as u64;
+        agg.min = Some(agg.min.map_or(v, |m| m.min(v)));
+        agg.max = Some(agg.max.map_or(v, |m| m.max(v)));
+      }
+      assert_eq!(tree.aggregate(low ..= high)?, agg);
+    }
+    assert_eq!(tree.aggregate(10 .. 10)?, Aggregate::default());
+    assert_eq!(tree.aggregate(..)?, tree.aggregate(0 ..= u32::MAX)?);
+    Ok(())
+  };
+  check(&mut tree, &expected)?;
+
+  // Updates in place, inserts, and deletes
+  for _i in 0 .. 3000
+  {
+    let k = (rng.next_u32() % 80000) * 50000 + rng.next_u32() % 2;
+    let v = rng.next_u32() % 2000000;
+    if v % 3 == 0
+    {
+      tree.delete(k)?;
+      expected.remove(&k);
+    }
+    else
+    {
+      tree.put(k, v)?;
+      expected.insert(k, v);
+    }
+  }
+  check(&mut tree, &expected)?;
+  let mut cursor = tree.cursor()?;
+  cursor.seek(5000 * 50000)?;
+  cursor.update_value(u32::MAX)?;
+  expected.insert(5000 * 50000, u32::MAX);
+  check(&mut tree, &expected)?;
+
+  // Whole subtrees deleted and moved
+  tree.delete_range(20000 * 50000 .. 30000 * 50000)?;
+  expected.retain(|k, _| !(20000 * 50000 .. 30000 * 50000).contains(k));
+  check(&mut tree, &expected)?;
+  let mut high = tree.split_off_into(60000 * 50000 + 7, BPlusTree::init_in_memory()?)?;
+  let mut expected_high = expected.split_off(&(60000 * 50000 + 7));
+  check(&mut tree, &expected)?;
+  check(&mut high, &expected_high)?;
+  tree.append(&mut high)?;
+  expected.append(&mut expected_high);
+  check(&mut tree, &expected)?;
+
+  Ok(())
+}
+
+/// A clock that only moves when told to
+#[derive(Debug, Clone)]
+struct ManualClock(Rc<Cell<u32>>);
+
+impl Clock for ManualClock
+{
+  fn now(&self) -> u32 { self.0.get() }
+}
+
+/// Expired entries must be hidden from reads and treated as absent
+/// by updates, and `purge_expired` must delete exactly them
+#[test]
+fn test_expiry() -> BPlusResult<()>
+{
+  use crate::bplus_tree::Aggregate;
+
+  let now = Rc::new(Cell::new(1000));
+  let mut tree = BPlusTree::init_in_memory()?;
+  assert!(matches!(tree.put_expiring(1, 1, 2000), Err(BPlusError::Unsupported(_))));
+  tree.set_expiries(BPlusTree::init_in_memory()?)?;
+  tree.set_clock(Box::new(ManualClock(now.clone())));
+
+  let records: Vec<(u32, u32)> = (0 .. 20000).map(|k| (k * 10, k)).collect();
+  tree.put_many(&records)?;
+  let mut expected: BTreeMap<u32, (u32, Option<u32>)> = records.iter().map(|(k, v)| (*k, (*v, None))).collect();
+  let mut put_expiring = |tree: &mut BPlusTree, key: u32, value: u32, at: u32| -> BPlusResult<()>
+  {
+    tree.put_expiring(key, value, at)?;
+    expected.insert(key, (value, Some(at)));
+    Ok(())
+  };
+  // A long run that expires together, scattered keys that expire
+  // later, and keys that don't expire during the test
+  for k in 5000 .. 9000 { put_expiring(&mut tree, k * 10, k + 1, 1100)?; }
+  for k in (0 .. 20000).step_by(7) { put_expiring(&mut tree, k * 10 + 1, k, 1200)?; }
+  for k in (0 .. 20000).step_by(13) { put_expiring(&mut tree, k * 10 + 2, k, 5000)?; }
+
+  // Plain puts clear the expiry time; updates of live keys keep it
+  tree.put(6000 * 10, 7)?;
+  expected.insert(6000 * 10, (7, None));
+  assert_eq!(tree.fetch_add(6001 * 10, 1)?, 6002);
+  expected.insert(6001 * 10, (6003, Some(1100)));
+  assert_eq!(tree.expiry(6001 * 10)?, Some(1100));
+
+  let check = |tree: &mut BPlusTree, expected: &BTreeMap<u32, (u32, Option<u32>)>, now: u32| -> BPlusResult<()>
+  {
+    let live: Vec<(u32, u32)> = expected.iter()
+      .filter(|(_, (_, at))| at.is_none_or(|at| at > now))
+      .map(|(k, (v, _))| (*k, *v))
+      .collect();
+    assert_eq!(tree.iter()?.collect::<BPlusResult<Vec<_>>>()?, live);
+    assert_eq!(tree.range(50000 .. 60000)?.collect::<BPlusResult<Vec<_>>>()?, 
+               live.iter().filter(|(k, _)| (50000 .. 60000).contains(k)).copied().collect::<Vec<_>>());
+    for k in [0, 1, 2, 5000 * 10, 6000 * 10, 6001 * 10, 7 * 10 + 1, 8999 * 10, 13 * 10 + 2]
+    {
+      let value = live.binary_search_by_key(&k, |(k, _)| *k).ok().map(|i| live[i].1);
+      assert_eq!(tree.get(k)?, value);
+      // The other queries hide expired records too
+      assert_eq!(tree.ceiling(k)?, live.iter().find(|(key, _)| *key >= k).copied());
+      assert_eq!(tree.floor(k)?, live.iter().rev().find(|(key, _)| *key <= k).copied());
+      assert_eq!(tree.successor(k)?, live.iter().find(|(key, _)| *key > k).copied());
+      assert_eq!(tree.predecessor(k)?, live.iter().rev().find(|(key, _)| *key < k).copied());
+      if expected.contains_key(&k)
+      {
+        let rank = tree.rank(k)?;
+        assert_eq!(tree.select(rank)?, value.map(|value| (k, value)));
+      }
+    }
+    assert_eq!(tree.first()?, live.first().copied());
+    assert_eq!(tree.last()?, live.last().copied());
+    // ...and so do cursors, in both directions
+    let mut cursor = tree.cursor()?;
+    let mut seen = Vec::new();
+    while let Some(record) = cursor.current()
+    {
+      seen.push(record);
+      cursor.next()?;
+    }
+    assert!(seen == live);
+    let mut seen = Vec::new();
+    while let Some(record) = cursor.prev()? { seen.push(record); }
+    seen.reverse();
+    assert!(seen == live);
+    assert_eq!(cursor.seek(5000 * 10)?, live.iter().find(|(key, _)| *key >= 5000 * 10).copied());
+    let mut agg = Aggregate::default();
+    for (_, value) in live.iter().filter(|(k, _)| (45000 ..= 95000).contains(k))
+    {
+      agg.count += 1;
+      agg.sum += *value as u64;
+      agg.min = Some(agg.min.map_or(*value, |min| min.min(*value)));
+      agg.max = Some(agg.max.map_or(*value, |max| max.max(*value)));
+    }
+    assert_eq!(tree.aggregate(45000 ..= 95000)?, agg);
+    Ok(())
+  };
+  check(&mut tree, &expected, 1000)?;
+  now.set(1150);
+  check(&mut tree, &expected, 1150)?;
+
+  // An expired key is absent as far as updates are concerned, and
+  // loses its expiry time once it's written again
+  tree.insert_new(5000 * 10, 42)?;
+  expected.insert(5000 * 10, (42, None));
+  assert_eq!(tree.expiry(5000 * 10)?, None);
+  assert_eq!(tree.remove(5001 * 10)?, None);
+  expected.remove(&(5001 * 10));
+
+  // Bringing an expired key back with the value it still holds is
+  // a write like any other
+  let (mut journaled, _, _) = journaled_tree()?;
+  journaled.set_sync_policy(SyncPolicy::Always)?;
+  journaled.set_expiries(BPlusTree::init_in_memory()?)?;
+  journaled.set_clock(Box::new(ManualClock(now.clone())));
+  journaled.put_expiring(10, 11, 1100)?;
+  let commits = journaled.commits();
+  assert_eq!(journaled.update(10, |old| old.or(Some(11)))?, None);
+  assert_eq!(journaled.commits(), commits + 1);
+  assert_eq!(journaled.expiry(10)?, None);
+  assert_eq!(journaled.get(10)?, Some(11));
+
+  now.set(1500);
+  check(&mut tree, &expected, 1500)?;
+  let before = tree.record_count()?;
+  let purged = tree.purge_expired()?;
+  expected.retain(|_, (_, at)| at.is_none_or(|at| at > 1500));
+  check_tree(&mut tree)?;
+  assert_eq!(before - purged, expected.len() as u64);
+  assert_eq!(tree.record_count()?, expected.len() as u64);
+  check(&mut tree, &expected, 1500)?;
+  assert_eq!(tree.purge_expired()?, 0);
+  assert_eq!(tree.expiry(13 * 10 + 2)?, Some(5000));
+  assert_eq!(tree.expiry(7 * 10 + 1)?, None);
+
+  // Expiry times move with split off records
+  let mut high = BPlusTree::init_in_memory()?;
+  high.set_expiries(BPlusTree::init_in_memory()?)?;
+  let mut high = tree.split_off_into(10000 * 10, high)?;
+  assert_eq!(tree.expiry(13000 * 10 + 2)?, None);
+  assert_eq!(high.expiry(13000 * 10 + 2)?, Some(5000));
+  tree.append(&mut high)?;
+  assert_eq!(tree.expiry(13000 * 10 + 2)?, Some(5000));
+
+  // Splitting off into a tree that keeps expiry times replaces all
+  // of them, even if there are none to move
+  let mut plain = BPlusTree::init_in_memory()?;
+  plain.put_many(&records)?;
+  let mut high = BPlusTree::init_in_memory()?;
+  high.set_expiries(BPlusTree::init_in_memory()?)?;
+  high.put_expiring(5, 5, 5000)?;
+  high.put_expiring(15000 * 10, 5, 5000)?;
+  let mut high = plain.split_off_into(10000 * 10, high)?;
+  assert_eq!(high.expiry(5)?, None);
+  assert_eq!(high.expiry(15000 * 10)?, None);
+  assert_eq!(high.get(15000 * 10)?, Some(15000));
+
+  Ok(())
+}
+
+/// An expiry time must only be dropped once the write that made it
+/// stale is committed, so a crash can't leave the previous value
+/// without it
+#[test]
+fn test_expiry_crash() -> BPlusResult<()>
+{
+  let now = Rc::new(Cell::new(1000));
+  let (storage, journal) = (MemStorage::new(), MemStorage::new());
+  let (index, index_journal) = (MemStorage::new(), MemStorage::new());
+  let open = |now: &Rc<Cell<u32>>| -> BPlusResult<BPlusTree>
+  {
+    let mut tree = BPlusTree::open_journaled(Box::new(storage.clone()), Box::new(journal.clone()))?;
+    tree.set_expiries(BPlusTree::open_journaled(Box::new(index.clone()), Box::new(index_journal.clone()))?)?;
+    tree.set_clock(Box::new(ManualClock(now.clone())));
+    tree.set_sync_policy(SyncPolicy::EveryOps(1000))?;
+    Ok(tree)
+  };
+  drop(BPlusTree::init_journaled(Box::new(storage.clone()), Box::new(journal.clone()))?);
+  drop(BPlusTree::init_journaled(Box::new(index.clone()), Box::new(index_journal.clone()))?);
+
+  let mut tree = open(&now)?;
+  for k in 0 .. 100 { tree.put_expiring(k, k, 2000)?; }
+  tree.sync()?;
+  tree.put(1, 10)?;
+  tree.delete(2)?;
+  tree.delete_range(50 .. 60)?;
+  assert_eq!(tree.expiry(1)?, None);
+  assert_eq!(tree.expiry(55)?, None);
+  assert_eq!(tree.get(1)?, Some(10));
+  std::mem::forget(tree);
+
+  // None of the changes were committed, so the expiry times stay
+  now.set(3000);
+  let mut tree = open(&now)?;
+  assert_eq!(tree.expiry(1)?, Some(2000));
+  assert_eq!(tree.expiry(55)?, Some(2000));
+  assert_eq!(tree.get(1)?, None);
+  assert_eq!(tree.iter()?.count(), 0);
+
+  // Once they are, the times are gone for good
+  tree.put(1, 10)?;
+  tree.delete_range(50 .. 60)?;
+  tree.sync()?;
+  std::mem::forget(tree);
+  let mut tree = open(&now)?;
+  assert_eq!(tree.expiry(1)?, None);
+  assert_eq!(tree.expiry(55)?, None);
+  assert_eq!(tree.get(1)?, Some(10));
+  assert_eq!(tree.purge_expired()?, 89);
+  assert_eq!(tree.iter()?.collect::<BPlusResult<Vec<_>>>()?, vec![(1, 10)]);
+
+  Ok(())
+}
+
+/// Replaying the change log must reproduce the tree, from the 
+/// start or from any point a consumer left off, across reopens,
+/// and without changes that were never committed
+#[test]
+fn test_change_log() -> BPlusResult<()>
+{
+  use crate::changelog::Mutation;
+
+  let replay = |mirror: &mut BTreeMap<u32, u32>, changes: &[(u64, Mutation)]|
+  {
+    for (_, change) in changes
+    {
+      match *change
+      {
+        Mutation::Put { key, value }        => { mirror.insert(key, value); },
+        Mutation::Delete { key }            => { mirror.remove(&key); },
+        Mutation::DeleteRange { low, high } => mirror.retain(|k, _| !(low ..= high).contains(k)),
+        Mutation::Clear                     => mirror.clear(),
+      }
+    }
+  };
+
+  let (mut tree, storage, journal) = journaled_tree()?;
+  let log = MemStorage::new();
+  tree.set_sync_policy(SyncPolicy::EveryOps(50))?;
+  assert!(matches!(tree.changes_since(0, 10), Err(BPlusError::Unsupported(_))));
+  tree.set_change_log(Box::new(log.clone()))?;
+
+  // Enough changes to fill several log pages
+  let records: Vec<(u32, u32)> = (0 .. 3000).map(|k| (k * 7, k)).collect();
+  tree.put_many(&records)?;
+  tree.put(5, 5)?;
+  tree.insert_new(6, 6)?;
+  tree.fetch_add(7, 10)?;
+  tree.remove(14)?;
+  tree.delete(15)?;
+  tree.delete_range(700 .. 1400)?;
+  tree.cursor()?.update_value(99)?;
+  let last = tree.last_seq();
+  assert_eq!(tree.changes_since(0, usize::MAX)?.len() as u64, last);
+
+  let mut mirror = BTreeMap::new();
+  replay(&mut mirror, &tree.changes_since(0, usize::MAX)?);
+  assert_eq!(mirror, contents(&mut tree)?);
+
+  // Consume in small batches, resuming from the last one applied
+  let mut mirror = BTreeMap::new();
+  let mut seq = 0;
+  loop
+  {
+    let batch = tree.changes_since(seq, 100)?;
+    let Some((last, _)) = batch.last() else { break };
+    assert!(batch.iter().zip(seq+1 ..).all(|((s, _), expected)| *s == expected));
+    seq = *last;
+    replay(&mut mirror, &batch);
+  }
+  assert_eq!(seq, tree.last_seq());
+  assert_eq!(mirror, contents(&mut tree)?);
+
+  // The log picks up where it left off after a reopen
+  drop(tree);
+  let mut tree = BPlusTree::open_journaled(Box::new(storage.clone()), Box::new(journal.clone()))?;
+  tree.set_change_log(Box::new(log.clone()))?;
+  assert_eq!(tree.last_seq(), last);
+  tree.clear()?;
+  assert_eq!(tree.changes_since(last, 10)?, vec![(last + 1, Mutation::Clear)]);
+  replay(&mut mirror, &tree.changes_since(last, 10)?);
+  assert_eq!(mirror, contents(&mut tree)?);
+
+  // Changes the tree never committed are dropped from the log
+  tree.set_sync_policy(SyncPolicy::EveryOps(1000))?;
+  let committed = tree.last_seq();
+  for k in 0 .. 10 { tree.put(k, k)?; }
+  assert_eq!(tree.last_seq(), committed + 10);
+  std::mem::forget(tree);
+  let mut tree = BPlusTree::open_journaled(Box::new(storage.clone()), Box::new(journal.clone()))?;
+  tree.set_change_log(Box::new(log.clone()))?;
+  assert_eq!(tree.last_seq(), committed);
+  assert_eq!(tree.changes_since(committed, 10)?, vec![]);
+  tree.put(1, 2)?;
+  assert_eq!(tree.changes_since(committed, 10)?, vec![(committed + 1, Mutation::Put { key: 1, value: 2 })]);
+
+  // Each change carries the clock's time
+  tree.set_clock(Box::new(ManualClock(Rc::new(Cell::new(500)))));
+  tree.put(3, 4)?;
+  let timed = tree.timed_changes_since(committed + 1, 10)?;
+  assert_eq!(timed.iter().map(|change| (change.seq, change.time)).collect::<Vec<_>>(), vec![(committed + 2, 500)]);
+
+  Ok(())
+}
+
+/// Storage that flips a bit in everything written to one page
+#[derive(Debug)]
+struct BitRotStorage
+{
+  inner: MemStorage,
+  page: PagePointer,
+}
+
+impl Storage for BitRotStorage
+{
+  fn read_page(&mut self, ptr: PagePointer, buffer: &mut [u8]) -> BPlusResult<()>
+    { self.inner.read_page(ptr, buffer) }
+  fn write_page(&mut self, ptr: PagePointer, buffer: &[u8]) -> BPlusResult<()>
+  {
+    let mut buffer = buffer.to_vec();
+    if ptr == self.page { buffer[100] ^= 1; }
+    self.inner.write_page(ptr, &buffer)
+  }
+  fn allocate(&mut self, pages: PagePointer) -> BPlusResult<()>
+    { self.inner.allocate(pages) }
+  fn sync(&mut self) -> BPlusResult<()>
+    { self.inner.sync() }
+  fn len(&self) -> PagePointer
+    { self.inner.len() }
+}
+
+/// A backup taken from an open tree, plus its change log, must
+/// restore the tree as it was at any later change or time
+#[test]
+fn test_backup_restore() -> BPlusResult<()>
+{
+  use crate::backup::{ copy_pages, RestorePoint };
+  use crate::page::METADATA_IDX;
+
+  let restore = |backup: &MemStorage, source: Option<&mut BPlusTree>, until: RestorePoint| -> BPlusResult<BPlusTree>
+  {
+    BPlusTree::restore(&mut backup.clone(), vec![], Box::new(MemStorage::new()), source, until)
+  };
+
+  let now = Rc::new(Cell::new(100));
+  let log = MemStorage::new();
+  let mut tree = BPlusTree::init_journaled(Box::new(MemStorage::new()), Box::new(MemStorage::new()))?;
+  tree.set_sync_policy(SyncPolicy::EveryOps(20))?;
+  tree.set_clock(Box::new(ManualClock(now.clone())));
+  tree.set_change_log(Box::new(log.clone()))?;
+
+  for k in 0 .. 1000 { tree.put(k, k)?; }
+  let backup = MemStorage::new();
+  let backup_seq = tree.backup(&mut backup.clone())?.last_seq;
+  assert_eq!(backup_seq, tree.last_seq());
+  assert_eq!(tree.pending_pages(), 0);
+  let at_backup = contents(&mut tree)?;
+
+  // The tree stays open, and the backup is a tree of its own
+  now.set(200);
+  tree.delete_range(0 .. 500)?;
+  tree.put(2000, 1)?;
+  let (at_200, seq_200) = (contents(&mut tree)?, tree.last_seq());
+  now.set(300);
+  for k in 5000 .. 5100 { tree.put(k, k)?; }
+  tree.delete(600)?;
+  tree.sync()?;
+  let (latest, committed) = (contents(&mut tree)?, tree.last_seq());
+  assert_eq!(contents(&mut BPlusTree::open_with(Box::new(backup.clone()))?)?, at_backup);
+  // Changes that haven't been committed aren't replayed
+  tree.put(7777, 1)?;
+  assert!(tree.last_seq() > committed && tree.pending_pages() > 0);
+
+  for (until, expected) in [(RestorePoint::Latest, &latest), (RestorePoint::Seq(seq_200), &at_200), 
+                            (RestorePoint::Seq(backup_seq), &at_backup), (RestorePoint::Time(250), &at_200), 
+                            (RestorePoint::Time(150), &at_backup)]
+  {
+    let mut restored = restore(&backup, Some(&mut tree), until)?;
+    assert_eq!(&contents(&mut restored)?, expected, "Restoring to {:?}", until);
+    assert_eq!(restored.check_tree()?, None);
+  }
+  assert!(tree.last_seq() > committed && tree.pending_pages() > 0);
+  assert_eq!(contents(&mut restore(&backup, None, RestorePoint::Latest)?)?, at_backup);
+  assert!(matches!(restore(&backup, Some(&mut tree), RestorePoint::Seq(backup_seq - 1)), Err(BPlusError::Unsupported(_))));
+  // ...until they are
+  tree.sync()?;
+  assert_eq!(restore(&backup, Some(&mut tree), RestorePoint::Latest)?.get(7777)?, Some(1));
+
+  // A restored tree can log its changes to a copy of the log, 
+  // leaving the original alone
+  let mut restored = restore(&backup, Some(&mut tree), RestorePoint::Seq(seq_200))?;
+  assert_eq!(restored.last_seq(), seq_200);
+  let branch = MemStorage::new();
+  copy_pages(&mut log.clone(), &mut branch.clone(), 0 .. log.len(), |_, _| Ok(()))?;
+  restored.set_change_log(Box::new(branch.clone()))?;
+  restored.put(1, 1)?;
+  assert_eq!(restored.changes_since(seq_200, 10)?.len(), 1);
+  assert_eq!(tree.changes_since(seq_200, usize::MAX)?.len() as u64, tree.last_seq() - seq_200);
+
+  // Pages that don't survive the copy are caught
+  let mut rotten = BitRotStorage { inner: MemStorage::new(), page: 3 };
+  assert!(matches!(tree.backup(&mut rotten), Err(BPlusError::Corruption { page: Some(3), .. })));
+
+  // ...and so are pages that were damaged before it
+  let (mut damaged, storage, _) = journaled_tree()?;
+  damaged.put_many(&(0 .. 5000).map(|k| (k, k)).collect::<Vec<_>>())?;
+  let (head, _) = damaged.data_bounds();
+  let mut leaf = [0; PAGE_SIZE];
+  storage.clone().read_page(head, &mut leaf)?;
+  storage.clone().write_page(head, &[9; PAGE_SIZE])?;
+  assert!(matches!(damaged.backup(&mut MemStorage::new()), Err(BPlusError::Corruption { page: Some(p), .. }) if p == head));
+  storage.clone().write_page(head, &leaf)?;
+  storage.clone().write_page(METADATA_IDX, &leaf)?;
+  assert!(matches!(damaged.backup(&mut MemStorage::new()), Err(BPlusError::PageTypeMismatch { page: METADATA_IDX, .. })));
+
+  Ok(())
+}
+
+/// A backup taken a few pages at a time, while the tree is being
+/// written, must hold the tree as of the step that finishes it
+#[test]
+fn test_stepped_backup() -> BPlusResult<()>
+{
+  let (mut tree, _, _) = journaled_tree()?;
+  tree.set_sync_policy(SyncPolicy::EveryOps(20))?;
+  tree.put_many(&(0 .. 20000).map(|k| (k * 2, k)).collect::<Vec<_>>())?;
+  assert!(matches!(tree.backup_step(10), Err(BPlusError::Unsupported(_))));
+
+  let dest = MemStorage::new();
+  tree.begin_backup(Box::new(dest.clone()))?;
+  let mut rng = StdRng::seed_from_u64(47);
+  let mut steps = 0;
+  let info = loop
+  {
+    // Writes between steps land on pages already copied and on 
+    // pages yet to be, and grow and shrink the tree
+    for _ in 0 .. 5
+    {
+      let key = rng.next_u32() % 60000;
+      if key % 3 == 0 { tree.delete(key & !1)?; }
+      else            { tree.put(key, steps)?; }
+    }
+    if steps == 30 { tree.delete_range(10000 .. 14000)?; }
+    steps += 1;
+    if let Some(info) = tree.backup_step(4)? { break info }
+  };
+  assert!(steps > 20);
+  assert_eq!(info.generation + 1, tree.generation());
+  assert_eq!(info.last_seq, tree.last_seq());
+
+  let mut copy = BPlusTree::open_with(Box::new(dest.clone()))?;
+  check_tree(&mut copy)?;
+  assert!(contents(&mut copy)? == contents(&mut tree)?);
+  assert!(matches!(tree.backup_step(10), Err(BPlusError::Unsupported(_))));
+  Ok(())
+}
+
+/// Incremental backups must hold only the pages written since the
+/// backup they build on, and restore the tree when applied in order
+#[test]
+fn test_incremental_backup() -> BPlusResult<()>
+{
+  use crate::backup::RestorePoint;
+
+  let restore = |full: &MemStorage, deltas: &[&MemStorage]| -> BPlusResult<BTreeMap<u32, u32>>
+  {
+    let deltas = deltas.iter().map(|delta| Box::new((*delta).clone()) as Box<dyn Storage>).collect();
+    let mut tree = BPlusTree::restore(&mut full.clone(), deltas, Box::new(MemStorage::new()), None, RestorePoint::Latest)?;
+    assert_eq!(tree.check_tree()?, None);
+    contents(&mut tree)
+  };
+
+  let (mut tree, storage, journal) = journaled_tree()?;
+  let map = MemStorage::new();
+  tree.set_sync_policy(SyncPolicy::EveryOps(50))?;
+  assert!(matches!(tree.backup_incremental(0, &mut MemStorage::new()), Err(BPlusError::Unsupported(_))));
+  tree.set_generation_map(Box::new(map.clone()))?;
+  let records: Vec<(u32, u32)> = (0 .. 50000).map(|k| (k * 2, k)).collect();
+  tree.put_many(&records)?;
+
+  let full = MemStorage::new();
+  let base = tree.backup(&mut full.clone())?;
+  assert_eq!(tree.generation(), base.generation + 1);
+  assert!(matches!(tree.backup_incremental(base.generation + 1, &mut MemStorage::new()), Err(BPlusError::Unsupported(_))));
+
+  // A few changes make for a small delta
+  for k in 0 .. 10 { tree.put(k * 2001, 7)?; }
+  tree.delete_range(40000 .. 40100)?;
+  let first = MemStorage::new();
+  let info = tree.backup_incremental(base.generation, &mut first.clone())?;
+  assert!(first.len() * 10 < full.len(), "{} page delta of a {} page tree", first.len(), full.len());
+  let after_first = contents(&mut tree)?;
+
+  // The generation map survives a reopen
+  for k in 50000 .. 60000 { tree.put(k * 2 + 1, k)?; }
+  drop(tree);
+  let mut tree = BPlusTree::open_journaled(Box::new(storage.clone()), Box::new(journal.clone()))?;
+  tree.set_generation_map(Box::new(map.clone()))?;
+  tree.delete(8)?;
+  let second = MemStorage::new();
+  tree.backup_incremental(info.generation, &mut second.clone())?;
+  let after_second = contents(&mut tree)?;
+
+  tree.clear()?;
+  tree.put(1, 1)?;
+  let since_full = MemStorage::new();
+  tree.backup_incremental(base.generation, &mut since_full.clone())?;
+  let latest = contents(&mut tree)?;
+
+  assert_eq!(restore(&full, &[&first])?, after_first);
+  assert_eq!(restore(&full, &[&first, &second])?, after_second);
+  assert_eq!(restore(&full, &[&first, &second, &since_full])?, latest);
+  assert_eq!(restore(&full, &[&since_full])?, latest);
+  // Deltas can't skip a generation, or go back to an older one
+  assert!(matches!(restore(&full, &[&second]), Err(BPlusError::Unsupported(_))));
+  assert!(matches!(restore(&full, &[&first, &first]), Err(BPlusError::Unsupported(_))));
+  assert!(matches!(restore(&full, &[&since_full, &second]), Err(BPlusError::Unsupported(_))));
+
+  // Damaged deltas are caught before anything is written
+  let mut page = [0u8; PAGE_SIZE];
+  second.clone().read_page(2, &mut page)?;
+  page[50] ^= 1;
+  second.clone().write_page(2, &page)?;
+  assert!(matches!(restore(&full, &[&first, &second]), Err(BPlusError::Corruption { page: Some(0), .. })));
+
+  Ok(())
+}
+
+/// A follower that applies its leader's batches in order must 
+/// match the leader's committed state after every batch, and skip
+/// the batches it already has
+#[test]
+fn test_replication() -> BPlusResult<()>
+{
+  use std::sync::mpsc::channel;
+  use crate::page::{ MetadataPage, Page, PageBuffer, LAYOUT_KEY_COMPRESSION };
+  use crate::replication::PageBatch;
+
+  let mut leader = BPlusTree::init_journaled(Box::new(MemStorage::new()), Box::new(MemStorage::new()))?;
+  let (sender, batches) = channel();
+  assert!(matches!(leader.set_replica(Box::new(sender.clone())), Err(BPlusError::Unsupported(_))));
+  leader.set_sync_policy(SyncPolicy::EveryOps(25))?;
+  leader.set_replica(Box::new(sender))?;
+  assert!(matches!(leader.set_sync_policy(SyncPolicy::Never), Err(BPlusError::Unsupported(_))));
+  leader.put_many(&(0 .. 5000).map(|k| (k * 3, k)).collect::<Vec<_>>())?;
+
+  let storage = MemStorage::new();
+  leader.backup(&mut storage.clone())?;
+  let mut follower = BPlusTree::open_journaled(Box::new(storage.clone()), Box::new(MemStorage::new()))?;
+  assert_eq!(follower.commits() + 1, leader.commits());
+
+  let mut rng = StdRng::seed_from_u64(49);
+  for _round in 0 .. 5
+  {
+    for _ in 0 .. 500
+    {
+      let key = rng.next_u32() % 20000;
+      if rng.next_u32() % 3 == 0 { leader.delete(key)?; }
+      else                       { leader.put(key, rng.next_u32())?; }
+    }
+    leader.sync()?;
+
+    // The follower is consistent between batches, and reaches the 
+    // leader's state once it has applied them all
+    for batch in batches.try_iter()
+    {
+      let new = batch.commit > follower.commits();
+      assert_eq!(follower.apply_batch(&batch)?, new);
+      assert!(follower.commits() >= batch.commit);
+      assert_eq!(follower.check_tree()?, None);
+    }
+    assert_eq!(follower.commits(), leader.commits());
+    assert_eq!(contents(&mut follower)?, contents(&mut leader)?);
+  }
+
+  // Batches can't be skipped, and are only applied once
+  leader.put(1, 1)?;
+  leader.sync()?;
+  leader.put(2, 2)?;
+  leader.sync()?;
+  let first = batches.try_recv().expect("A batch for the first commit");
+  let second = batches.try_recv().expect("A batch for the second commit");
+  assert!(matches!(follower.apply_batch(&second), Err(BPlusError::OutOfOrder { .. })));
+  // Batches from a leader with another key or page layout are 
+  // refused before anything is written
+  let mismatched = |change: fn(&mut MetadataPage)| -> BPlusResult<PageBatch>
+  {
+    let mut meta = MetadataPage::decode(&first.meta.0);
+    change(&mut meta);
+    Ok(PageBatch { meta: PageBuffer::encode(&meta)?, ..first.clone() })
+  };
+  let commits = follower.commits();
+  assert!(matches!(follower.apply_batch(&mismatched(|meta| meta.key_check = [7; 16])?), Err(BPlusError::WrongKey)));
+  assert!(matches!(follower.apply_batch(&mismatched(|meta| meta.layout ^= LAYOUT_KEY_COMPRESSION)?), Err(BPlusError::IncompatibleStorage(_))));
+  assert!(matches!(follower.apply_batch(&mismatched(|meta| meta.cipher = 9)?), Err(BPlusError::IncompatibleStorage(_))));
+  assert_eq!(follower.commits(), commits);
+  assert_eq!(follower.check_tree()?, None);
+  assert!(follower.apply_batch(&first)?);
+  assert!(!follower.apply_batch(&first)?);
+  assert!(follower.apply_batch(&second)?);
+
+  // A restarted follower picks up where it left off
+  drop(follower);
+  leader.delete_range(1000 .. 2000)?;
+  leader.sync()?;
+  let mut follower = BPlusTree::open_with(Box::new(storage.clone()))?;
+  assert_eq!(follower.catch_up(&batches)?, 1);
+  assert_eq!(contents(&mut follower)?, contents(&mut leader)?);
+
+  // The expiry index and change log aren't replicated
+  assert!(matches!(leader.set_change_log(Box::new(MemStorage::new())), Err(BPlusError::Unsupported(_))));
+  assert!(matches!(leader.set_expiries(BPlusTree::init_in_memory()?), Err(BPlusError::Unsupported(_))));
+  let mut logged = BPlusTree::init_in_memory()?;
+  logged.set_sync_policy(SyncPolicy::Always)?;
+  logged.set_change_log(Box::new(MemStorage::new()))?;
+  assert!(matches!(logged.set_replica(Box::new(channel().0)), Err(BPlusError::Unsupported(_))));
+
+  // A replica that fails is let go, but what it failed to send is 
+  // still committed
+  let (sender, receiver) = channel();
+  assert!(leader.remove_replica()?.is_some());
+  leader.set_replica(Box::new(sender))?;
+  drop(receiver);
+  let commits = leader.commits();
+  leader.put(3, 3)?;
+  leader.sync()?;
+  assert_eq!(leader.commits(), commits + 1);
+  assert_eq!(leader.pending_pages(), 0);
+  assert!(matches!(leader.take_replica_error(), Some(BPlusError::Io(_))));
+  assert!(leader.take_replica_error().is_none());
+  assert!(leader.remove_replica()?.is_none());
+  leader.set_sync_policy(SyncPolicy::Never)?;
+
+  // A commit that fails keeps its number for the next attempt
+  let fail = Rc::new(Cell::new(false));
+  let mut tree = BPlusTree::init_journaled(
+    Box::new(FailingStorage { inner: MemStorage::new(), fail: fail.clone() }),
+    Box::new(MemStorage::new())
+  )?;
+  tree.set_sync_policy(SyncPolicy::EveryOps(100))?;
+  tree.put(1, 1)?;
+  let commits = tree.commits();
+  fail.set(true);
+  assert!(tree.sync().is_err());
+  assert_eq!(tree.commits(), commits);
+  fail.set(false);
+  tree.sync()?;
+  assert_eq!(tree.commits(), commits + 1);
+
+  Ok(())
+}
+
+/// The contents of a tree after each commit of a crash workload, 
+/// by commit number
+type Commits = BTreeMap<u64, BTreeMap<u32, u32>>;
+
+/// The records a crash workload starts from
+fn crash_records() -> Vec<(u32, u32)>
+{
+  (0 .. 2000).map(|k| (k * 5, k)).collect()
+}
+
+/// Run a seeded workload of puts and deletes on the journaled tree
+/// in `files` (which holds `crash_records`), with the files on 
+/// `disk`, crashing as `crash` says (see FaultDisk::arm).  Returns
+/// the tree's contents after each commit (including the one 
+/// interrupted by the crash, if any), and the number of writes
+/// and syncs.
+fn crash_workload(disk: &FaultDisk, files: &(MemStorage, MemStorage), crash: Option<(u64, Fault)>) 
+  -> BPlusResult<(Commits, u64)>
+{
+  let mut tree = BPlusTree::open_journaled(Box::new(disk.file(files.0.clone())), Box::new(disk.file(files.1.clone())))?;
+  tree.set_sync_policy(SyncPolicy::EveryOps(4))?;
+  let mut oracle: BTreeMap<u32, u32> = crash_records().into_iter().collect();
+  let mut commits = Commits::from([(tree.commits(), oracle.clone())]);
+
+  disk.arm(crash);
+  let mut rng = StdRng::seed_from_u64(50);
+  for i in 0 ..= 60
+  {
+    let key = rng.next_u32() % 12000;
+    let result =
+      if i == 60                      { tree.sync() }
+      else if rng.next_u32() % 3 == 0 { oracle.remove(&key); tree.delete(key) }
+      else                            { oracle.insert(key, i); tree.put(key, i) };
+    match result
+    {
+      Ok(()) => (),
+      Err(_) if disk.crashed() =>
+      {
+        // The interrupted commit may or may not have made it.  A 
+        // failed commit leaves the count as it was, so it's the next.
+        let interrupted = tree.commits() + 1;
+        assert!(commits.insert(interrupted, oracle).is_none(), "Commit {} was already recorded", interrupted);
+        std::mem::forget(tree);
+        return Ok((commits, disk.writes()))
+      }
+      Err(err) => return Err(err)
+    }
+    commits.entry(tree.commits()).or_insert_with(|| oracle.clone());
+  }
+  Ok((commits, disk.writes()))
+}
+
+/// Crashing at any write or sync, however a write is damaged, and 
+/// whether or not unsynced writes survive, must leave a tree that
+/// reopens intact, as of the last commit or the interrupted one
+#[test]
+fn test_crash_recovery() -> BPlusResult<()>
+{
+  use crate::backup::{ copy_pages, check_tree_page };
+
+  // Every run starts from a copy of the same tree.  Its journal is
+  // empty once it's closed, so each run gets a fresh one.
+  let start = MemStorage::new();
+  let mut tree = BPlusTree::init_journaled(Box::new(start.clone()), Box::new(MemStorage::new()))?;
+  tree.put_many(&crash_records())?;
+  drop(tree);
+  let files = || -> BPlusResult<(MemStorage, MemStorage)>
+  {
+    let file = MemStorage::new();
+    copy_pages(&mut start.clone(), &mut file.clone(), 0 .. start.len(), check_tree_page)?;
+    Ok((file, MemStorage::new()))
+  };
+  let (_, writes) = crash_workload(&FaultDisk::new(false), &files()?, None)?;
+  assert!(writes > 100);
+
+  for drop_unsynced in [false, true]
+  {
+    for fault in [Fault::Fail, Fault::Truncate, Fault::Tear]
+    {
+      for n in 1 ..= writes
+      {
+        let files = files()?;
+        let (commits, _) = crash_workload(&FaultDisk::new(drop_unsynced), &files, Some((n, fault)))?;
+        let (&interrupted, _) = commits.last_key_value().expect("Commits before the crash");
+        let (&first, _) = commits.first_key_value().expect("Commits before the crash");
+        assert_eq!(commits.len() as u64, interrupted - first + 1, "Commits missing after {:?} at write {}", fault, n);
+
+        let mut tree = BPlusTree::open_journaled(Box::new(files.0.clone()), Box::new(files.1.clone()))?;
+        check_tree(&mut tree)?;
+        let recovered = tree.commits();
+        assert!(recovered == interrupted || recovered + 1 == interrupted, 
+                "Recovered commit {} after crashing in commit {} ({:?} at write {})", recovered, interrupted, fault, n);
+        assert!(contents(&mut tree)? == commits[&recovered], "Wrong contents after {:?} at write {}", fault, n);
+      }
+    }
+  }
+  Ok(())
+}
+
+/// A FaultDisk must crash at syncs as well as writes, and a 
+/// truncated write must cut its file short
+#[test]
+fn test_fault_disk() -> BPlusResult<()>
+{
+  let page = |byte: u8| vec![byte; PAGE_SIZE];
+  let read = |file: &mut dyn Storage, ptr: PagePointer| -> BPlusResult<Vec<u8>>
+  {
+    let mut buffer = vec![0; PAGE_SIZE];
+    file.read_page(ptr, &mut buffer)?;
+    Ok(buffer)
+  };
+
+  // Truncating a write zeroes the rest of its page
+  let durable = MemStorage::new();
+  let disk = FaultDisk::new(false);
+  let mut file = disk.file(durable.clone());
+  for ptr in 0 .. 4 { file.write_page(ptr, &page(1))?; }
+  disk.arm(Some((1, Fault::Truncate)));
+  assert!(file.write_page(1, &page(2)).is_err());
+  assert!(disk.crashed());
+  assert_eq!(durable.len(), 4);
+  let torn = read(&mut durable.clone(), 1)?;
+  assert!(torn[.. PAGE_SIZE / 2].iter().all(|&b| b == 2));
+  assert!(torn[PAGE_SIZE / 2 ..].iter().all(|&b| b == 0));
+  assert_eq!(read(&mut durable.clone(), 2)?, page(1));
+
+  // ...and one that grows the file leaves it short
+  let durable = MemStorage::new();
+  let disk = FaultDisk::new(false);
+  let mut file = disk.file(durable.clone());
+  for ptr in 0 .. 2 { file.write_page(ptr, &page(1))?; }
+  disk.arm(Some((1, Fault::Truncate)));
+  assert!(file.write_page(2, &page(2)).is_err());
+  assert_eq!(durable.len(), 2);
+  assert_eq!(file.len(), 2);
+
+  // Tearing a write leaves the rest of the file as it was
+  let durable = MemStorage::new();
+  let disk = FaultDisk::new(false);
+  let mut file = disk.file(durable.clone());
+  for ptr in 0 .. 4 { file.write_page(ptr, &page(1))?; }
+  disk.arm(Some((1, Fault::Tear)));
+  assert!(file.write_page(1, &page(2)).is_err());
+  assert_eq!(durable.len(), 4);
+  assert!(read(&mut durable.clone(), 1)?[PAGE_SIZE / 2 ..].iter().all(|&b| b == 1));
+
+  // Crashing at a sync loses everything since the last one
+  let durable = MemStorage::new();
+  let disk = FaultDisk::new(true);
+  let mut file = disk.file(durable.clone());
+  file.write_page(0, &page(1))?;
+  file.sync()?;
+  disk.arm(Some((3, Fault::Fail)));
+  file.write_page(0, &page(2))?;
+  file.write_page(1, &page(2))?;
+  assert_eq!(disk.writes(), 2);
+  assert!(file.sync().is_err());
+  assert!(disk.crashed());
+  assert_eq!(disk.writes(), 3);
+  assert_eq!(durable.len(), 1);
+  assert_eq!(read(&mut durable.clone(), 0)?, page(1));
+  assert!(file.write_page(0, &page(3)).is_err());
+  assert!(file.sync().is_err());
+
+  Ok(())
+}
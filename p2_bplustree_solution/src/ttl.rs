@@ -0,0 +1,92 @@
+use std::collections::BTreeMap;
+use std::fmt::Debug;
+use std::time::{ SystemTime, UNIX_EPOCH };
+
+/// A source of the current time, against which entry expiry
+/// times are compared (see `BPlusTree::put_expiring`)
+///
+/// Times are whole seconds since the UNIX epoch.  Trees use the
+/// SystemClock unless given another with `BPlusTree::set_clock`
+/// (e.g., to test expiry without waiting).
+pub trait Clock: Debug
+{
+  /// The current time
+  fn now(&self) -> u32;
+}
+
+/// The system's wall clock
+#[derive(Debug, Clone, Copy, Default)]
+pub struct SystemClock;
+
+impl Clock for SystemClock
+{
+  fn now(&self) -> u32
+  {
+    let secs = SystemTime::now().duration_since(UNIX_EPOCH).map_or(0, |d| d.as_secs());
+    secs.min(u32::MAX as u64) as u32
+  }
+}
+
+/// A set of keys, held as disjoint inclusive ranges
+///
+/// Trees use one to remember the keys whose expiry times are to be
+/// dropped once the writes that made them stale are committed (see
+/// `BPlusTree::put_expiring`).
+#[derive(Debug, Default)]
+pub struct KeyRanges
+{
+  /// The high key of each range, by its low key
+  ranges: BTreeMap<u32, u32>,
+}
+
+#[allow(dead_code)]
+impl KeyRanges
+{
+  /// Add the keys in [low, high], merging any ranges they overlap
+  pub fn insert(&mut self, mut low: u32, mut high: u32)
+  {
+    for (l, h) in self.overlapping(low, high)
+    {
+      self.ranges.remove(&l);
+      low = low.min(l);
+      high = high.max(h);
+    }
+    self.ranges.insert(low, high);
+  }
+
+  /// Remove the keys in [low, high], splitting any ranges that hold
+  /// keys on either side
+  pub fn remove(&mut self, low: u32, high: u32)
+  {
+    for (l, h) in self.overlapping(low, high)
+    {
+      self.ranges.remove(&l);
+      if l < low  { self.ranges.insert(l, low - 1); }
+      if high < h { self.ranges.insert(high + 1, h); }
+    }
+  }
+
+  /// True if `key` is in one of the ranges
+  pub fn contains(&self, key: u32) -> bool
+  {
+    self.ranges.range(..= key).next_back().is_some_and(|(_, high)| *high >= key)
+  }
+
+  /// Remove and return every range, in order
+  pub fn take(&mut self) -> Vec<(u32, u32)>
+  {
+    std::mem::take(&mut self.ranges).into_iter().collect()
+  }
+
+  /// The ranges that hold any of the keys in [low, high]
+  fn overlapping(&self, low: u32, high: u32) -> Vec<(u32, u32)>
+  {
+    // The ranges are disjoint, so the ones that start at or before 
+    // `high` end in the same order, and those that overlap are the 
+    // last few.
+    self.ranges.range(..= high).rev()
+      .take_while(|(_, h)| **h >= low)
+      .map(|(l, h)| (*l, *h))
+      .collect()
+  }
+}